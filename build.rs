@@ -0,0 +1,11 @@
+// The XOR'ed token file is optional, binaries built without it read the
+// token at startup instead, see `config::discord_token`
+fn main() {
+    println!("cargo::rerun-if-changed=.token.xor");
+    // Embedded by `sqlx::migrate!`
+    println!("cargo::rerun-if-changed=migrations");
+    println!("cargo::rustc-check-cfg=cfg(embedded_token)");
+    if std::path::Path::new(".token.xor").exists() {
+        println!("cargo::rustc-cfg=embedded_token");
+    }
+}
@@ -0,0 +1,26 @@
+-- Saved options, as the JSON the settings file used to hold, so adding an
+-- option doesn't need a migration
+CREATE TABLE users (
+    id INTEGER PRIMARY KEY,
+    prefs TEXT NOT NULL
+);
+
+CREATE TABLE guilds (
+    id INTEGER PRIMARY KEY,
+    settings TEXT NOT NULL
+);
+
+-- Renders counted against the daily quotas, per UTC day since the epoch
+CREATE TABLE quota_usage (
+    day INTEGER NOT NULL,
+    guild INTEGER NOT NULL,
+    user INTEGER NOT NULL,
+    renders INTEGER NOT NULL,
+    PRIMARY KEY (day, guild, user)
+);
+
+-- Lifetime counters shown by /stats
+CREATE TABLE stats (
+    name TEXT PRIMARY KEY,
+    value INTEGER NOT NULL
+);
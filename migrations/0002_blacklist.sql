@@ -0,0 +1,7 @@
+-- Users and servers the bot refuses, managed with /admin blacklist
+CREATE TABLE blacklist (
+    kind TEXT NOT NULL CHECK (kind IN ('user', 'guild')),
+    id INTEGER NOT NULL,
+    reason TEXT,
+    PRIMARY KEY (kind, id)
+);
@@ -0,0 +1,8 @@
+-- The only users and servers served when `allowlist_only` is set, managed
+-- with /admin allowlist
+CREATE TABLE allowlist (
+    kind TEXT NOT NULL CHECK (kind IN ('user', 'guild')),
+    id INTEGER NOT NULL,
+    reason TEXT,
+    PRIMARY KEY (kind, id)
+);
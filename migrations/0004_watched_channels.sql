@@ -0,0 +1,5 @@
+-- Channels where every posted image is converted, set with /watch_channel
+CREATE TABLE watched_channels (
+    channel INTEGER PRIMARY KEY,
+    guild INTEGER NOT NULL
+);
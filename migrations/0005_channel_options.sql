@@ -0,0 +1,6 @@
+-- Options of the last /image_to_ascii run in each channel, reused by
+-- /same_as_last. JSON like the saved preferences
+CREATE TABLE channel_options (
+    channel INTEGER PRIMARY KEY,
+    prefs TEXT NOT NULL
+);
@@ -0,0 +1,128 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Metadata written into the SAUCE record of an `.ans` file
+pub struct Sauce<'a> {
+    pub title: &'a str,
+    pub author: &'a str,
+    pub width: u16,
+    pub height: u16,
+}
+
+/// Unicode characters of the upper half of code page 437, from 0x80 on
+const CP437_UPPER_HALF: &str = "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»\
+    ░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡\
+    ÷≈°∙·√ⁿ²■\u{a0}";
+
+/// Convert the ANSI colored output into a classic `.ans` file
+/// Lines are terminated with CRLF and characters are encoded in CP437, as
+/// expected by DOS-era ANSI viewers, followed by a SAUCE record
+pub fn to_ans_file(ascii_art: &str, sauce: &Sauce) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(ascii_art.len() + 256);
+
+    for line in ascii_art.lines() {
+        bytes.extend(line.chars().map(cp437_byte));
+        bytes.extend_from_slice(b"\r\n");
+    }
+
+    let file_size = bytes.len() as u32;
+    bytes.push(0x1a); // EOF marker, viewers stop reading here
+    bytes.extend_from_slice(&sauce_record(sauce, file_size));
+
+    bytes
+}
+
+/// Decode a `.ans`/`.txt` file back into text the renderer understands
+/// Drops the SAUCE record and falls back to CP437 for non UTF-8 files
+pub fn from_ans_file(bytes: &[u8]) -> String {
+    // Everything after the EOF marker is SAUCE metadata
+    let content = bytes.split(|&b| b == 0x1a).next().unwrap_or_default();
+
+    let text = match std::str::from_utf8(content) {
+        Ok(text) => text.to_string(),
+        Err(_) => content.iter().map(|&b| cp437_char(b)).collect(),
+    };
+
+    text.replace("\r\n", "\n")
+}
+
+/// Map a code page 437 byte to its unicode character
+fn cp437_char(byte: u8) -> char {
+    if byte < 0x80 {
+        byte as char
+    } else {
+        CP437_UPPER_HALF.chars().nth(byte as usize - 0x80).unwrap_or('?')
+    }
+}
+
+/// Map a unicode character to its code page 437 byte, `?` when CP437 has
+/// no such character
+fn cp437_byte(ch: char) -> u8 {
+    if ch.is_ascii() {
+        return ch as u8;
+    }
+    CP437_UPPER_HALF
+        .chars()
+        .position(|upper| upper == ch)
+        .map_or(b'?', |index| 0x80 + index as u8)
+}
+
+/// Build the 128 byte SAUCE record
+/// Spec: https://www.acid.org/info/sauce/sauce.htm
+fn sauce_record(sauce: &Sauce, file_size: u32) -> [u8; 128] {
+    let mut record = [0u8; 128];
+    let mut offset = 0;
+
+    let mut put = |field: &[u8]| {
+        record[offset..offset + field.len()].copy_from_slice(field);
+        offset += field.len();
+    };
+
+    put(b"SAUCE00");
+    put(&padded::<35>(sauce.title));
+    put(&padded::<20>(sauce.author));
+    put(&padded::<20>("")); // Group
+    put(current_date().as_bytes());
+    put(&file_size.to_le_bytes());
+    put(&[1, 1]); // DataType: Character, FileType: ANSi
+    put(&sauce.width.to_le_bytes());
+    put(&sauce.height.to_le_bytes());
+    // TInfo3, TInfo4, Comments, TFlags and TInfoS stay zeroed
+
+    record
+}
+
+/// Space-padded, ASCII only fixed-width field
+fn padded<const N: usize>(text: &str) -> [u8; N] {
+    let mut field = [b' '; N];
+
+    for (slot, ch) in field.iter_mut().zip(text.chars()) {
+        *slot = if ch.is_ascii() && !ch.is_ascii_control() {
+            ch as u8
+        } else {
+            b'?'
+        };
+    }
+
+    field
+}
+
+/// Today's date (UTC) formatted as CCYYMMDD
+fn current_date() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / 86_400)
+        .unwrap_or(0) as i64;
+
+    // Howard Hinnant's days_from_civil, inverted
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}{month:02}{day:02}")
+}
@@ -0,0 +1,455 @@
+use std::{fmt, str::CharIndices};
+
+use image::Rgba;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use crate::palette;
+
+/// Color of text before any escape sets one
+const DEFAULT_FOREGROUND: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+/// A parsed grapheme cluster with its colors and text attributes
+#[derive(Clone)]
+pub struct Cell {
+    /// First character of the cluster, the one the cell is sized by
+    pub ch: char,
+    /// The rest of the cluster: combining accents, variation selectors,
+    /// emoji joined with a zero width joiner
+    pub marks: Option<Box<str>>,
+    /// Columns taken on a terminal, 2 for wide characters like CJK
+    pub width: u32,
+    pub fg: Rgba<u8>,
+    pub bg: Option<Rgba<u8>>,
+    pub bold: bool,
+    pub underline: bool,
+}
+
+/// What to do with malformed escape sequences
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Refuse the text, pointing at the first malformed sequence
+    Strict,
+    /// Skip them, keeping whatever could be understood
+    Lossy,
+}
+
+/// A malformed escape sequence, found while parsing in strict mode
+#[derive(Debug)]
+pub struct ParseError {
+    /// Line and column of the escape character, from 1
+    pub line: usize,
+    pub column: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Line {}, column {}: {}", self.line, self.column, self.reason)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Colors and attributes set by the escapes read so far
+#[derive(Clone, Copy)]
+struct Style {
+    fg: Rgba<u8>,
+    bg: Option<Rgba<u8>>,
+    bold: bool,
+    underline: bool,
+}
+
+impl Style {
+    const DEFAULT: Style = Style {
+        fg: DEFAULT_FOREGROUND,
+        bg: None,
+        bold: false,
+        underline: false,
+    };
+
+    fn apply(&mut self, change: AnsiColor) {
+        match change {
+            AnsiColor::Foreground(color) => self.fg = color,
+            AnsiColor::Background(color) => self.bg = Some(color),
+            AnsiColor::DefaultForeground => self.fg = DEFAULT_FOREGROUND,
+            AnsiColor::DefaultBackground => self.bg = None,
+            AnsiColor::Bold(on) => self.bold = on,
+            AnsiColor::Underline(on) => self.underline = on,
+            AnsiColor::Reset => *self = Style::DEFAULT,
+        }
+    }
+}
+
+impl Cell {
+    /// Every character of the cluster, `ch` first
+    pub fn chars(&self) -> impl Iterator<Item = char> + '_ {
+        std::iter::once(self.ch)
+            .chain(self.marks.iter().flat_map(|m| m.chars()))
+    }
+}
+
+/// Changes an SGR parameter makes to the current style
+enum AnsiColor {
+    Foreground(Rgba<u8>),
+    Background(Rgba<u8>),
+    DefaultForeground,
+    DefaultBackground,
+    Bold(bool),
+    Underline(bool),
+    Reset,
+}
+
+/// Parse every line of `text`, see [`parse_line`]
+pub fn parse(
+    text: &str,
+    mode: ParseMode,
+) -> Result<Vec<Vec<Cell>>, ParseError> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| {
+            parse_line(line, mode).map_err(|e| ParseError { line: i + 1, ..e })
+        })
+        .collect()
+}
+
+/// Parse a line of ANSI colored text into a cell per grapheme cluster.
+/// Handles combined SGR
+/// sequences (`0;38;2;R;G;B`), the colon syntax (`38:2::R:G:B`), 256
+/// colors (`38;5;n`), the 16 color codes, bold and underline. Other CSI
+/// sequences, like cursor moves, are dropped and unknown SGR codes are
+/// ignored in both modes
+pub fn parse_line(
+    line: &str,
+    mode: ParseMode,
+) -> Result<Vec<Cell>, ParseError> {
+    let mut cells = Vec::new();
+    let mut style = Style::DEFAULT;
+    let mut chars = line.char_indices();
+    // Start of the text that follows the last escape
+    let mut text_start = 0;
+
+    while let Some((start, ch)) = chars.next() {
+        if ch != '\x1b' {
+            continue;
+        }
+        push_text(&mut cells, &line[text_start..start], style);
+
+        let error = |reason: String| ParseError {
+            line: 1,
+            column: line[..start].chars().count() + 1,
+            reason,
+        };
+        let bracket = chars.next().map(|(_, c)| c) == Some('[');
+        let csi = bracket.then(|| read_csi(&mut chars));
+        text_start = chars.offset();
+        let Some((code, kind)) = csi else {
+            match mode {
+                ParseMode::Strict => {
+                    return Err(error(
+                        "The escape character isn't followed by `[`".into(),
+                    ));
+                }
+                ParseMode::Lossy => continue,
+            }
+        };
+        let changes = match kind {
+            Some('m') => parse_sgr(&code, mode).map_err(error)?,
+            Some(_) => continue,
+            None if mode == ParseMode::Strict => {
+                return Err(error("This escape sequence never ends".into()));
+            }
+            None => continue,
+        };
+        for change in changes {
+            style.apply(change);
+        }
+    }
+    push_text(&mut cells, &line[text_start..], style);
+
+    Ok(cells)
+}
+
+/// Add a cell per grapheme cluster of `text`. Clusters that take no room
+/// of their own, like an accent colored apart from its letter, join the
+/// previous cell
+fn push_text(cells: &mut Vec<Cell>, text: &str, style: Style) {
+    for cluster in text.graphemes(true) {
+        let mut chars = cluster.chars();
+        let Some(ch) = chars.next() else {
+            continue;
+        };
+        if ch.width() == Some(0)
+            && let Some(last) = cells.last_mut()
+        {
+            let marks = last.marks.as_deref().unwrap_or_default();
+            last.marks = Some(format!("{marks}{cluster}").into());
+            continue;
+        }
+
+        let marks = chars.as_str();
+        cells.push(Cell {
+            ch,
+            marks: (!marks.is_empty()).then(|| marks.into()),
+            width: cluster_width(cluster),
+            fg: style.fg,
+            bg: style.bg,
+            bold: style.bold,
+            underline: style.underline,
+        });
+    }
+}
+
+/// The characters of a line without its escape sequences, whichever
+/// command a CSI sequence ends with
+pub fn strip_escapes(line: &str) -> String {
+    let mut text = String::with_capacity(line.len());
+    let mut chars = line.char_indices();
+
+    while let Some((_, ch)) = chars.next() {
+        if ch == '\x1b' {
+            // Skip ANSI escape sequence
+            if chars.next().map(|(_, c)| c) == Some('[') {
+                read_csi(&mut chars);
+            }
+        } else {
+            text.push(ch);
+        }
+    }
+
+    text
+}
+
+/// Columns taken by the visible text of a line (excluding ANSI escape
+/// sequences), counted like [`parse_line`] lays out its cells
+pub fn visible_width(line: &str) -> usize {
+    strip_escapes(line)
+        .graphemes(true)
+        .enumerate()
+        .filter(|(i, cluster)| {
+            let first = cluster.chars().next().and_then(|c| c.width());
+            *i == 0 || first != Some(0)
+        })
+        .map(|(_, cluster)| cluster_width(cluster) as usize)
+        .sum()
+}
+
+/// Terminal width of a grapheme cluster: at least a column, so controls
+/// and stray marks stay visible, and at most 2
+fn cluster_width(cluster: &str) -> u32 {
+    cluster.width().clamp(1, 2) as u32
+}
+
+/// Read the rest of a CSI sequence, after `ESC[`: its parameters and its
+/// final character, `m` for colors. `None` when the line ends first
+fn read_csi(chars: &mut CharIndices) -> (String, Option<char>) {
+    let mut code = String::new();
+    for (_, c) in chars.by_ref() {
+        // Final bytes, every other one is a parameter or intermediate
+        if ('\x40'..='\x7e').contains(&c) {
+            return (code, Some(c));
+        }
+        code.push(c);
+    }
+    (code, None)
+}
+
+/// Changes made by the parameters of an SGR sequence, in order
+fn parse_sgr(code: &str, mode: ParseMode) -> Result<Vec<AnsiColor>, String> {
+    let mut changes = Vec::new();
+    let mut params = code.split(';');
+
+    while let Some(param) = params.next() {
+        match sgr_param(param, &mut params) {
+            Ok(change) => changes.extend(change),
+            Err(reason) if mode == ParseMode::Strict => return Err(reason),
+            Err(_) => {}
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Change made by one SGR parameter, 38 and 48 take the parameters of
+/// their color from `rest`
+fn sgr_param(
+    param: &str,
+    rest: &mut std::str::Split<char>,
+) -> Result<Option<AnsiColor>, String> {
+    if param.contains(':') {
+        let mut sub = param.split(':');
+        let kind = sub.next().unwrap_or_default();
+        if kind != "38" && kind != "48" {
+            // Sub-parameters of other codes, like underline styles, aren't
+            // drawn
+            return Ok(None);
+        }
+        let color = extended_color(&sub.collect::<Vec<_>>(), true);
+        return color_change(kind, color);
+    }
+
+    // An empty parameter means 0
+    let code = match param {
+        "" => 0,
+        param => param
+            .parse::<u8>()
+            .map_err(|_| format!("`{param}` isn't a valid SGR parameter"))?,
+    };
+    let change = match code {
+        0 => AnsiColor::Reset,
+        1 => AnsiColor::Bold(true),
+        22 => AnsiColor::Bold(false),
+        4 => AnsiColor::Underline(true),
+        24 => AnsiColor::Underline(false),
+        39 => AnsiColor::DefaultForeground,
+        49 => AnsiColor::DefaultBackground,
+        38 | 48 => {
+            // The color takes the next 2 (`5;n`) or 4 (`2;R;G;B`)
+            // parameters
+            let params: Vec<&str> = match rest.clone().next() {
+                Some("5") => rest.take(2).collect(),
+                Some("2") => rest.take(4).collect(),
+                _ => Vec::new(),
+            };
+            return color_change(param, extended_color(&params, false));
+        }
+        code => match palette::sgr_color(code) {
+            Some((true, color)) => AnsiColor::Foreground(rgb(color)),
+            Some((false, color)) => AnsiColor::Background(rgb(color)),
+            // Italics, blinking and the like aren't drawn
+            None => return Ok(None),
+        },
+    };
+    Ok(Some(change))
+}
+
+/// Color of the parameters following 38 or 48: `5, n` or `2, R, G, B`.
+/// The colon syntax puts a color space id before R, G and B, often empty
+fn extended_color(params: &[&str], colon: bool) -> Option<Rgba<u8>> {
+    let number = |i: usize| params.get(i)?.parse::<u8>().ok();
+    match params.first() {
+        Some(&"5") => Some(rgb(palette::xterm_color(number(1)?))),
+        Some(&"2") => {
+            let start = if colon && params.len() >= 5 { 2 } else { 1 };
+            let channel = |i: usize| number(start + i);
+            Some(rgb([channel(0)?, channel(1)?, channel(2)?]))
+        }
+        _ => None,
+    }
+}
+
+/// Change made by a 38 (foreground) or 48 (background) color
+fn color_change(
+    kind: &str,
+    color: Option<Rgba<u8>>,
+) -> Result<Option<AnsiColor>, String> {
+    let color = color
+        .ok_or_else(|| format!("`{kind}` needs `5;n` or `2;R;G;B` after it"))?;
+    Ok(Some(if kind == "38" {
+        AnsiColor::Foreground(color)
+    } else {
+        AnsiColor::Background(color)
+    }))
+}
+
+fn rgb([r, g, b]: [u8; 3]) -> Rgba<u8> {
+    Rgba([r, g, b, 255])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strict_error(text: &str) -> ParseError {
+        match parse(text, ParseMode::Strict) {
+            Ok(_) => panic!("{text:?} should be refused"),
+            Err(e) => e,
+        }
+    }
+
+    fn lossy_line(line: &str) -> Vec<Cell> {
+        parse_line(line, ParseMode::Lossy).expect("lossy mode never fails")
+    }
+
+    fn text(cells: &[Cell]) -> String {
+        cells.iter().flat_map(Cell::chars).collect()
+    }
+
+    #[test]
+    fn escape_without_bracket() {
+        let e = strict_error("plain\nab\x1b(Bcd");
+        assert_eq!((e.line, e.column), (2, 3));
+        assert!(e.reason.contains('['));
+
+        assert_eq!(text(&lossy_line("ab\x1b(Bcd")), "abBcd");
+    }
+
+    #[test]
+    fn unterminated_csi() {
+        let e = strict_error("x\x1b[38;2;1");
+        assert_eq!((e.line, e.column), (1, 2));
+
+        assert_eq!(text(&lossy_line("x\x1b[38;2;1")), "x");
+    }
+
+    #[test]
+    fn non_numeric_parameter() {
+        // Columns count characters, not bytes
+        let e = strict_error("é\x1b[1;?;4mA");
+        assert_eq!((e.line, e.column), (1, 2));
+        assert!(e.reason.contains("`?`"));
+
+        let cells = lossy_line("é\x1b[1;?;4mA");
+        assert_eq!(text(&cells), "éA");
+        assert!(cells[1].bold && cells[1].underline);
+    }
+
+    #[test]
+    fn indexed_color_without_index() {
+        let e = strict_error("ok\n\n\x1b[38;5mA");
+        assert_eq!((e.line, e.column), (3, 1));
+
+        let cells = lossy_line("\x1b[38;5mA");
+        assert_eq!(text(&cells), "A");
+        assert_eq!(cells[0].fg, DEFAULT_FOREGROUND);
+    }
+
+    #[test]
+    fn combined_truecolor() {
+        let cells = lossy_line("\x1b[1m\x1b[0;38;2;10;20;30mA");
+        assert_eq!(cells[0].fg, Rgba([10, 20, 30, 255]));
+        // The leading 0 reset the bold set before
+        assert!(!cells[0].bold);
+    }
+
+    #[test]
+    fn colon_truecolor() {
+        let cells = lossy_line("\x1b[38:2::10:20:30;48:2:40:50:60mA");
+        assert_eq!(cells[0].fg, Rgba([10, 20, 30, 255]));
+        assert_eq!(cells[0].bg, Some(Rgba([40, 50, 60, 255])));
+    }
+
+    #[test]
+    fn indexed_colors() {
+        let cells = lossy_line("\x1b[38;5;196;48;5;232mA\x1b[38;5;9mB");
+        assert_eq!(cells[0].fg, Rgba([255, 0, 0, 255]));
+        assert_eq!(cells[0].bg, Some(Rgba([8, 8, 8, 255])));
+        assert_eq!(cells[1].fg, Rgba([255, 85, 85, 255]));
+    }
+
+    #[test]
+    fn sixteen_colors() {
+        let cells = lossy_line("\x1b[31;102mA\x1b[39;49mB");
+        assert_eq!(cells[0].fg, Rgba([170, 0, 0, 255]));
+        assert_eq!(cells[0].bg, Some(Rgba([85, 255, 85, 255])));
+        assert_eq!(cells[1].fg, DEFAULT_FOREGROUND);
+        assert_eq!(cells[1].bg, None);
+    }
+
+    #[test]
+    fn strip_every_csi() {
+        let line = "\x1b[2K\x1b[1;31mab\x1b[0m\x1b[3Cc\x1b[?25l";
+        assert_eq!(strip_escapes(line), "abc");
+        assert_eq!(visible_width(line), 3);
+    }
+}
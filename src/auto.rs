@@ -0,0 +1,260 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use poise::serenity_prelude::{
+    Context as SerenityContext, CreateAllowedMentions, CreateAttachment,
+    CreateMessage, Mentionable, Message, Reaction, ReactionType, UserId,
+};
+
+use tracing::{info, instrument};
+
+use crate::{
+    Data, Res,
+    commands::{DOWNSCALED_NOTE, TILED_NOTE},
+    config::config,
+    interactions::{CachedRender, render_buttons},
+    links::{MessageImage, message_image},
+    moderation,
+    options::{Assets, GuildSettings, RenderOptions},
+    render::{output_name, render_blocking, tile_name},
+    tiers::tier,
+};
+
+/// Last conversion of each channel or user, so a flood of images or
+/// reactions only gets one reply per cooldown
+pub struct Cooldowns<K>(Mutex<HashMap<K, Instant>>);
+
+impl<K: Hash + Eq> Cooldowns<K> {
+    pub fn new() -> Self {
+        Self(Mutex::new(HashMap::new()))
+    }
+
+    /// Whether `key` may convert again, starting its cooldown if so
+    fn try_start(&self, key: K, cooldown: Duration) -> bool {
+        let mut last = self.0.lock().unwrap();
+        let now = Instant::now();
+        // Keys past their cooldown are forgotten, keeping the map small
+        last.retain(|_, at| now.duration_since(*at) < cooldown);
+        if last.contains_key(&key) {
+            return false;
+        }
+        last.insert(key, now);
+        true
+    }
+}
+
+/// Convert the first image of messages posted in watched channels
+pub async fn on_message(
+    ctx: &SerenityContext,
+    data: &Data,
+    message: &Message,
+) -> Res<()> {
+    if !config().watch_channels || message.author.bot {
+        return Ok(());
+    }
+    let Some(image) = message_image(message) else {
+        return Ok(());
+    };
+    if message.guild_id.is_none()
+        || !data.storage.is_watched(message.channel_id).await?
+    {
+        return Ok(());
+    }
+    let cooldown = Duration::from_secs(config().watch_cooldown);
+    if !data.channel_cooldowns.try_start(message.channel_id, cooldown) {
+        return Ok(());
+    }
+
+    convert_and_reply(ctx, data, message, &image, message.author.id).await
+}
+
+/// Convert the first image of a message someone reacted to with the
+/// configured emoji
+pub async fn on_reaction(
+    ctx: &SerenityContext,
+    data: &Data,
+    reaction: &Reaction,
+) -> Res<()> {
+    let Some(trigger) = &config().reaction_trigger else {
+        return Ok(());
+    };
+    let Some(user) = reaction.user_id else {
+        return Ok(());
+    };
+    if reaction.guild_id.is_none()
+        || user == ctx.cache.current_user().id
+        || !same_emoji(&reaction.emoji, trigger)
+    {
+        return Ok(());
+    }
+    let cooldown = Duration::from_secs(config().user_cooldown);
+    if !data.reaction_cooldowns.try_start(user, cooldown) {
+        return Ok(());
+    }
+
+    let message = reaction.message(ctx).await?;
+    let Some(image) = message_image(&message) else {
+        return Ok(());
+    };
+    convert_and_reply(ctx, data, &message, &image, user).await
+}
+
+/// Whether `emoji` is the one configured as `trigger`, a unicode emoji or
+/// a custom one written `<:name:id>`
+fn same_emoji(emoji: &ReactionType, trigger: &str) -> bool {
+    match (emoji, ReactionType::try_from(trigger)) {
+        (
+            ReactionType::Custom { id, .. },
+            Ok(ReactionType::Custom { id: trigger, .. }),
+        ) => *id == trigger,
+        (ReactionType::Unicode(emoji), _) => emoji == trigger,
+        _ => false,
+    }
+}
+
+/// Render `image` with the defaults of `user`, who posted or reacted to
+/// it, and reply to `message` with it. Nobody ran a command, so refusals
+/// (moderation, quotas, a full queue, a broken image) skip the message
+/// quietly
+#[instrument(skip_all, fields(channel = %message.channel_id))]
+async fn convert_and_reply(
+    ctx: &SerenityContext,
+    data: &Data,
+    message: &Message,
+    image: &MessageImage<'_>,
+    user: UserId,
+) -> Res<()> {
+    let guild_id = message.guild_id;
+    let storage = &data.storage;
+    if moderation::check(storage, user, guild_id).await.is_err() {
+        return Ok(());
+    }
+    let Ok(_job) = data.shutdown.start() else {
+        return Ok(());
+    };
+    let tier = tier(ctx, user, &[]).await;
+    let Ok(ticket) = data.queue.join(tier) else {
+        info!("Queue full, skipping an image");
+        return Ok(());
+    };
+
+    let guild = match guild_id {
+        Some(guild_id) => storage.guild(guild_id).await?,
+        None => GuildSettings::default(),
+    };
+    // Counted before the render waits for a worker, given back if it fails
+    let reservation = match guild_id {
+        Some(guild_id) => {
+            match storage.reserve_quota(guild_id, user, &guild).await {
+                Ok(reservation) => reservation,
+                Err(e) => {
+                    info!("Skipping an image: {e}");
+                    return Ok(());
+                }
+            }
+        }
+        None => None,
+    };
+    let prefs = storage
+        .user(user)
+        .await?
+        .or(guild.defaults.clone())
+        .or(RenderOptions::context_menu().defaults);
+    let prefs = tier.limit(guild.apply_caps(prefs));
+
+    let image_bytes: Arc<[u8]> = match image.download().await {
+        Ok(bytes) => bytes.into(),
+        Err(e) => {
+            info!("Couldn't download an image: {e}");
+            storage.refund_quota(reservation).await?;
+            return Ok(());
+        }
+    };
+    let cache = &data.result_cache;
+    let assets = Assets::default();
+    let key = cache.key(&image_bytes, &prefs, &assets, false);
+    let hit = match key {
+        Some(key) => cache.get(key).await,
+        None => None,
+    };
+    let rendered = match hit {
+        Some(rendered) => rendered,
+        None => {
+            let _worker = ticket.start().await;
+            let started = Instant::now();
+            let rendered = render_blocking(
+                image_bytes.clone(),
+                prefs.clone(),
+                assets.clone(),
+                false,
+            )
+            .await;
+            let rendered = match rendered {
+                Ok(rendered) => rendered,
+                Err(e) => {
+                    info!("Couldn't convert an image: {e}");
+                    storage.refund_quota(reservation).await?;
+                    return Ok(());
+                }
+            };
+            let output_bytes = rendered.images.iter().map(Vec::len).sum();
+            data.stats.record_render(started.elapsed(), output_bytes);
+            if let Some(key) = key {
+                cache.insert(key, &rendered).await;
+            }
+            rendered
+        }
+    };
+
+    let source_name = image.name();
+    let spoiler = source_name.starts_with("SPOILER_");
+    let name = output_name(
+        &source_name,
+        &prefs,
+        false,
+        spoiler,
+        rendered.format.extension(),
+    );
+    let count = rendered.images.len();
+    let mut reply = CreateMessage::new()
+        .reference_message(message)
+        .allowed_mentions(CreateAllowedMentions::new());
+    for (i, tile) in rendered.images.into_iter().enumerate() {
+        reply = reply.add_file(CreateAttachment::bytes(
+            tile,
+            tile_name(&name, i, count),
+        ));
+    }
+    let mut notes = Vec::new();
+    if count > 1 {
+        notes.push(TILED_NOTE.to_string());
+    } else if rendered.downscaled {
+        notes.push(DOWNSCALED_NOTE.to_string());
+    }
+    if user != message.author.id {
+        notes.push(format!("-# Requested by {}", user.mention()));
+    }
+    if !notes.is_empty() {
+        reply = reply.content(notes.join("\n"));
+    }
+
+    let id = data.render_cache.insert(CachedRender {
+        image_bytes,
+        prefs,
+        source_name,
+        spoiler,
+        collage: false,
+        assets,
+        ascii_art: rendered.ascii_art,
+        owner: user,
+    });
+    message
+        .channel_id
+        .send_message(ctx, reply.components(render_buttons(id)))
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,109 @@
+//! Convert a local image on the command line, with the same renderer as the
+//! bot, so rendering changes can be tried without a Discord token
+
+use std::{env, fs, path::PathBuf, process::ExitCode};
+
+use ascii_bot::{AsciiRenderer, Res};
+
+const USAGE: &str = "\
+usage: ascii-cli <image> [options]
+
+options:
+  -o, --output <file>     write a PNG there, or the colored text when it
+                          ends in .ans or .txt (default: <image>_ascii.png)
+  -c, --charset <chars>   characters from darkest to brightest
+  -w, --width <columns>   maximum width in characters
+  -b, --background <0-1>  background brightness
+      --ansi              print the colored text to stdout instead";
+
+struct Args {
+    input: PathBuf,
+    output: Option<PathBuf>,
+    charset: Option<String>,
+    width: Option<u32>,
+    background: Option<f32>,
+    ansi: bool,
+}
+
+fn main() -> ExitCode {
+    if env::args().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{USAGE}");
+        return ExitCode::SUCCESS;
+    }
+
+    match parse_args().and_then(run) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}\n\n{USAGE}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: Args) -> Res<()> {
+    let mut builder = AsciiRenderer::builder();
+    if let Some(charset) = &args.charset {
+        builder = builder.charset(charset);
+    }
+    if let Some(width) = args.width {
+        builder = builder.max_width(width);
+    }
+    if let Some(background) = args.background {
+        builder = builder.background_brightness(background);
+    }
+    let renderer = builder.build()?;
+
+    let ascii_art = renderer.process_image(&fs::read(&args.input)?)?;
+    if args.ansi {
+        print!("{ascii_art}");
+        return Ok(());
+    }
+
+    let output = args.output.unwrap_or_else(|| {
+        let stem = args.input.file_stem().unwrap_or_default();
+        let stem = stem.to_string_lossy();
+        args.input.with_file_name(format!("{stem}_ascii.png"))
+    });
+    match output.extension().and_then(|e| e.to_str()) {
+        Some("ans" | "txt") => fs::write(&output, ascii_art)?,
+        _ => fs::write(&output, renderer.render_to_png(&ascii_art)?)?,
+    }
+
+    println!("{}", output.display());
+    Ok(())
+}
+
+fn parse_args() -> Res<Args> {
+    let mut args = env::args().skip(1);
+    let mut input = None;
+    let mut parsed = Args {
+        input: PathBuf::new(),
+        output: None,
+        charset: None,
+        width: None,
+        background: None,
+        ansi: false,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value =
+            || args.next().ok_or_else(|| format!("{arg} needs a value"));
+        match arg.as_str() {
+            "-o" | "--output" => parsed.output = Some(value()?.into()),
+            "-c" | "--charset" => parsed.charset = Some(value()?),
+            "-w" | "--width" => parsed.width = Some(value()?.parse()?),
+            "-b" | "--background" => {
+                parsed.background = Some(value()?.parse()?)
+            }
+            "--ansi" => parsed.ansi = true,
+            _ if arg.starts_with('-') => {
+                return Err(format!("unknown option {arg}").into());
+            }
+            _ if input.is_none() => input = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unexpected argument {arg}").into()),
+        }
+    }
+
+    parsed.input = input.ok_or("no image given")?;
+    Ok(parsed)
+}
@@ -0,0 +1,169 @@
+use std::fmt::Write;
+
+use image::{
+    Rgba, RgbaImage,
+    imageops::{self, FilterType},
+};
+
+/// Text modes drawing with block characters instead of a charset
+#[derive(Clone, Copy)]
+pub enum BlockMode {
+    /// `▀` cells, two vertical pixels each
+    Half,
+    /// Quadrant cells like `▚`, a 2x2 pixel block each
+    Quadrant,
+}
+
+impl BlockMode {
+    /// Pixels of the image shown by each cell, across and down
+    pub fn pixels(self) -> (u32, u32) {
+        match self {
+            BlockMode::Half => (1, 2),
+            BlockMode::Quadrant => (2, 2),
+        }
+    }
+}
+
+/// Quadrant glyphs indexed by their mask: top left 1, top right 2,
+/// bottom left 4 and bottom right 8
+const QUADRANTS: [char; 16] = [
+    ' ', '▘', '▝', '▀', '▖', '▌', '▞', '▛', '▗', '▚', '▐', '▜', '▄', '▙', '▟',
+    '█',
+];
+
+/// Convert an image into colored block characters, `columns` by `rows`
+/// cells
+pub fn make_blocks(
+    image: &RgbaImage,
+    columns: u32,
+    rows: u32,
+    mode: BlockMode,
+) -> String {
+    match mode {
+        BlockMode::Half => half_blocks(image, columns, rows),
+        BlockMode::Quadrant => quadrant_blocks(image, columns, rows),
+    }
+}
+
+/// The upper half block painted with the top pixel as foreground and the
+/// bottom one as background doubles the vertical resolution
+fn half_blocks(image: &RgbaImage, columns: u32, rows: u32) -> String {
+    let pixels =
+        imageops::resize(image, columns, rows * 2, FilterType::Lanczos3);
+    let mut art = String::with_capacity((columns * rows * 40) as usize);
+
+    for row in 0..rows {
+        for x in 0..columns {
+            let top = *pixels.get_pixel(x, row * 2);
+            let bottom = *pixels.get_pixel(x, row * 2 + 1);
+            push_cell(&mut art, '▀', top, bottom);
+        }
+        art.push_str("\x1b[0m\n");
+    }
+
+    art
+}
+
+/// Every cell picks the quadrant glyph and color pair that reproduce its
+/// 2x2 pixels with the least error
+fn quadrant_blocks(image: &RgbaImage, columns: u32, rows: u32) -> String {
+    let pixels =
+        imageops::resize(image, columns * 2, rows * 2, FilterType::Lanczos3);
+    let mut art = String::with_capacity((columns * rows * 40) as usize);
+
+    for row in 0..rows {
+        for x in 0..columns {
+            let block = [
+                *pixels.get_pixel(x * 2, row * 2),
+                *pixels.get_pixel(x * 2 + 1, row * 2),
+                *pixels.get_pixel(x * 2, row * 2 + 1),
+                *pixels.get_pixel(x * 2 + 1, row * 2 + 1),
+            ];
+            let (mask, fg, bg) = best_quadrant(&block);
+            push_cell(&mut art, QUADRANTS[mask], fg, bg);
+        }
+        art.push_str("\x1b[0m\n");
+    }
+
+    art
+}
+
+/// Try every split of the block into a foreground and a background set,
+/// each painted with the mean color of its pixels
+fn best_quadrant(block: &[Rgba<u8>; 4]) -> (usize, Rgba<u8>, Rgba<u8>) {
+    // A full block of the mean color is the split with an empty background
+    let full = mean(block.iter());
+    let mut best = (15, full, full);
+    let mut best_error: u32 = block.iter().map(|p| distance(*p, full)).sum();
+
+    // Complementary masks give the same split with swapped colors, so
+    // leaving the bottom right pixel out is enough
+    for mask in 1..8 {
+        let fg = mean(pixels_in(block, mask, true));
+        let bg = mean(pixels_in(block, mask, false));
+        let error = block
+            .iter()
+            .enumerate()
+            .map(|(i, pixel)| {
+                let target = if mask & (1 << i) != 0 { fg } else { bg };
+                distance(*pixel, target)
+            })
+            .sum();
+
+        if error < best_error {
+            best_error = error;
+            best = (mask, fg, bg);
+        }
+    }
+
+    best
+}
+
+fn pixels_in(
+    block: &[Rgba<u8>; 4],
+    mask: usize,
+    inside: bool,
+) -> impl Iterator<Item = &Rgba<u8>> {
+    block
+        .iter()
+        .enumerate()
+        .filter(move |(i, _)| (mask & (1 << i) != 0) == inside)
+        .map(|(_, pixel)| pixel)
+}
+
+/// Mean color of the pixels, black when there are none
+fn mean<'a>(pixels: impl Iterator<Item = &'a Rgba<u8>>) -> Rgba<u8> {
+    let mut sum = [0u32; 3];
+    let mut count = 0;
+
+    for pixel in pixels {
+        for (total, channel) in sum.iter_mut().zip(pixel.0) {
+            *total += channel as u32;
+        }
+        count += 1;
+    }
+
+    if count == 0 {
+        return Rgba([0, 0, 0, 255]);
+    }
+    let [r, g, b] = sum.map(|total| (total / count) as u8);
+    Rgba([r, g, b, 255])
+}
+
+/// Squared distance between two colors
+fn distance(a: Rgba<u8>, b: Rgba<u8>) -> u32 {
+    a.0.iter()
+        .zip(b.0)
+        .take(3)
+        .map(|(&x, y)| (x as i32 - y as i32).pow(2) as u32)
+        .sum()
+}
+
+/// Append a character with truecolor foreground and background escapes
+fn push_cell(art: &mut String, ch: char, fg: Rgba<u8>, bg: Rgba<u8>) {
+    let _ = write!(
+        art,
+        "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m{ch}",
+        fg[0], fg[1], fg[2], bg[0], bg[1], bg[2]
+    );
+}
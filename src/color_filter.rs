@@ -0,0 +1,88 @@
+use image::Rgba;
+
+/// Transform applied to the color of every cell before it's drawn
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorFilter {
+    /// Brown tones of old photographs
+    Sepia,
+    /// Brightness mapped between a shadow and a highlight color
+    Duotone { shadows: [u8; 3], highlights: [u8; 3] },
+    /// Colors as seen without red cones
+    Protanopia,
+    /// Without green cones, the most common color blindness
+    Deuteranopia,
+    /// Without blue cones
+    Tritanopia,
+}
+
+/// Classic sepia tone matrix, on sRGB values
+const SEPIA: [[f32; 3]; 3] =
+    [[0.393, 0.769, 0.189], [0.349, 0.686, 0.168], [0.272, 0.534, 0.131]];
+
+/// Color blindness simulations of Machado, Oliveira and Fernandes (2009)
+/// at full severity, on linear RGB
+const PROTANOPIA: [[f32; 3]; 3] = [
+    [0.152286, 1.052583, -0.204868],
+    [0.114503, 0.786281, 0.099216],
+    [-0.003882, -0.048116, 1.051998],
+];
+const DEUTERANOPIA: [[f32; 3]; 3] = [
+    [0.367322, 0.860646, -0.227968],
+    [0.280085, 0.672501, 0.047413],
+    [-0.011820, 0.042940, 0.968881],
+];
+const TRITANOPIA: [[f32; 3]; 3] = [
+    [1.255528, -0.076749, -0.178779],
+    [-0.078411, 0.930809, 0.147602],
+    [0.004733, 0.691367, 0.303900],
+];
+
+impl ColorFilter {
+    pub fn apply(self, color: Rgba<u8>) -> Rgba<u8> {
+        let rgb = [color[0], color[1], color[2]].map(|c| c as f32);
+        let [r, g, b] = match self {
+            ColorFilter::Sepia => {
+                SEPIA.map(|row| dot(row, rgb).round().min(255.0) as u8)
+            }
+            ColorFilter::Duotone { shadows, highlights } => {
+                let luma = dot([0.299, 0.587, 0.114], rgb) / 255.0;
+                [0, 1, 2].map(|i| {
+                    let (from, to) = (shadows[i] as f32, highlights[i] as f32);
+                    (from + (to - from) * luma).round() as u8
+                })
+            }
+            ColorFilter::Protanopia => simulate(&PROTANOPIA, rgb),
+            ColorFilter::Deuteranopia => simulate(&DEUTERANOPIA, rgb),
+            ColorFilter::Tritanopia => simulate(&TRITANOPIA, rgb),
+        };
+        Rgba([r, g, b, color[3]])
+    }
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+/// Apply a color blindness matrix, which works on light rather than on
+/// sRGB values
+fn simulate(matrix: &[[f32; 3]; 3], rgb: [f32; 3]) -> [u8; 3] {
+    let linear = rgb.map(to_linear);
+    matrix.map(|row| from_linear(dot(row, linear)))
+}
+
+/// sRGB channel, from 0 to 255, to linear light, from 0 to 1
+fn to_linear(channel: f32) -> f32 {
+    let c = channel / 255.0;
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+/// Inverse of [`to_linear`], out of gamut values are clamped
+fn from_linear(c: f32) -> u8 {
+    let c = c.clamp(0.0, 1.0);
+    let srgb = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (srgb * 255.0).round() as u8
+}
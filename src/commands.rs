@@ -1,102 +1,1352 @@
-use std::time::Duration;
+use std::{
+    num::NonZeroU64,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use image::RgbaImage;
+use crate::{
+    ApplicationContext, Context, Data, Error, Res,
+    ans_file::{Sauce, from_ans_file, to_ans_file},
+    ansi::{self, ParseMode},
+    config::{Config, config},
+    embed,
+    help::{HELP_TEXT, help_pages},
+    i18n::help_text,
+    image_to_ascii::{
+        AsciiRenderer, MAX_FONT_FILE_SIZE, decode_image, load_font_file,
+    },
+    interactions::{CachedRender, render_buttons},
+    links::{MessageImage, message_image},
+    metadata::{embedded_options, embedded_original},
+    options::{
+        Assets, Effect, Filter, Fit, FontChoice, GradientDirection,
+        GuildSettings, Mode, Orientation, OutputFormat, Preferences,
+        RenderOptions, Style, TextFile, Thumbnail, Visibility, WebhookOutput,
+        clean_caption, clean_charset, parse_background_brightness,
+        parse_duotone, parse_gradient, parse_palette_option,
+    },
+    queue::Worker,
+    render::{
+        MESSAGE_LIMIT, UPLOAD_LIMIT, html_export, output_name, render_blocking,
+        tile_name,
+    },
+    stats::format_duration,
+    storage::{List, Target},
+    tiers::{Tier, command_tier},
+};
 
-use crate::{Context, Error, Res, image_to_ascii::AsciiRenderer};
+use tracing::{Instrument, Span, field, info, info_span, warn};
 
 use poise::{
-    command,
-    serenity_prelude::{Attachment, CreateAttachment, Message, User},
+    CreateReply, Modal, ReplyHandle, command,
+    serenity_prelude::{
+        Attachment, Builder, CreateAllowedMentions, CreateAttachment,
+        CreateEmbed, CreateMessage, CreateWebhook, ExecuteWebhook,
+        GuildChannel, GuildId, Mentionable, Message, User,
+    },
 };
 
 #[command(
     slash_command,
+    category = "Render",
     install_context = "Guild|User",
     interaction_context = "Guild|BotDm|PrivateChannel"
 )]
 pub async fn image_to_ascii(
     ctx: Context<'_>,
     #[description = "Image to convert to ASCII"] attachment: Attachment,
-    #[description = "Custom charset (Max 20 chars)"] charset: Option<String>,
-    #[description = "A Brightness percentage for the background (Default 20%)"]
-    background_brightness: Option<u32>,
-    #[description = "Sets the maximum size of the image (Accepts up to 200)"]
+    #[description = "Custom charset (Max 20 chars), or auto to pick one"]
+    charset: Option<String>,
+    #[description = "Background brightness percentage, or auto (Default 20%)"]
+    background_brightness: Option<String>,
+    #[description = "Width in characters (Up to 200, 400 for supporters)"]
+    #[min = 1]
+    #[max = 400]
     max_size: Option<u32>,
+    #[description = "Maximum number of rows (Up to 200, 400 for supporters)"]
+    #[min = 1]
+    #[max = 400]
+    max_height: Option<u32>,
+    #[description = "Keep proportions, or pad to max_size x max_height"]
+    fit: Option<Fit>,
+    #[description = "Color style (Default Mixed)"] style: Option<Style>,
+    #[description = "Also attach the colored text as an .ans file or web page"]
+    text_file: Option<TextFile>,
+    #[description = "Rotate or flip the image first, for sideways photos"]
+    orientation: Option<Orientation>,
+    #[description = "Send as a spoiler, or only to you (Default Public)"]
+    visibility: Option<Visibility>,
+    #[description = "Show four different looks side by side (Default false)"]
+    collage: Option<bool>,
+    #[description = "Leave the background transparent (Default false)"]
+    transparent: Option<bool>,
+    #[description = "Font the art is drawn with (Default Roboto Mono)"]
+    font: Option<FontChoice>,
+    #[description = "Your own TTF/OTF font to draw with (Max 4 MB)"]
+    font_file: Option<Attachment>,
+    #[description = "Image drawn, blurred and darkened, behind the art"]
+    background_image: Option<Attachment>,
+    #[description = "Pixel size of each character, 12 to 36 (Default 18)"]
+    #[min = 12]
+    #[max = 36]
+    font_size: Option<u32>,
+    #[description = "Post-processing applied to the image (Default None)"]
+    effect: Option<Effect>,
+    #[description = "Image format (Default WebP for photos, PNG otherwise)"]
+    format: Option<OutputFormat>,
+    #[description = "Draw with the charset or with block characters"]
+    mode: Option<Mode>,
+    #[description = "Dither gradients, for short charsets (Default false)"]
+    dither: Option<bool>,
+    #[description = "Gamma correction, above 1 brightens (Default 1.0)"]
+    #[min = 0.1]
+    #[max = 5.0]
+    gamma: Option<f32>,
+    #[description = "Contrast change in percent, -100 to 100 (Default 0)"]
+    #[min = -100]
+    #[max = 100]
+    contrast: Option<i32>,
+    #[description = "Brightness change in percent, -100 to 100 (Default 0)"]
+    #[min = -100]
+    #[max = 100]
+    brightness: Option<i32>,
+    #[description = "Comma separated hex colors, or ansi16 for 16 colors"]
+    palette: Option<String>,
+    #[description = "Line of text shown under the art (Max 100 chars)"]
+    #[max_length = 100]
+    caption: Option<String>,
 ) -> Result<(), Error> {
-    let background_brightness =
-        background_brightness.unwrap_or(20).clamp(0, 100);
-    let size = max_size.unwrap_or(150);
-    let charset = charset.map(|mut c| {
-        c.truncate(20);
-        c
-    });
+    let span = command_span(ctx);
+    let features = &config().features;
+    if font_file.is_some() && !features.custom_fonts {
+        return Err("Custom fonts are turned off on this bot".into());
+    }
+    if background_image.is_some() && !features.background_images {
+        return Err("Background images are turned off on this bot".into());
+    }
+    if collage == Some(true) && !features.collages {
+        return Err("Collages are turned off on this bot".into());
+    }
 
-    _image_to_ascii(
-        ctx,
-        &attachment.download().await?,
-        charset.as_deref(),
-        background_brightness as f32 / 100.0,
-        size,
-    )
-    .await
+    let charset = charset.as_deref().map(clean_charset).transpose()?;
+    let background_brightness = background_brightness
+        .as_deref()
+        .map(parse_background_brightness)
+        .transpose()?;
+    let (palette, ansi16) = parse_palette_option(palette.as_deref())?;
+    let caption = caption.as_deref().map(clean_caption);
+    let custom_font = match font_file {
+        Some(file) if file.size > MAX_FONT_FILE_SIZE => {
+            return Err("Font files are limited to 4 MB".into());
+        }
+        Some(file) => Some(load_font_file(download(&span, &file).await?)?),
+        None => None,
+    };
+    let background = match background_image {
+        Some(file) => {
+            let bytes = download(&span, &file).await?;
+            let budget = config().pixel_budget;
+            let image = tokio::task::spawn_blocking(move || {
+                Res::Ok(decode_image(&bytes, budget)?.to_rgba8())
+            })
+            .await??;
+            Some(Arc::new(image))
+        }
+        None => None,
+    };
+
+    let options = RenderOptions {
+        overrides: Preferences {
+            transparent,
+            font,
+            font_size,
+            effect,
+            format,
+            mode,
+            dither,
+            gamma,
+            contrast,
+            brightness,
+            palette,
+            ansi16,
+            max_height,
+            fit,
+            caption,
+            orientation,
+            ..Preferences::new(charset, max_size, style, background_brightness)
+        },
+        ansi_file: text_file.is_some_and(TextFile::ansi),
+        html_file: text_file.is_some_and(TextFile::html),
+        ephemeral: matches!(visibility, Some(Visibility::OnlyMe)),
+        spoiler: matches!(visibility, Some(Visibility::Spoiler)),
+        source_name: attachment.filename.clone(),
+        spoilered_source: attachment.filename.starts_with("SPOILER_"),
+        collage: collage.unwrap_or(false),
+        assets: Assets { font: custom_font, background },
+        ..Default::default()
+    };
+
+    // Ephemeral renders are private, so they aren't offered to the channel
+    let remembered = (!options.ephemeral).then(|| options.overrides.clone());
+    let image_bytes = download(&span, &attachment).await?;
+    _image_to_ascii(ctx, image_bytes, options).instrument(span).await?;
+    if let Some(overrides) = remembered {
+        let storage = &ctx.data().storage;
+        storage.set_channel_options(ctx.channel_id(), &overrides).await?;
+    }
+    Ok(())
+}
+
+/// Convert an image with the options of the last `/image_to_ascii` run in
+/// this channel, for groups iterating on a look
+#[command(
+    slash_command,
+    category = "Render",
+    install_context = "Guild|User",
+    interaction_context = "Guild|BotDm|PrivateChannel"
+)]
+pub async fn same_as_last(
+    ctx: Context<'_>,
+    #[description = "Image to convert to ASCII"] attachment: Attachment,
+    #[description = "Only show the result to you (Default false)"]
+    ephemeral: Option<bool>,
+) -> Res<()> {
+    let span = command_span(ctx);
+    let overrides = ctx
+        .data()
+        .storage
+        .channel_options(ctx.channel_id())
+        .await?
+        .ok_or("Nothing was converted with /image_to_ascii here yet")?;
+
+    let options = RenderOptions {
+        overrides,
+        ephemeral: ephemeral.unwrap_or(false),
+        source_name: attachment.filename.clone(),
+        spoilered_source: attachment.filename.starts_with("SPOILER_"),
+        ..Default::default()
+    };
+
+    let image_bytes = download(&span, &attachment).await?;
+    _image_to_ascii(ctx, image_bytes, options).instrument(span).await
+}
+
+/// Convert an image with the options a PNG render of the bot was made
+/// with, read back from the render itself
+#[command(
+    slash_command,
+    category = "Render",
+    install_context = "Guild|User",
+    interaction_context = "Guild|BotDm|PrivateChannel"
+)]
+pub async fn same_as_render(
+    ctx: Context<'_>,
+    #[description = "A PNG render of this bot, to copy the options of"]
+    render: Attachment,
+    #[description = "Image to convert to ASCII"] attachment: Attachment,
+    #[description = "Only show the result to you (Default false)"]
+    ephemeral: Option<bool>,
+) -> Res<()> {
+    let span = command_span(ctx);
+    let overrides = embedded_options(&download(&span, &render).await?)?
+        .ok_or("That file isn't a PNG render of this bot")?;
+
+    let options = RenderOptions {
+        overrides,
+        ephemeral: ephemeral.unwrap_or(false),
+        source_name: attachment.filename.clone(),
+        spoilered_source: attachment.filename.starts_with("SPOILER_"),
+        ..Default::default()
+    };
+
+    let image_bytes = download(&span, &attachment).await?;
+    _image_to_ascii(ctx, image_bytes, options).instrument(span).await
+}
+
+/// Largest text file accepted by `/ascii_to_image`
+const MAX_TEXT_FILE_SIZE: u32 = 1024 * 1024;
+/// Largest grid `/ascii_to_image` renders, in characters
+const MAX_TEXT_COLUMNS: usize = 400;
+const MAX_TEXT_ROWS: usize = 250;
+
+/// Render ANSI colored text back into an image
+#[command(
+    slash_command,
+    category = "Render",
+    install_context = "Guild|User",
+    interaction_context = "Guild|BotDm|PrivateChannel"
+)]
+pub async fn ascii_to_image(
+    ctx: Context<'_>,
+    #[description = "A .txt or .ans file containing ANSI colored text"]
+    file: Attachment,
+    #[description = "Only show the result to you (Default false)"]
+    ephemeral: Option<bool>,
+) -> Res<()> {
+    if file.size > MAX_TEXT_FILE_SIZE {
+        return Err("Text files are limited to 1 MB".into());
+    }
+
+    let ephemeral = ephemeral.unwrap_or(false);
+    if ephemeral {
+        ctx.defer_ephemeral().await?;
+    } else {
+        ctx.defer().await?;
+    }
+
+    let text = from_ans_file(&file.download().await?);
+    _ascii_to_image(ctx, &text, ParseMode::Strict, ephemeral).await
+}
+
+/// Form where ANSI colored text gets pasted
+#[derive(Modal)]
+#[name = "Paste ANSI text"]
+struct PasteAnsi {
+    #[name = "ANSI colored text"]
+    #[placeholder = "\\e[38;2;255;0;0mred \\e[0mplain"]
+    #[paragraph]
+    text: String,
+}
+
+/// Paste ANSI colored text and get it back as an image
+#[command(
+    slash_command,
+    category = "Render",
+    install_context = "Guild|User",
+    interaction_context = "Guild|BotDm|PrivateChannel"
+)]
+pub async fn paste_ansi(
+    ctx: ApplicationContext<'_>,
+    #[description = "Only show the result to you (Default false)"]
+    ephemeral: Option<bool>,
+) -> Res<()> {
+    let Some(PasteAnsi { text }) =
+        poise::execute_modal(ctx, None, None).await?
+    else {
+        return Ok(());
+    };
+
+    // The escape character doesn't survive most copy-pastes, so accept the
+    // usual ways of spelling it out
+    let text = ["\\x1b", "\\033", "\\u001b", "\\e", "^["]
+        .iter()
+        .fold(text, |text, escape| text.replace(escape, "\x1b"));
+
+    // Pasted text often carries stray escapes of the terminal it was copied
+    // from, so broken ones are skipped rather than refused
+    let ephemeral = ephemeral.unwrap_or(false);
+    _ascii_to_image(ctx.into(), &text, ParseMode::Lossy, ephemeral).await
+}
+
+async fn _ascii_to_image(
+    ctx: Context<'_>,
+    text: &str,
+    mode: ParseMode,
+    ephemeral: bool,
+) -> Res<()> {
+    let _job = ctx.data().shutdown.start()?;
+    let storage = &ctx.data().storage;
+    // Counted before queueing, given back if the render fails
+    let reservation = match ctx.guild_id() {
+        Some(guild_id) => {
+            let settings = storage.guild(guild_id).await?;
+            storage.reserve_quota(guild_id, ctx.author().id, &settings).await?
+        }
+        None => None,
+    };
+    let rendered = render_text(ctx, text, mode, ephemeral).await;
+    if rendered.is_err() {
+        storage.refund_quota(reservation).await?;
+    }
+    let (png, queued) = rendered?;
+
+    let mut reply = CreateReply::default()
+        .ephemeral(ephemeral)
+        .attachment(CreateAttachment::bytes(png, "ascii.png"));
+    if queued.is_some() {
+        reply = reply.content("");
+    }
+    send_result(ctx, queued, reply).await
+}
+
+/// Render ANSI colored `text` to a PNG once a worker is free
+async fn render_text<'a>(
+    ctx: Context<'a>,
+    text: &str,
+    mode: ParseMode,
+    ephemeral: bool,
+) -> Res<(Vec<u8>, Option<ReplyHandle<'a>>)> {
+    let tier = command_tier(ctx).await;
+    let (_worker, queued) = wait_for_worker(ctx, tier, ephemeral).await?;
+    let renderer =
+        AsciiRenderer::builder().max_width(MAX_TEXT_COLUMNS as u32).build()?;
+
+    let rows = text.lines().count();
+    let columns =
+        text.lines().map(|l| renderer.visible_width(l)).max().unwrap_or(0);
+
+    if rows == 0 || columns == 0 {
+        return Err("There is no text to render".into());
+    }
+    if rows > MAX_TEXT_ROWS || columns > MAX_TEXT_COLUMNS {
+        return Err(format!(
+            "Text is limited to {MAX_TEXT_COLUMNS}x{MAX_TEXT_ROWS} \
+             characters, this one is {columns}x{rows}"
+        )
+        .into());
+    }
+    // Points at the first broken escape, the renderer itself skips them
+    if mode == ParseMode::Strict {
+        ansi::parse(text, mode)?;
+    }
+
+    let text = text.to_string();
+    let span = Span::current();
+    let png = tokio::task::spawn_blocking(move || {
+        span.in_scope(|| renderer.render_to_png(&text))
+    })
+    .await??;
+    Ok((png, queued))
+}
+
+/// Save your default conversion options
+#[command(
+    slash_command,
+    install_context = "Guild|User",
+    interaction_context = "Guild|BotDm|PrivateChannel"
+)]
+pub async fn settings(
+    ctx: Context<'_>,
+    #[description = "Default charset (Max 20 chars)"] charset: Option<String>,
+    #[description = "Default background brightness percentage, or auto"]
+    background_brightness: Option<String>,
+    #[description = "Default width (Up to 200, 400 for supporters)"]
+    #[min = 1]
+    #[max = 400]
+    max_size: Option<u32>,
+    #[description = "Default style"] style: Option<Style>,
+    #[description = "Pixels between characters, up to 32"]
+    #[max = 32]
+    char_spacing: Option<u32>,
+    #[description = "Pixels between lines, up to 32"]
+    #[max = 32]
+    line_spacing: Option<u32>,
+    #[description = "Add a small \"made with ASCII-Bot\" line under renders"]
+    credit: Option<bool>,
+    #[description = "Inset a thumbnail of the source image into a corner"]
+    thumbnail: Option<Thumbnail>,
+    #[description = "Widen (above 1) or squash (below 1) the art, 0.25 to 4"]
+    #[min = 0.25]
+    #[max = 4.0]
+    stretch: Option<f32>,
+    #[description = "Background gradient colors, like #101030, #503070"]
+    background_gradient: Option<String>,
+    #[description = "Which way the background gradient runs"]
+    gradient_direction: Option<GradientDirection>,
+    #[description = "Sort your charset by ink, False keeps your order"]
+    sort_charset: Option<bool>,
+    #[description = "Recolor the art, or see it as with a color blindness"]
+    filter: Option<Filter>,
+    #[description = "Two hex colors for Duotone, like #202048, #ffd080"]
+    duotone: Option<String>,
+    #[description = "Levels per color channel, 2 to 32 for a flat look, 0 off"]
+    #[max = 32]
+    posterize: Option<u32>,
+    #[description = "Smooth out the speckles of noisy photos first"]
+    denoise: Option<bool>,
+    #[description = "Keep fine edges through the downscale, 0 to 200%"]
+    #[max = 200]
+    sharpen: Option<u32>,
+    #[description = "Embed the original in PNG renders, to restore it later"]
+    keep_original: Option<bool>,
+    #[description = "Forget all your saved defaults"] reset: Option<bool>,
+) -> Res<()> {
+    let storage = &ctx.data().storage;
+    let user_id = ctx.author().id;
+    let charset = charset.as_deref().map(clean_charset).transpose()?;
+    let background_brightness = background_brightness
+        .as_deref()
+        .map(parse_background_brightness)
+        .transpose()?;
+    let background_gradient =
+        background_gradient.as_deref().map(parse_gradient).transpose()?;
+    let duotone = duotone.as_deref().map(parse_duotone).transpose()?;
+    let changes = Preferences {
+        sort_charset,
+        char_spacing,
+        line_spacing,
+        credit,
+        thumbnail,
+        stretch,
+        background_gradient,
+        gradient_direction,
+        filter,
+        duotone,
+        posterize,
+        denoise,
+        sharpen,
+        keep_original,
+        ..Preferences::new(charset, max_size, style, background_brightness)
+    };
+
+    let saved = if reset.unwrap_or(false) {
+        storage.set_user(user_id, Preferences::default()).await?;
+        Preferences::default()
+    } else if changes.is_empty() {
+        storage.user(user_id).await?
+    } else {
+        let saved = changes.or(storage.user(user_id).await?);
+        storage.set_user(user_id, saved.clone()).await?;
+        saved
+    };
+
+    ctx.send(embed!(
+        title: "Your defaults",
+        description: saved.describe(),
+        ephemeral: true,
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Set server-wide default options and limits
+#[command(
+    slash_command,
+    install_context = "Guild",
+    interaction_context = "Guild",
+    default_member_permissions = "MANAGE_GUILD",
+    required_permissions = "MANAGE_GUILD"
+)]
+pub async fn server_settings(
+    ctx: Context<'_>,
+    #[description = "Default charset (Max 20 chars)"] charset: Option<String>,
+    #[description = "Default background brightness percentage, or auto"]
+    background_brightness: Option<String>,
+    #[description = "Default width (Up to 200, 400 for supporters)"]
+    #[min = 1]
+    #[max = 400]
+    max_size: Option<u32>,
+    #[description = "Default style"] style: Option<Style>,
+    #[description = "Largest maximum size members may request"]
+    max_size_cap: Option<u32>,
+    #[description = "Renders the whole server may run per day (0 for no \
+                     limit)"]
+    daily_quota: Option<u32>,
+    #[description = "Renders each member may run per day (0 for no limit)"]
+    user_daily_quota: Option<u32>,
+    #[description = "Forget all server settings"] reset: Option<bool>,
+) -> Res<()> {
+    let guild_id =
+        ctx.guild_id().ok_or("This command only works in servers")?;
+    let storage = &ctx.data().storage;
+    let charset = charset.as_deref().map(clean_charset).transpose()?;
+    let background_brightness = background_brightness
+        .as_deref()
+        .map(parse_background_brightness)
+        .transpose()?;
+    let defaults =
+        Preferences::new(charset, max_size, style, background_brightness);
+
+    let saved = if reset.unwrap_or(false) {
+        if let Some(webhook) = storage.guild(guild_id).await?.webhook {
+            delete_webhook(ctx, &webhook).await;
+        }
+        storage.set_guild(guild_id, GuildSettings::default()).await?;
+        GuildSettings::default()
+    } else if defaults.is_empty()
+        && max_size_cap.is_none()
+        && daily_quota.is_none()
+        && user_daily_quota.is_none()
+    {
+        storage.guild(guild_id).await?
+    } else {
+        let current = storage.guild(guild_id).await?;
+        // 0 lifts a quota
+        let quota = |new: Option<u32>, current| {
+            new.or(current).filter(|&quota| quota > 0)
+        };
+        let saved = GuildSettings {
+            defaults: defaults.or(current.defaults),
+            max_size_cap: max_size_cap.or(current.max_size_cap),
+            daily_quota: quota(daily_quota, current.daily_quota),
+            user_daily_quota: quota(user_daily_quota, current.user_daily_quota),
+            webhook: current.webhook,
+        };
+        storage.set_guild(guild_id, saved.clone()).await?;
+        saved
+    };
+
+    ctx.send(embed!(
+        title: "Server defaults",
+        description: saved.describe(),
+        ephemeral: true,
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Convert every image posted in a channel, or list the watched channels
+#[command(
+    slash_command,
+    install_context = "Guild",
+    interaction_context = "Guild",
+    default_member_permissions = "MANAGE_CHANNELS",
+    required_permissions = "MANAGE_CHANNELS"
+)]
+pub async fn watch_channel(
+    ctx: Context<'_>,
+    #[description = "Channel whose images are converted"]
+    #[channel_types("Text")]
+    channel: Option<GuildChannel>,
+    #[description = "Stop watching the channel"] remove: Option<bool>,
+) -> Res<()> {
+    if !config().watch_channels {
+        return Err("Watched channels are turned off on this bot".into());
+    }
+    let guild_id =
+        ctx.guild_id().ok_or("This command only works in servers")?;
+    let storage = &ctx.data().storage;
+
+    if let Some(channel) = channel {
+        let watched = !remove.unwrap_or(false);
+        storage.set_watched(guild_id, channel.id, watched).await?;
+    }
+
+    let channels = storage.watched_channels(guild_id).await?;
+    let description = if channels.is_empty() {
+        "*No watched channels*".to_string()
+    } else {
+        channels
+            .iter()
+            .map(|channel| format!("<#{channel}>"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    ctx.send(embed!(
+        title: "Watched channels",
+        description: format!(
+            "{description}\n\n-# At most one image is converted every \
+             {}s per channel",
+            config().watch_cooldown,
+        ),
+        ephemeral: true,
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Largest webhook avatar accepted
+const MAX_AVATAR_SIZE: u32 = 8 * 1024 * 1024;
+
+/// Post the server's renders through a webhook instead of replying, for
+/// art gallery channels
+#[command(
+    slash_command,
+    install_context = "Guild",
+    interaction_context = "Guild",
+    default_member_permissions = "MANAGE_WEBHOOKS",
+    required_permissions = "MANAGE_WEBHOOKS",
+    required_bot_permissions = "MANAGE_WEBHOOKS"
+)]
+pub async fn webhook_output(
+    ctx: Context<'_>,
+    #[description = "Channel the renders are posted in"]
+    #[channel_types("Text")]
+    channel: Option<GuildChannel>,
+    #[description = "Name the renders are posted under (Default ASCII Bot)"]
+    #[max_length = 80]
+    name: Option<String>,
+    #[description = "Avatar the renders are posted with (Max 8 MB)"]
+    avatar: Option<Attachment>,
+    #[description = "Reply to the commands again"] off: Option<bool>,
+) -> Res<()> {
+    let guild_id =
+        ctx.guild_id().ok_or("This command only works in servers")?;
+    let storage = &ctx.data().storage;
+    let mut settings = storage.guild(guild_id).await?;
+
+    let webhook = if off.unwrap_or(false) {
+        None
+    } else {
+        let channel = channel.ok_or("Pick a channel, or turn it off")?;
+        let mut create =
+            CreateWebhook::new(name.as_deref().unwrap_or("ASCII Bot"));
+        if let Some(avatar) = avatar {
+            if avatar.size > MAX_AVATAR_SIZE {
+                return Err("Avatars are limited to 8 MB".into());
+            }
+            let image = CreateAttachment::bytes(
+                avatar.download().await?,
+                avatar.filename,
+            );
+            create = create.avatar(&image);
+        }
+        let webhook = channel.create_webhook(ctx, create).await?;
+        let token = webhook.token.ok_or("Discord sent no webhook token")?;
+        Some(WebhookOutput {
+            id: webhook.id,
+            token: token.expose_secret().to_string(),
+            channel: channel.id,
+        })
+    };
+
+    if let Some(old) = settings.webhook.take() {
+        delete_webhook(ctx, &old).await;
+    }
+    settings.webhook = webhook;
+    storage.set_guild(guild_id, settings.clone()).await?;
+
+    ctx.send(embed!(
+        title: "Server defaults",
+        description: settings.describe(),
+        ephemeral: true,
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Delete a webhook the bot no longer posts through. Someone may have
+/// deleted it already, so failing is fine
+async fn delete_webhook(ctx: Context<'_>, webhook: &WebhookOutput) {
+    let deleted = ctx
+        .http()
+        .delete_webhook_with_token(webhook.id, &webhook.token, None)
+        .await;
+    if let Err(e) = deleted {
+        info!(webhook = %webhook.id, "Couldn't delete a webhook: {e}");
+    }
+}
+
+/// Explains every option, with example renders
+#[command(
+    slash_command,
+    install_context = "Guild|User",
+    interaction_context = "Guild|BotDm|PrivateChannel"
+)]
+pub async fn help(ctx: Context<'_>) -> Res<()> {
+    let examples = &ctx.data().help_examples;
+    let captions: Vec<&str> = examples.iter().map(|e| e.caption).collect();
+    let text = help_text(ctx.locale()).unwrap_or(HELP_TEXT);
+    let pages = help_pages(text, &captions);
+
+    // Too long for one embed, so each page is its own message and the
+    // examples go under the last one, next to their captions
+    let last = pages.len().saturating_sub(1);
+    for (i, page) in pages.into_iter().enumerate() {
+        let mut reply = if i == 0 {
+            embed!(title: "ASCII Bot help", description: page, ephemeral: true,)
+        } else {
+            embed!(description: page, ephemeral: true,)
+        };
+        if i == last {
+            reply = examples.iter().fold(reply, |reply, example| {
+                reply.attachment(CreateAttachment::bytes(
+                    example.png.clone(),
+                    example.file_name,
+                ))
+            });
+        }
+        ctx.send(reply).await?;
+    }
+    Ok(())
+}
+
+/// Manage the bot, only usable by its owners
+#[command(
+    slash_command,
+    category = "Admin",
+    subcommands(
+        "admin_shutdown",
+        "admin_reload",
+        "admin_blacklist",
+        "admin_allowlist",
+        "admin_announce"
+    ),
+    subcommand_required,
+    default_member_permissions = "ADMINISTRATOR",
+    install_context = "Guild|User",
+    interaction_context = "Guild|BotDm|PrivateChannel"
+)]
+pub async fn admin(_ctx: Context<'_>) -> Res<()> {
+    Ok(())
+}
+
+/// Let running renders finish, then disconnect
+#[command(slash_command, category = "Admin", rename = "shutdown")]
+async fn admin_shutdown(ctx: Context<'_>) -> Res<()> {
+    info!(user = ctx.author().id.get(), "Shutdown requested");
+    ctx.send(embed!(
+        title: "Shutting down",
+        description: "Running renders finish first, new ones are refused.",
+        ephemeral: true,
+    ))
+    .await?;
+
+    ctx.data().shutdown.request();
+    Ok(())
+}
+
+/// Re-read the config file, new defaults and limits apply to the next
+/// renders
+#[command(slash_command, category = "Admin", rename = "reload")]
+async fn admin_reload(ctx: Context<'_>) -> Res<()> {
+    Config::load()?;
+    apply_cooldowns(&ctx.framework().options().commands);
+    // Cached renders may have used the old defaults
+    ctx.data().result_cache.clear().await;
+    info!(user = ctx.author().id.get(), "Reloaded the config");
+
+    ctx.send(embed!(
+        title: "Config reloaded",
+        description: "The cache size, render workers, queue size, HTTP \
+                      listener, log format and dev guild only change after \
+                      a restart.",
+        ephemeral: true,
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Refuse a user or server, or list the refused ones without arguments
+#[command(slash_command, category = "Admin", rename = "blacklist")]
+async fn admin_blacklist(
+    ctx: Context<'_>,
+    #[description = "User to refuse"] user: Option<User>,
+    #[description = "Id of the server to refuse"] guild_id: Option<String>,
+    #[description = "Why, for the record"] reason: Option<String>,
+    #[description = "Take them off the blacklist instead"] remove: Option<bool>,
+) -> Res<()> {
+    manage_list(ctx, List::Blacklist, user, guild_id, reason, remove).await
+}
+
+/// Allow a user or server when `allowlist_only` is set, or list the
+/// allowed ones without arguments
+#[command(slash_command, category = "Admin", rename = "allowlist")]
+async fn admin_allowlist(
+    ctx: Context<'_>,
+    #[description = "User to allow"] user: Option<User>,
+    #[description = "Id of the server to allow"] guild_id: Option<String>,
+    #[description = "Why, for the record"] reason: Option<String>,
+    #[description = "Take them off the allowlist instead"] remove: Option<bool>,
+) -> Res<()> {
+    manage_list(ctx, List::Allowlist, user, guild_id, reason, remove).await
+}
+
+/// Add `user` or the server `guild_id` to `list`, remove them, or show the
+/// list when neither is given
+async fn manage_list(
+    ctx: Context<'_>,
+    list: List,
+    user: Option<User>,
+    guild_id: Option<String>,
+    reason: Option<String>,
+    remove: Option<bool>,
+) -> Res<()> {
+    let name = match list {
+        List::Blacklist => "Blacklist",
+        List::Allowlist => "Allowlist",
+    };
+    let storage = &ctx.data().storage;
+    let target = match (user, guild_id) {
+        (Some(user), None) => Target::User(user.id),
+        (None, Some(id)) => Target::Guild(GuildId::from(
+            id.trim()
+                .parse::<NonZeroU64>()
+                .map_err(|_| format!("`{id}` isn't a server id"))?,
+        )),
+        (None, None) => {
+            let entries = storage.list_entries(list).await?;
+            let lines = entries
+                .iter()
+                .map(|(target, reason)| match reason {
+                    Some(reason) => format!("{target}: {reason}"),
+                    None => target.to_string(),
+                })
+                .collect::<Vec<_>>();
+            ctx.send(embed!(
+                title: name,
+                description: if lines.is_empty() {
+                    "*empty*".to_string()
+                } else {
+                    lines.join("\n")
+                },
+                ephemeral: true,
+            ))
+            .await?;
+            return Ok(());
+        }
+        (Some(_), Some(_)) => {
+            return Err("Pick either a user or a server".into());
+        }
+    };
+
+    let title = if remove.unwrap_or(false) {
+        storage.list_remove(list, target).await?;
+        format!("Removed from the {}", name.to_lowercase())
+    } else {
+        storage.list_add(list, target, reason.as_deref()).await?;
+        format!("Added to the {}", name.to_lowercase())
+    };
+    info!(user = ctx.author().id.get(), %target, "{title}");
+
+    ctx.send(embed!(
+        title: title,
+        description: target.to_string(),
+        ephemeral: true,
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Post a message to the system channel of every server the bot is in
+#[command(slash_command, category = "Admin", rename = "announce")]
+async fn admin_announce(
+    ctx: Context<'_>,
+    #[description = "What to announce"]
+    #[max_length = 4000]
+    message: String,
+) -> Res<()> {
+    ctx.defer_ephemeral().await?;
+
+    let channels: Vec<_> = ctx
+        .cache()
+        .guilds()
+        .into_iter()
+        .filter_map(|id| ctx.cache().guild(id)?.system_channel_id)
+        .collect();
+    let announcement = CreateMessage::new().embed(
+        CreateEmbed::new()
+            .title("ASCII Bot announcement")
+            .description(&message),
+    );
+
+    let mut sent = 0;
+    for channel in &channels {
+        match channel.send_message(ctx, announcement.clone()).await {
+            Ok(_) => sent += 1,
+            Err(e) => warn!(%channel, "Couldn't post the announcement: {e}"),
+        }
+    }
+    info!(user = ctx.author().id.get(), sent, "Posted an announcement");
+
+    ctx.send(embed!(
+        title: "Announcement posted",
+        description: format!(
+            "Sent to {sent} of {} servers with a system channel",
+            channels.len()
+        ),
+        ephemeral: true,
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Set the cooldowns of the rendering commands from the config
+pub fn apply_cooldowns(commands: &[poise::Command<Data, Error>]) {
+    let cooldowns = config().cooldowns();
+    for command in commands {
+        if command.category.as_deref() == Some("Render") {
+            *command.cooldown_config.write().unwrap() = cooldowns.clone();
+        }
+    }
+}
+
+/// Shows how busy the bot has been
+#[command(
+    slash_command,
+    install_context = "Guild|User",
+    interaction_context = "Guild|BotDm|PrivateChannel"
+)]
+pub async fn stats(ctx: Context<'_>) -> Res<()> {
+    let stats = &ctx.data().stats;
+
+    ctx.send(embed!(
+        title: "ASCII Bot stats",
+        description: format!(
+            "**Renders:** {}\n**Average render time:** {} ms\n\
+             **Uptime:** {}",
+            stats.renders(),
+            stats.average_render_time().as_millis(),
+            format_duration(stats.uptime()),
+        ),
+    ))
+    .await?;
+    Ok(())
 }
 
 #[command(
     context_menu_command = "Attachment to ASCII",
+    category = "Render",
     install_context = "Guild|User",
     interaction_context = "Guild|BotDm|PrivateChannel"
 )]
 pub async fn attachment_to_ascii(ctx: Context<'_>, msg: Message) -> Res<()> {
-    let attachment =
-        msg.attachments.first().ok_or("No attachment in this message")?;
+    let image = message_image(&msg).ok_or("No attachment in this message")?;
 
-    _image_to_ascii(ctx, &attachment.download().await?, None, 0.4, 150).await
+    let name = image.name();
+    let options = RenderOptions {
+        spoilered_source: name.starts_with("SPOILER_"),
+        source_name: name,
+        ..RenderOptions::context_menu()
+    };
+
+    let span = command_span(ctx);
+    let image_bytes = match image {
+        MessageImage::Attachment(file) => download(&span, file).await?,
+        MessageImage::Gif(_) => {
+            let download = info_span!(parent: &span, "download");
+            image.download().instrument(download).await?
+        }
+    };
+    _image_to_ascii(ctx, image_bytes, options).instrument(span).await
+}
+
+/// Send back the original image a PNG render of the bot kept, only to
+/// whoever asked
+#[command(
+    context_menu_command = "Restore original",
+    category = "Render",
+    install_context = "Guild|User",
+    interaction_context = "Guild|BotDm|PrivateChannel"
+)]
+pub async fn restore_original(ctx: Context<'_>, msg: Message) -> Res<()> {
+    let render = msg
+        .attachments
+        .iter()
+        .find(|file| file.filename.ends_with(".png"))
+        .ok_or("No PNG render in this message")?;
+    ctx.defer_ephemeral().await?;
+
+    let span = command_span(ctx);
+    let png = download(&span, render).await?;
+    let original = embedded_original(&png)
+        .ok_or("That render doesn't carry its original image")?;
+    let extension = image::guess_format(original)
+        .ok()
+        .and_then(|format| format.extensions_str().first().copied())
+        .unwrap_or("bin");
+    let spoiler =
+        if render.filename.starts_with("SPOILER_") { "SPOILER_" } else { "" };
+
+    let reply = CreateReply::default().ephemeral(true).attachment(
+        CreateAttachment::bytes(
+            original.to_vec(),
+            format!("{spoiler}original.{extension}"),
+        ),
+    );
+    ctx.send(reply).await?;
+    Ok(())
 }
 
 #[command(
     context_menu_command = "User Avatar to ASCII",
+    category = "Render",
     install_context = "Guild|User",
     interaction_context = "Guild|BotDm|PrivateChannel"
 )]
 pub async fn avatar_to_ascii(ctx: Context<'_>, user: User) -> Res<()> {
+    let span = command_span(ctx);
     let client = reqwest::Client::builder()
         .connect_timeout(Duration::from_secs(10))
         .read_timeout(Duration::from_secs(30))
         .build()?;
-    let avatar = client
-        .get(user.static_face())
-        .send()
-        .await?
-        .error_for_status()?
-        .bytes()
-        .await?;
+    let avatar = async {
+        client
+            .get(user.static_face())
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await
+    }
+    .instrument(info_span!(parent: &span, "download"))
+    .await?;
+
+    let options = RenderOptions {
+        source_name: format!("{}_avatar", user.name),
+        ..RenderOptions::context_menu()
+    };
+
+    _image_to_ascii(ctx, avatar.to_vec(), options).instrument(span).await
+}
+
+/// Shown when the output was shrunk to fit the upload limit
+pub const DOWNSCALED_NOTE: &str = "-# Downscaled to fit Discord's upload limit";
+/// Shown when the output had to be split into several images
+pub const TILED_NOTE: &str =
+    "-# Too large for a single upload, split into tiles from top to bottom";
+/// Shown when a text file didn't fit in the message next to the render
+const TEXT_FILE_NOTE: &str = "-# A text file was left out, it didn't fit \
+                              in the message";
+
+/// Span around a whole rendering command, its steps are timed inside it
+fn command_span(ctx: Context<'_>) -> Span {
+    info_span!(
+        "command",
+        name = %ctx.command().qualified_name,
+        user = ctx.author().id.get(),
+        guild = ctx.guild_id().map(|g| g.get()),
+        image_bytes = field::Empty,
+    )
+}
 
-    _image_to_ascii(ctx, &avatar, None, 0.4, 150).await
+/// Download an attachment, timed in a `download` span under `span`
+async fn download(span: &Span, file: &Attachment) -> Res<Vec<u8>> {
+    let download = info_span!(parent: span, "download", bytes = file.size);
+    Ok(file.download().instrument(download).await?)
 }
 
 async fn _image_to_ascii(
     ctx: Context<'_>,
-    image_bytes: &[u8],
-    charset: Option<&str>,
-    background_brightness: f32,
-    size: u32,
+    image_bytes: Vec<u8>,
+    options: RenderOptions,
 ) -> Res<()> {
-    ctx.defer().await?;
+    Span::current().record("image_bytes", image_bytes.len());
+    let _job = ctx.data().shutdown.start()?;
+    if options.ephemeral {
+        ctx.defer_ephemeral().await?;
+    } else {
+        ctx.defer().await?;
+    }
 
-    let charset = charset.unwrap_or(".:-+=#@");
-    let renderer: AsciiRenderer =
-        AsciiRenderer::new(background_brightness, size)?;
-    let ascii_art = renderer.process_image(image_bytes, charset)?;
-    let output_image: RgbaImage = renderer.render_to_image(&ascii_art)?;
-    let mut png_bytes = Vec::new();
+    let storage = &ctx.data().storage;
+    let guild = match ctx.guild_id() {
+        Some(guild_id) => storage.guild(guild_id).await?,
+        None => GuildSettings::default(),
+    };
+    let saved = storage.user(ctx.author().id).await?;
+    let tier = command_tier(ctx).await;
+    let prefs = options
+        .overrides
+        .or(saved)
+        .or(guild.defaults.clone())
+        .or(options.defaults);
+    let prefs = tier.limit(guild.apply_caps(prefs));
 
-    output_image.write_to(
-        &mut std::io::Cursor::new(&mut png_bytes),
-        image::ImageFormat::Png,
-    )?;
+    let image_bytes: Arc<[u8]> = image_bytes.into();
+    let cache = &ctx.data().result_cache;
+    let key = cache.key(&image_bytes, &prefs, &options.assets, options.collage);
+    let hit = match key {
+        Some(key) => cache.get(key).await,
+        None => None,
+    };
 
-    let files = CreateAttachment::bytes(png_bytes, "ascii.png");
+    // Counted before queueing, given back if the render fails
+    let reservation = match ctx.guild_id() {
+        Some(guild_id) => {
+            storage.reserve_quota(guild_id, ctx.author().id, &guild).await?
+        }
+        None => None,
+    };
 
-    ctx.send(poise::CreateReply::default().attachment(files)).await?;
+    // Cached results skip the queue
+    let mut queued = None;
+    let rendered = match hit {
+        Some(rendered) => Ok(rendered),
+        None => {
+            async {
+                let (_worker, status) =
+                    wait_for_worker(ctx, tier, options.ephemeral).await?;
+                queued = status;
+
+                let started = Instant::now();
+                let rendered = render_blocking(
+                    image_bytes.clone(),
+                    prefs.clone(),
+                    options.assets.clone(),
+                    options.collage,
+                )
+                .await?;
+                let output_bytes = rendered.images.iter().map(Vec::len).sum();
+                ctx.data().stats.record_render(started.elapsed(), output_bytes);
+
+                if let Some(key) = key {
+                    cache.insert(key, &rendered).await;
+                }
+                Res::Ok(rendered)
+            }
+            .await
+        }
+    };
+    if rendered.is_err() {
+        storage.refund_quota(reservation).await?;
+    }
+    let rendered = rendered?;
+
+    let mut reply = CreateReply::default().ephemeral(options.ephemeral);
+
+    let mut notes = Vec::new();
+
+    // Never unspoiler content that was hidden at the source
+    let mut spoiler = options.spoiler;
+    if !spoiler && (options.spoilered_source || is_nsfw_channel(ctx).await) {
+        spoiler = true;
+        notes.push(
+            "-# Sent as a spoiler, since the source is marked as NSFW or \
+             spoiler",
+        );
+    }
+    if rendered.downscaled {
+        notes.push(DOWNSCALED_NOTE);
+    }
+
+    let name = output_name(
+        &options.source_name,
+        &prefs,
+        options.collage,
+        spoiler,
+        rendered.format.extension(),
+    );
+    let count = rendered.images.len();
+    if count > 1 {
+        notes.push(TILED_NOTE);
+    }
+    let mut message_size: usize = rendered.images.iter().map(Vec::len).sum();
+    for (i, image) in rendered.images.into_iter().enumerate() {
+        reply = reply.attachment(CreateAttachment::bytes(
+            image,
+            tile_name(&name, i, count),
+        ));
+    }
+
+    let mut text_files = Vec::new();
+    if options.ansi_file {
+        let sauce = Sauce {
+            title: "ASCII Bot render",
+            author: &ctx.author().name,
+            width: rendered.columns as u16,
+            height: rendered.rows as u16,
+        };
+        text_files.push((to_ans_file(&rendered.ascii_art, &sauce), "ans"));
+    }
+    if options.html_file {
+        let html = html_export(&rendered.ascii_art, &prefs)?;
+        text_files.push((html.into_bytes(), "html"));
+    }
+
+    // Discord refuses the whole message past its limit, so text files
+    // that don't fit are left out rather than the render
+    for (bytes, extension) in text_files {
+        if bytes.len() > UPLOAD_LIMIT
+            || message_size + bytes.len() > MESSAGE_LIMIT
+        {
+            if !notes.contains(&TEXT_FILE_NOTE) {
+                notes.push(TEXT_FILE_NOTE);
+            }
+            continue;
+        }
+        message_size += bytes.len();
+        reply = reply.attachment(CreateAttachment::bytes(
+            bytes,
+            output_name(
+                &options.source_name,
+                &prefs,
+                options.collage,
+                spoiler,
+                extension,
+            ),
+        ));
+    }
+
+    // Also clears the queue status the result replaces
+    if !notes.is_empty() || queued.is_some() {
+        reply = reply.content(notes.join("\n"));
+    }
+
+    let id = ctx.data().render_cache.insert(CachedRender {
+        image_bytes,
+        prefs,
+        source_name: options.source_name,
+        spoiler,
+        collage: options.collage,
+        assets: options.assets,
+        ascii_art: rendered.ascii_art,
+        owner: ctx.author().id,
+    });
+
+    let reply = reply.components(render_buttons(id));
+    match guild.webhook.filter(|_| !options.ephemeral) {
+        Some(webhook) => post_to_webhook(ctx, &webhook, queued, reply).await,
+        None => send_result(ctx, queued, reply).await,
+    }
+}
+
+/// Wait for a render worker. When none is free, the user is told their
+/// place in line, and the returned message is the one the result replaces
+async fn wait_for_worker<'a>(
+    ctx: Context<'a>,
+    tier: Tier,
+    ephemeral: bool,
+) -> Res<(Worker<'a>, Option<ReplyHandle<'a>>)> {
+    let ticket = ctx.data().queue.join(tier)?;
+    let Some(position) = ticket.position() else {
+        return Ok((ticket.start().await, None));
+    };
+
+    let status = |text: String| {
+        CreateReply::default().content(text).ephemeral(ephemeral)
+    };
+    let handle =
+        ctx.send(status(format!("-# Queued, position {position}"))).await?;
+    let worker = ticket.start().await;
+    handle.edit(ctx, status("-# Rendering…".to_string())).await?;
+
+    Ok((worker, Some(handle)))
+}
+
+/// Post a result, in place of the queue status when there is one
+async fn send_result(
+    ctx: Context<'_>,
+    queued: Option<ReplyHandle<'_>>,
+    reply: CreateReply,
+) -> Res<()> {
+    match queued {
+        Some(handle) => handle.edit(ctx, reply).await?,
+        None => {
+            ctx.send(reply).await?;
+        }
+    }
     Ok(())
 }
+
+/// Post a result through the server's webhook output, the command's reply
+/// linking to it. Replies as usual when the webhook is gone
+async fn post_to_webhook(
+    ctx: Context<'_>,
+    webhook: &WebhookOutput,
+    queued: Option<ReplyHandle<'_>>,
+    reply: CreateReply,
+) -> Res<()> {
+    // Webhook posts don't show who ran the command
+    let requested = format!("-# Requested by {}", ctx.author().mention());
+    let content = match reply.content.as_deref() {
+        Some(notes) if !notes.is_empty() => format!("{notes}\n{requested}"),
+        _ => requested,
+    };
+    let mut post = ExecuteWebhook::new()
+        .content(content)
+        .add_files(reply.attachments.clone())
+        .allowed_mentions(CreateAllowedMentions::new());
+    if let Some(components) = reply.components.clone() {
+        post = post.components(components);
+    }
+
+    let posted = post.execute(ctx, (webhook.id, &webhook.token, true)).await;
+    match posted {
+        Ok(Some(message)) => {
+            let link = CreateReply::default()
+                .content(format!("-# Posted in {}", message.link()));
+            send_result(ctx, queued, link).await
+        }
+        Ok(None) => send_result(ctx, queued, reply).await,
+        Err(e) => {
+            let id = webhook.id;
+            warn!(webhook = %id, "Couldn't post through a webhook: {e}");
+            send_result(ctx, queued, reply).await
+        }
+    }
+}
+
+async fn is_nsfw_channel(ctx: Context<'_>) -> bool {
+    ctx.guild_channel().await.is_some_and(|channel| channel.nsfw)
+}
@@ -0,0 +1,311 @@
+use std::{
+    io::ErrorKind,
+    net::SocketAddr,
+    num::NonZeroU64,
+    path::PathBuf,
+    str::FromStr,
+    sync::{LazyLock, RwLock},
+    time::Duration,
+};
+
+use poise::{
+    CooldownConfig,
+    serenity_prelude::{GuildId, RoleId, SkuId},
+};
+use serde::Deserialize;
+
+use crate::{
+    Res,
+    image_to_ascii::{
+        DEFAULT_CHARSET, DEFAULT_PIXEL_BUDGET, TYPEWRITER_FRAMES,
+    },
+    options::DEFAULT_SIZE,
+};
+
+/// Key the embedded token file is XOR'ed with, see `xor_token.sh`
+#[cfg(embedded_token)]
+const XOR_KEY: u8 = 66;
+
+/// Command line arguments, each written `--flag value` or `--flag=value`
+#[derive(Default)]
+pub struct Args {
+    /// File holding the bot token
+    pub token_file: Option<String>,
+    /// Guild the commands are registered in, instead of globally
+    pub dev_guild: Option<NonZeroU64>,
+}
+
+impl Args {
+    pub fn parse() -> Res<Self> {
+        let mut parsed = Self::default();
+        let mut args = std::env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag, Some(value.to_string())),
+                None => (arg.as_str(), None),
+            };
+            let value = value
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("`{flag}` needs a value"))?;
+
+            match flag {
+                "--token-file" => parsed.token_file = Some(value),
+                "--dev-guild" => {
+                    let id = value
+                        .parse()
+                        .map_err(|_| format!("`{value}` isn't a guild id"))?;
+                    parsed.dev_guild = Some(id);
+                }
+                _ => return Err(format!("Unknown argument `{flag}`").into()),
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+/// The bot token, from the file given with `--token-file`, the
+/// `DISCORD_TOKEN` environment variable or, when the binary was built with
+/// one, the embedded `.token.xor` file, in that order
+pub fn discord_token(args: &Args) -> Res<String> {
+    if let Some(path) = &args.token_file {
+        let token = std::fs::read_to_string(path)
+            .map_err(|e| format!("Couldn't read the token file {path}: {e}"))?;
+        return Ok(token.trim().to_string());
+    }
+
+    if let Ok(token) = std::env::var("DISCORD_TOKEN") {
+        return Ok(token.trim().to_string());
+    }
+
+    embedded_token()
+}
+
+// Token file is generated through
+// ./xor_token.sh "MTQTHISIS.ANEXAMPLE.TOKEN"
+#[cfg(embedded_token)]
+fn embedded_token() -> Res<String> {
+    let bytes = include_bytes!("../.token.xor").iter().map(|b| b ^ XOR_KEY);
+    Ok(String::from_utf8(bytes.collect())?)
+}
+
+#[cfg(not(embedded_token))]
+fn embedded_token() -> Res<String> {
+    Err("No token given, set DISCORD_TOKEN or pass --token-file <path>".into())
+}
+
+/// Config file read when `ASCII_BOT_CONFIG` doesn't point to another one
+const CONFIG_PATH: &str = "ascii-bot.toml";
+
+/// The current config. Replaced ones are leaked on purpose: reloads are
+/// rare, and readers get to keep `&'static` references across them
+static CONFIG: LazyLock<RwLock<&'static Config>> =
+    LazyLock::new(|| RwLock::new(Box::leak(Box::default())));
+
+/// Operator settings, read from a TOML file at startup. Every key is
+/// optional, see `ascii-bot.example.toml`
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Width of the art when nobody picked one
+    pub default_size: u32,
+    pub default_charset: String,
+    /// Cooldowns of the commands that render images, in seconds, 0
+    /// disables the bucket
+    pub user_cooldown: u64,
+    pub channel_cooldown: u64,
+    /// Most pixels an input may have
+    pub pixel_budget: u64,
+    /// Largest original image, in bytes, embedded into the PNG renders of
+    /// users who asked for it. 0 never embeds any
+    pub max_embedded_original: usize,
+    /// SQLite database the settings, quotas and stats are kept in
+    pub database: String,
+    /// How many renders keep working buttons
+    pub render_cache_size: usize,
+    /// How many finished renders are kept in memory, to answer the same
+    /// conversion again without redoing it. 0 disables the cache
+    pub result_cache_size: usize,
+    /// Directory renders pushed out of memory are kept in, off when unset
+    pub result_cache_dir: Option<PathBuf>,
+    /// How many renders the directory keeps
+    pub result_cache_disk_size: usize,
+    /// How many renders run at once
+    pub render_workers: usize,
+    /// How many renders may wait for a worker, more are turned away
+    pub queue_size: usize,
+    /// How long shutting down waits for running renders, in seconds
+    pub shutdown_timeout: u64,
+    /// Log JSON lines instead of plain text, for log aggregators
+    pub json_logs: bool,
+    /// Guild the commands are registered in, for development, since global
+    /// commands take a while to update. `--dev-guild` overrides it
+    pub dev_guild: Option<NonZeroU64>,
+    /// Address of the HTTP listener serving `/metrics` and `/healthz`, off
+    /// when unset
+    pub http_listen: Option<SocketAddr>,
+    /// Also serve `POST /render` on `http_listen`, read at startup
+    pub render_api: bool,
+    /// Bearer token the render API requires, open to anyone when unset
+    pub render_api_key: Option<String>,
+    /// Discord webhook unexpected errors are reported to
+    pub error_webhook: Option<String>,
+    /// Also report unexpected errors to the bot owners in DMs
+    pub report_to_owners: bool,
+    /// Only serve the users and servers on the allowlist, for private
+    /// deployments
+    pub allowlist_only: bool,
+    /// Let servers pick channels where every posted image is converted.
+    /// Needs the privileged Message Content intent, read at startup
+    pub watch_channels: bool,
+    /// Seconds between two conversions in the same watched channel, images
+    /// posted meanwhile are skipped
+    pub watch_cooldown: u64,
+    /// Emoji converting the image of the message it's added to, unicode or
+    /// `<:name:id>`. Needs the Message Content intent, read at startup
+    pub reaction_trigger: Option<String>,
+    pub features: Features,
+    pub tiers: Tiers,
+}
+
+/// Options that can be turned off, for bots on small machines
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+    pub custom_fonts: bool,
+    pub background_images: bool,
+    pub collages: bool,
+    pub animations: bool,
+}
+
+/// Who counts as a supporter, and the limits of each tier
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tiers {
+    /// SKU whose entitlement makes a user a supporter
+    pub supporter_sku: Option<SkuId>,
+    /// Role making its members supporters, in `supporter_guild`
+    pub supporter_guild: Option<GuildId>,
+    pub supporter_role: Option<RoleId>,
+    /// Widest art, in characters
+    pub free_max_size: u32,
+    pub supporter_max_size: u32,
+    /// Steps of the typewriter animation
+    pub free_animation_frames: u32,
+    pub supporter_animation_frames: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            default_size: DEFAULT_SIZE,
+            default_charset: DEFAULT_CHARSET.to_string(),
+            user_cooldown: 10,
+            channel_cooldown: 3,
+            pixel_budget: DEFAULT_PIXEL_BUDGET,
+            max_embedded_original: 2 * 1024 * 1024,
+            database: "ascii-bot.db".to_string(),
+            render_cache_size: 64,
+            result_cache_size: 32,
+            result_cache_dir: None,
+            result_cache_disk_size: 256,
+            render_workers: 2,
+            queue_size: 20,
+            shutdown_timeout: 30,
+            json_logs: false,
+            dev_guild: None,
+            http_listen: None,
+            render_api: false,
+            render_api_key: None,
+            error_webhook: None,
+            report_to_owners: false,
+            allowlist_only: false,
+            watch_channels: false,
+            watch_cooldown: 30,
+            reaction_trigger: None,
+            features: Features::default(),
+            tiers: Tiers::default(),
+        }
+    }
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            custom_fonts: true,
+            background_images: true,
+            collages: true,
+            animations: true,
+        }
+    }
+}
+
+impl Default for Tiers {
+    fn default() -> Self {
+        Self {
+            supporter_sku: None,
+            supporter_guild: None,
+            supporter_role: None,
+            free_max_size: 200,
+            supporter_max_size: 400,
+            free_animation_frames: TYPEWRITER_FRAMES,
+            supporter_animation_frames: 60,
+        }
+    }
+}
+
+impl Config {
+    /// Read the config file, then apply the `ASCII_BOT_*` environment
+    /// variables on top of it. A missing `ascii-bot.toml` leaves the
+    /// defaults, a missing `ASCII_BOT_CONFIG` file is an error. Loading it
+    /// again replaces the config used by the next renders
+    pub fn load() -> Res<()> {
+        let (path, required) = match std::env::var("ASCII_BOT_CONFIG") {
+            Ok(path) => (path, true),
+            Err(_) => (CONFIG_PATH.to_string(), false),
+        };
+        let mut config: Config = match std::fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text)
+                .map_err(|e| format!("Invalid config file {path}: {e}"))?,
+            Err(e) if required || e.kind() != ErrorKind::NotFound => {
+                return Err(format!("Couldn't read {path}: {e}").into());
+            }
+            Err(_) => Config::default(),
+        };
+        config.apply_env();
+
+        *CONFIG.write().unwrap() = Box::leak(Box::new(config));
+        Ok(())
+    }
+
+    fn apply_env(&mut self) {
+        fn var<T: FromStr>(name: &str) -> Option<T> {
+            std::env::var(name).ok().and_then(|v| v.parse().ok())
+        }
+
+        if let Some(secs) = var("ASCII_BOT_USER_COOLDOWN") {
+            self.user_cooldown = secs;
+        }
+        if let Some(secs) = var("ASCII_BOT_CHANNEL_COOLDOWN") {
+            self.channel_cooldown = secs;
+        }
+        if let Some(pixels) = var("ASCII_BOT_PIXEL_BUDGET") {
+            self.pixel_budget = pixels;
+        }
+    }
+
+    pub fn cooldowns(&self) -> CooldownConfig {
+        let bucket = |secs| (secs > 0).then(|| Duration::from_secs(secs));
+        CooldownConfig {
+            user: bucket(self.user_cooldown),
+            channel: bucket(self.channel_cooldown),
+            ..Default::default()
+        }
+    }
+}
+
+/// The latest loaded config, or the defaults before [`Config::load`] ran
+pub fn config() -> &'static Config {
+    *CONFIG.read().unwrap()
+}
@@ -0,0 +1,62 @@
+use image::{Rgba, RgbaImage, imageops};
+
+/// How strongly the picture bulges towards the viewer
+const BARREL_STRENGTH: f32 = 0.08;
+/// Brightness kept on every other pixel row
+const SCANLINE_BRIGHTNESS: f32 = 0.7;
+/// Blur radius and strength of the phosphor glow
+const GLOW_SIGMA: f32 = 2.5;
+const GLOW_STRENGTH: f32 = 0.45;
+
+/// Retro terminal look: curved screen, phosphor glow and scanlines
+pub fn crt(image: &RgbaImage) -> RgbaImage {
+    let mut screen = barrel_distort(image);
+    add_glow(&mut screen);
+    scanlines(&mut screen);
+    screen
+}
+
+/// Sample every pixel from further out the closer it is to the corners,
+/// pixels that fall outside the source stay transparent
+fn barrel_distort(image: &RgbaImage) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    let (half_w, half_h) = (width as f32 / 2.0, height as f32 / 2.0);
+
+    RgbaImage::from_fn(width, height, |x, y| {
+        let nx = (x as f32 + 0.5 - half_w) / half_w;
+        let ny = (y as f32 + 0.5 - half_h) / half_h;
+        let factor = 1.0 + BARREL_STRENGTH * (nx * nx + ny * ny);
+
+        let sx = nx * factor * half_w + half_w;
+        let sy = ny * factor * half_h + half_h;
+        if sx < 0.0 || sy < 0.0 || sx >= width as f32 || sy >= height as f32 {
+            return Rgba([0, 0, 0, 0]);
+        }
+
+        *image.get_pixel(sx as u32, sy as u32)
+    })
+}
+
+/// Add a blurred copy on top, so bright glyphs bleed into their
+/// surroundings like phosphor does
+fn add_glow(image: &mut RgbaImage) {
+    let glow = imageops::blur(image, GLOW_SIGMA);
+
+    for (pixel, halo) in image.pixels_mut().zip(glow.pixels()) {
+        for (dst, src) in pixel.0.iter_mut().zip(halo.0).take(3) {
+            *dst = (*dst as f32 + src as f32 * GLOW_STRENGTH).min(255.0) as u8;
+        }
+        pixel[3] = pixel[3].max(halo[3]);
+    }
+}
+
+/// Darken every other pixel row
+fn scanlines(image: &mut RgbaImage) {
+    for (_, y, pixel) in image.enumerate_pixels_mut() {
+        if y % 2 == 1 {
+            for channel in pixel.0.iter_mut().take(3) {
+                *channel = (*channel as f32 * SCANLINE_BRIGHTNESS) as u8;
+            }
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+
+use ab_glyph::{Font, PxScale, ScaleFont, point};
+use image::{Rgba, RgbaImage};
+
+/// Coverage of a single glyph, positioned relative to its cell's top left
+struct GlyphMask {
+    left: i32,
+    top: i32,
+    width: u32,
+    coverage: Vec<f32>,
+}
+
+/// Alpha masks of every glyph used by a render, rasterized only once and
+/// blitted with the cell's color afterwards
+pub struct GlyphAtlas {
+    masks: HashMap<char, Option<GlyphMask>>,
+}
+
+impl GlyphAtlas {
+    pub fn new(
+        font: &impl Font,
+        scale: PxScale,
+        chars: impl IntoIterator<Item = char>,
+    ) -> Self {
+        let ascent = font.as_scaled(scale).ascent();
+        let mut masks = HashMap::new();
+
+        for ch in chars {
+            masks.entry(ch).or_insert_with(|| {
+                // Same baseline as imageproc's draw_text_mut
+                let glyph = font
+                    .glyph_id(ch)
+                    .with_scale_and_position(scale, point(0.0, ascent));
+                let outlined = font.outline_glyph(glyph)?;
+                let bounds = outlined.px_bounds();
+                let width = bounds.width() as u32;
+                let height = bounds.height() as u32;
+
+                let mut coverage = vec![0.0; (width * height) as usize];
+                outlined.draw(|x, y, c| {
+                    if x < width && y < height {
+                        coverage[(y * width + x) as usize] = c;
+                    }
+                });
+
+                Some(GlyphMask {
+                    left: bounds.min.x.round() as i32,
+                    top: bounds.min.y.round() as i32,
+                    width,
+                    coverage,
+                })
+            });
+        }
+
+        Self { masks }
+    }
+
+    /// Blend `ch` tinted with `color` into the cell whose top left is (x, y)
+    pub fn draw(
+        &self,
+        image: &mut RgbaImage,
+        ch: char,
+        color: Rgba<u8>,
+        x: i32,
+        y: i32,
+    ) {
+        let Some(Some(mask)) = self.masks.get(&ch) else {
+            return; // Whitespace and glyphs missing from the font
+        };
+        let (image_width, image_height) = image.dimensions();
+
+        for (i, &alpha) in mask.coverage.iter().enumerate() {
+            if alpha <= 0.0 {
+                continue;
+            }
+
+            let px = x + mask.left + (i as u32 % mask.width) as i32;
+            let py = y + mask.top + (i as u32 / mask.width) as i32;
+            if px < 0
+                || py < 0
+                || px as u32 >= image_width
+                || py as u32 >= image_height
+            {
+                continue;
+            }
+
+            let alpha = alpha.min(1.0);
+            let pixel = image.get_pixel_mut(px as u32, py as u32);
+            blend_over(pixel, color, alpha);
+        }
+    }
+}
+
+/// Composite `color` with coverage `alpha` over `pixel`, honoring the
+/// pixel's own alpha so transparent backgrounds stay correct
+fn blend_over(pixel: &mut Rgba<u8>, color: Rgba<u8>, alpha: f32) {
+    let dst_alpha = pixel[3] as f32 / 255.0;
+    let out_alpha = alpha + dst_alpha * (1.0 - alpha);
+    if out_alpha <= 0.0 {
+        return;
+    }
+
+    for (dst, src) in pixel.0.iter_mut().zip(color.0).take(3) {
+        *dst = ((src as f32 * alpha + *dst as f32 * dst_alpha * (1.0 - alpha))
+            / out_alpha)
+            .round() as u8;
+    }
+    pixel[3] = (out_alpha * 255.0).round() as u8;
+}
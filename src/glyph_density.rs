@@ -0,0 +1,37 @@
+use ab_glyph::{Font, PxScale, ScaleFont, point};
+
+/// Pixel size glyphs are measured at, big enough for thin strokes to count
+const MEASURE_SIZE: f32 = 48.0;
+
+/// Share of its cell a glyph covers with ink, from 0 for blank glyphs to 1.
+/// Wide glyphs are measured against their own, wider, cell
+pub fn ink_coverage(font: &impl Font, ch: char) -> f32 {
+    let scale = PxScale::from(MEASURE_SIZE);
+    let scaled = font.as_scaled(scale);
+    let id = font.glyph_id(ch);
+    let advance = match scaled.h_advance(id) {
+        advance if advance > 0.0 => advance,
+        _ => scaled.h_advance(font.glyph_id('M')).max(1.0),
+    };
+    let area = advance * scaled.height().max(1.0);
+
+    // Same baseline as the glyph atlas
+    let glyph = id.with_scale_and_position(scale, point(0.0, scaled.ascent()));
+    let Some(outlined) = font.outline_glyph(glyph) else {
+        return 0.0; // Whitespace
+    };
+    let mut ink = 0.0;
+    outlined.draw(|_, _, coverage| ink += coverage.min(1.0));
+
+    (ink / area).min(1.0)
+}
+
+/// Sort `charset` from the glyph with the least ink to the one with the
+/// most, the order the renderer expects. Glyphs inked alike keep their
+/// order
+pub fn sort_by_density(font: &impl Font, charset: &str) -> String {
+    let mut glyphs: Vec<(char, f32)> =
+        charset.chars().map(|ch| (ch, ink_coverage(font, ch))).collect();
+    glyphs.sort_by(|a, b| a.1.total_cmp(&b.1));
+    glyphs.into_iter().map(|(ch, _)| ch).collect()
+}
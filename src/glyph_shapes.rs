@@ -0,0 +1,81 @@
+use ab_glyph::{Font, PxScale, ScaleFont, point};
+
+/// Ink of every glyph of a charset, averaged down to a few samples per
+/// cell, for picking the glyph whose shape best matches part of an image
+pub struct GlyphShapes {
+    glyphs: Vec<(char, Vec<f32>)>,
+}
+
+impl GlyphShapes {
+    /// Rasterize each glyph of `charset` in a `cell_width` by
+    /// `cell_height` cell, like the renderer draws them, and average its
+    /// coverage into `across` by `down` samples
+    pub fn new(
+        font: &impl Font,
+        scale: PxScale,
+        charset: &str,
+        (cell_width, cell_height): (u32, u32),
+        (across, down): (u32, u32),
+    ) -> Self {
+        let ascent = font.as_scaled(scale).ascent();
+        let samples = (across * down) as usize;
+        // Cell pixels averaged into each sample
+        let mut counts = vec![0.0f32; samples];
+        for y in 0..cell_height {
+            for x in 0..cell_width {
+                let sample =
+                    y * down / cell_height * across + x * across / cell_width;
+                counts[sample as usize] += 1.0;
+            }
+        }
+
+        let mut glyphs: Vec<(char, Vec<f32>)> = Vec::new();
+        for ch in charset.chars() {
+            if glyphs.iter().any(|(seen, _)| *seen == ch) {
+                continue;
+            }
+            let mut ink = vec![0.0; samples];
+            // Same baseline as the glyph atlas, whitespace stays blank
+            let glyph = font
+                .glyph_id(ch)
+                .with_scale_and_position(scale, point(0.0, ascent));
+            if let Some(outlined) = font.outline_glyph(glyph) {
+                let bounds = outlined.px_bounds();
+                outlined.draw(|gx, gy, coverage| {
+                    let x = bounds.min.x.round() as i32 + gx as i32;
+                    let y = bounds.min.y.round() as i32 + gy as i32;
+                    if x < 0
+                        || y < 0
+                        || x as u32 >= cell_width
+                        || y as u32 >= cell_height
+                    {
+                        return;
+                    }
+                    let sample = y as u32 * down / cell_height * across
+                        + x as u32 * across / cell_width;
+                    ink[sample as usize] += coverage.min(1.0);
+                });
+            }
+            for (ink, count) in ink.iter_mut().zip(&counts) {
+                *ink /= count.max(1.0);
+            }
+            glyphs.push((ch, ink));
+        }
+
+        Self { glyphs }
+    }
+
+    /// Glyph with the smallest sum of absolute differences to `cell`, the
+    /// brightness of its samples from 0 to 1 in the same layout
+    pub fn best_match(&self, cell: &[f32]) -> char {
+        self.glyphs
+            .iter()
+            .map(|(ch, ink)| {
+                let distance: f32 =
+                    ink.iter().zip(cell).map(|(a, b)| (a - b).abs()).sum();
+                (*ch, distance)
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map_or(' ', |(ch, _)| ch)
+    }
+}
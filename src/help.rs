@@ -0,0 +1,193 @@
+use crate::{Res, image_to_ascii::AsciiRenderer, options::Style};
+
+/// Sample rendered for the `/help` examples
+const SAMPLE_IMAGE: &[u8] = include_bytes!("../koakuma_txt.png");
+const EXAMPLE_SIZE: u32 = 48;
+/// Longest embed description Discord accepts, in characters
+const MAX_DESCRIPTION: usize = 4096;
+
+/// Caption, file name, charset, style and background brightness of each
+/// `/help` example
+const EXAMPLES: [(&str, &str, &str, Style, f32); 5] = [
+    ("Default options", "default.png", ".:-+=#@", Style::Mixed, 0.2),
+    (
+        "`style: Foreground paint`",
+        "fg_paint.png",
+        ".:-+=#@",
+        Style::FgPaint,
+        0.2,
+    ),
+    ("`style: Background only`", "bg_only.png", ".:-+=#@", Style::BgOnly, 0.2),
+    ("`charset: ░▒▓█`", "charset.png", "░▒▓█", Style::Mixed, 0.2),
+    (
+        "`background_brightness: 60`",
+        "brightness.png",
+        ".:-+=#@",
+        Style::Mixed,
+        0.6,
+    ),
+];
+
+pub const HELP_TEXT: &str = "\
+**Commands**
+`/image_to_ascii` converts an attached image. `/same_as_last` converts \
+another one with the options last used with it in the channel, and \
+`/same_as_render` with the options a PNG render of the bot was made with.
+`Attachment to ASCII` and `User Avatar to ASCII` are found in the \
+Apps context menu of messages and users. `Restore original` sends back \
+the image a render was made from, when it kept it.
+`/ascii_to_image` renders a `.txt`/`.ans` file of ANSI colored text, \
+`/paste_ansi` does the same with pasted text.
+`/settings` saves your own defaults, `/server_settings` the server's.
+`/webhook_output` posts the server's renders through a webhook, for art \
+gallery channels.
+`/watch_channel` converts every image posted in a channel.
+
+**Options**
+`charset`: characters used for the art, sorted by how much ink each one \
+takes in the font. `sort_charset` (`/settings` only) turned off keeps \
+your own order, from darkest to brightest. `auto` picks one from the \
+look of the image: dense for photos, blocks for pixel art, line art for \
+drawings.
+`background_brightness`: how much of each cell's color bleeds into \
+its background, in percent. `auto` picks it from the image's brightness, \
+keeping the characters readable.
+`max_size`: width of the art in characters. Up to 200, or 400 for \
+supporters, unless the bot's owner changed those limits. A server's \
+`max_size_cap` can lower them further.
+`max_height`: height limit in rows, keeps tall images from getting huge. \
+Limited like `max_size`.
+`fit`: `Exact` pads the art to exactly `max_size` by `max_height`, for \
+uniformly sized emotes and banners.
+`style`: whether colors paint the characters, the background, or both.
+`text_file`: also attach the colored text as an `.ans` file, as a web \
+page ready to embed, or both.
+`orientation`: rotate or flip the image before converting it, for photos \
+that arrive sideways.
+`visibility`: send the result as a spoiler, or only show it to you.
+`collage`: four looks side by side, to pick a favorite.
+`transparent`: leave the background transparent, for stickers and overlays.
+`font`: monospace font the art is drawn with.
+`font_file`: your own TTF/OTF font, used instead of `font`.
+`background_image`: a second image drawn blurred and darkened behind the \
+art, instead of the flat background. Ignored by SVG outputs.
+`font_size`: pixel size of each character, bigger is crisper but heavier.
+`mode`: draw with the charset, with half or quadrant blocks for more \
+detail per character, as line art following the edges, shaded like \
+the charset with the strongest edges outlined, or by shape: \
+each cell gets the glyph that best matches it, sharper on line art but \
+slower.
+`gamma`: brightens the midtones above 1, darkens them below, for photos \
+that come out too dark.
+`contrast`, `brightness`: adjust the image before converting it, so flat \
+screenshots don't become a wall of the same character.
+`palette`: up to 32 comma separated hex colors every cell gets snapped \
+to, or `ansi16` for the 16 classic terminal colors and lighter text files.
+`dither`: spread out shading errors, smoother gradients with few characters.
+`effect`: post-processing, like a CRT screen with scanlines and glow, or \
+a typewriter animation typing the art in, the art raining down into \
+place column by column, or a fade from the image to its art and back, \
+all sent as GIFs.
+`char_spacing`, `line_spacing` (`/settings` only): gaps between \
+characters and lines, in pixels.
+`caption`: a line of text shown under the art. `/settings` can also add \
+a small \"made with ASCII-Bot\" credit under every render.
+`thumbnail` (`/settings` only): inset the original image into a corner of \
+still renders.
+`stretch` (`/settings` only): widen the art by this factor, or squash \
+it below 1, for banner shaped outputs.
+`background_gradient`, `gradient_direction` (`/settings` only): fill \
+the background with a gradient between two hex colors instead of black.
+`filter` (`/settings` and the menu under renders): recolor the art in \
+sepia or duotone, or draw it as seen with a color blindness, to check \
+that colored art still reads. `duotone` (`/settings` only) takes the \
+shadow and highlight colors, like `#202048, #ffd080`.
+`posterize` (`/settings` only): reduce each color channel to this many \
+levels, for flat colors that read well small. 0 turns it off.
+`denoise` (`/settings` only): smooth out the speckles of noisy phone \
+photos before converting, so stray pixels don't become stray characters.
+`sharpen` (`/settings` only): unsharp mask strength, 0 to 200%, so fine \
+edges and text survive shrinking the image into the grid.
+`keep_original` (`/settings` only): embed the image into your PNG \
+renders, for `Restore original`. Anyone with the render can get it back.
+`crop` (**Configure** button only): convert only part of the image, \
+given as `x, y, width, height` in pixels.
+`format`: PNG, JPEG, WebP, GIF or scalable SVG, photos default to WebP. \
+SVG outputs take no `effect`.
+
+**Examples**";
+
+pub struct HelpExample {
+    pub caption: &'static str,
+    pub file_name: &'static str,
+    pub png: Vec<u8>,
+}
+
+/// Render the `/help` examples, done once at startup
+pub fn render_examples() -> Res<Vec<HelpExample>> {
+    EXAMPLES
+        .into_iter()
+        .map(|(caption, file_name, charset, style, brightness)| -> Res<_> {
+            let renderer = AsciiRenderer::builder()
+                .background_brightness(brightness)
+                .max_width(EXAMPLE_SIZE)
+                .charset(charset)
+                .style(style.into())
+                .build()?;
+            let ascii_art = renderer.process_image(SAMPLE_IMAGE)?;
+            let png = renderer.render_to_png(&ascii_art)?;
+
+            Ok(HelpExample { caption, file_name, png })
+        })
+        .collect()
+}
+
+/// Split the help text, followed by the numbered example captions, into
+/// pages that each fit in an embed. Every heading starts a page, and
+/// sections too long for one are split between lines
+pub fn help_pages(text: &str, captions: &[&str]) -> Vec<String> {
+    let captions = captions
+        .iter()
+        .enumerate()
+        .map(|(i, caption)| format!("{}. {caption}", i + 1));
+    let mut pages = Vec::new();
+    let mut page = String::new();
+
+    for line in text.lines().map(str::to_string).chain(captions) {
+        let heading = line.starts_with("**");
+        let full =
+            page.chars().count() + line.chars().count() + 1 > MAX_DESCRIPTION;
+        if !page.trim().is_empty() && (heading || full) {
+            pages.push(page.trim_end().to_string());
+            page.clear();
+        }
+        page.push_str(&line);
+        page.push('\n');
+    }
+    if !page.trim().is_empty() {
+        pages.push(page.trim_end().to_string());
+    }
+
+    pages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::i18n::help_text;
+
+    #[test]
+    fn help_pages_fit_in_embeds() {
+        let captions = EXAMPLES.map(|(caption, ..)| caption);
+        for locale in ["en-US", "pt-BR", "es-ES", "es-419"] {
+            let text = help_text(Some(locale)).unwrap_or(HELP_TEXT);
+            for page in help_pages(text, &captions) {
+                let length = page.chars().count();
+                assert!(
+                    length <= MAX_DESCRIPTION,
+                    "{locale} help page of {length} characters"
+                );
+            }
+        }
+    }
+}
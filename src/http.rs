@@ -0,0 +1,318 @@
+use std::{net::SocketAddr, sync::Arc, time::Instant};
+
+use axum::{
+    Router,
+    body::Bytes,
+    extract::{DefaultBodyLimit, Query},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use metrics_exporter_prometheus::{
+    Matcher, PrometheusBuilder, PrometheusHandle,
+};
+use poise::{
+    ChoiceParameter,
+    serenity_prelude::{ConnectionStage, ShardManager},
+};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{Instrument, info, info_span};
+
+use crate::{
+    Error, Res,
+    config::config,
+    options::{
+        Assets, OutputFormat, Preferences, clean_caption, clean_charset,
+        parse_background_brightness, parse_duotone, parse_palette_option,
+    },
+    queue::RenderQueue,
+    render::render_blocking,
+    shutdown::{RESTARTING, Shutdown},
+    stats::{OUTPUT_BYTES_METRIC, RENDER_SECONDS_METRIC, Stats},
+    tiers::Tier,
+};
+
+/// What `/healthz` looks at
+#[derive(Clone)]
+pub struct Health {
+    pub shard_manager: Arc<ShardManager>,
+    pub stats: Arc<Stats>,
+}
+
+/// What `/render` renders with, shared with the bot
+#[derive(Clone)]
+pub struct RenderApi {
+    pub queue: Arc<RenderQueue>,
+    pub shutdown: Arc<Shutdown>,
+    pub stats: Arc<Stats>,
+}
+
+/// Largest image `/render` accepts, in bytes
+const MAX_UPLOAD: usize = 25 * 1024 * 1024;
+
+/// Histogram buckets of the render durations, in seconds
+const RENDER_SECONDS_BUCKETS: &[f64] =
+    &[0.1, 0.25, 0.5, 1.0, 2.0, 5.0, 10.0, 30.0];
+/// Histogram buckets of the output sizes, in bytes, up to the upload limit
+const OUTPUT_BYTES_BUCKETS: &[f64] = &[
+    64.0 * 1024.0,
+    256.0 * 1024.0,
+    1024.0 * 1024.0,
+    4.0 * 1024.0 * 1024.0,
+    10.0 * 1024.0 * 1024.0,
+];
+
+/// Start collecting metrics, anything recorded before is lost
+pub fn install_metrics() -> Res<PrometheusHandle> {
+    let histogram = |name: &str| Matcher::Full(name.to_string());
+    Ok(PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            histogram(RENDER_SECONDS_METRIC),
+            RENDER_SECONDS_BUCKETS,
+        )?
+        .set_buckets_for_metric(
+            histogram(OUTPUT_BYTES_METRIC),
+            OUTPUT_BYTES_BUCKETS,
+        )?
+        .install_recorder()?)
+}
+
+/// Serve the metrics on `addr`, in the Prometheus text format at
+/// `/metrics`, the health check at `/healthz` and, when `api` is set, the
+/// render API at `/render`
+pub async fn serve(
+    addr: SocketAddr,
+    metrics: PrometheusHandle,
+    health: Health,
+    api: Option<RenderApi>,
+) -> Res<()> {
+    let mut app = Router::new()
+        .route("/metrics", get(move || async move { metrics.render() }))
+        .route("/healthz", get(move || healthz(health.clone())));
+    if let Some(api) = api {
+        let handler = move |headers: HeaderMap,
+                            Query(query): Query<RenderQuery>,
+                            body| {
+            render(api.clone(), headers, query, body)
+        };
+        app = app.route(
+            "/render",
+            post(handler).layer(DefaultBodyLimit::max(MAX_UPLOAD)),
+        );
+    }
+
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!(%addr, "Listening for HTTP requests");
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+/// State of every shard and the time of the latest render, as JSON. Answers
+/// 503 unless all shards are connected, so orchestrators restart a bot that
+/// lost the gateway
+async fn healthz(health: Health) -> impl IntoResponse {
+    let runners = health.shard_manager.runners.lock().await;
+    let shards: Vec<_> = runners
+        .iter()
+        .map(|(id, runner)| {
+            json!({
+                "id": id.0,
+                "stage": runner.stage.to_string(),
+                "latency_ms": runner.latency.map(|l| l.as_millis() as u64),
+            })
+        })
+        .collect();
+    let connected = !runners.is_empty()
+        && runners.values().all(|r| r.stage == ConnectionStage::Connected);
+    drop(runners);
+
+    let status = if connected {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = json!({
+        "connected": connected,
+        "shards": shards,
+        "last_render": health.stats.last_render(),
+    });
+
+    (status, [(header::CONTENT_TYPE, "application/json")], body.to_string())
+}
+
+/// Options of `/render`, named like the ones of `/image_to_ascii`. Choices
+/// are given by the name Discord shows, like `style=Foreground paint`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RenderQuery {
+    /// `image` for the image, PNG unless `format` says otherwise, or
+    /// `ansi` for the colored text
+    #[serde(default)]
+    output: ApiOutput,
+    charset: Option<String>,
+    background_brightness: Option<String>,
+    max_size: Option<u32>,
+    max_height: Option<u32>,
+    fit: Option<String>,
+    style: Option<String>,
+    orientation: Option<String>,
+    collage: Option<bool>,
+    transparent: Option<bool>,
+    font: Option<String>,
+    font_size: Option<u32>,
+    effect: Option<String>,
+    format: Option<String>,
+    mode: Option<String>,
+    dither: Option<bool>,
+    contrast: Option<i32>,
+    brightness: Option<i32>,
+    palette: Option<String>,
+    filter: Option<String>,
+    duotone: Option<String>,
+    posterize: Option<u32>,
+    denoise: Option<bool>,
+    sharpen: Option<u32>,
+    caption: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ApiOutput {
+    #[default]
+    Image,
+    Ansi,
+}
+
+impl RenderQuery {
+    fn preferences(self) -> Res<Preferences> {
+        let (palette, ansi16) = parse_palette_option(self.palette.as_deref())?;
+        Ok(Preferences {
+            max_height: self.max_height,
+            fit: choice("fit", self.fit)?,
+            orientation: choice("orientation", self.orientation)?,
+            transparent: self.transparent,
+            font: choice("font", self.font)?,
+            font_size: self.font_size,
+            effect: choice("effect", self.effect)?,
+            format: choice("format", self.format)?.or(Some(OutputFormat::Png)),
+            mode: choice("mode", self.mode)?,
+            dither: self.dither,
+            contrast: self.contrast,
+            brightness: self.brightness,
+            palette,
+            ansi16,
+            filter: choice("filter", self.filter)?,
+            posterize: self.posterize,
+            denoise: self.denoise,
+            sharpen: self.sharpen,
+            duotone: self.duotone.as_deref().map(parse_duotone).transpose()?,
+            caption: self.caption.as_deref().map(clean_caption),
+            ..Preferences::new(
+                self.charset.as_deref().map(clean_charset).transpose()?,
+                self.max_size,
+                choice("style", self.style)?,
+                self.background_brightness
+                    .as_deref()
+                    .map(parse_background_brightness)
+                    .transpose()?,
+            )
+        })
+    }
+}
+
+/// The choice of a slash command option named `value`
+fn choice<T: ChoiceParameter>(
+    option: &str,
+    value: Option<String>,
+) -> Res<Option<T>> {
+    value
+        .map(|value| {
+            T::from_name(&value).ok_or_else(|| {
+                Error::from(format!("Unknown {option} `{value}`"))
+            })
+        })
+        .transpose()
+}
+
+/// Convert the image in the request body, through the same queue and
+/// limits as the free tier of the bot
+async fn render(
+    api: RenderApi,
+    headers: HeaderMap,
+    mut query: RenderQuery,
+    body: Bytes,
+) -> Response {
+    let authorized = match &config().render_api_key {
+        Some(key) => headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| token == key),
+        None => true,
+    };
+    if !authorized {
+        return (StatusCode::UNAUTHORIZED, "Missing or wrong API key")
+            .into_response();
+    }
+
+    let collage = query.collage.unwrap_or(false);
+    if collage && !config().features.collages {
+        return bad_request("Collages are turned off on this bot");
+    }
+    let output = std::mem::take(&mut query.output);
+    let prefs = match query.preferences() {
+        Ok(prefs) => Tier::Free.limit(prefs),
+        Err(e) => return bad_request(&e.to_string()),
+    };
+
+    let Ok(_job) = api.shutdown.start() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, RESTARTING).into_response();
+    };
+    let ticket = match api.queue.join(Tier::Free) {
+        Ok(ticket) => ticket,
+        Err(e) => {
+            return (StatusCode::SERVICE_UNAVAILABLE, e.to_string())
+                .into_response();
+        }
+    };
+    let _worker = ticket.start().await;
+
+    let span = info_span!("api_render", image_bytes = body.len());
+    let started = Instant::now();
+    let rendered = render_blocking(
+        Arc::from(&body[..]),
+        prefs,
+        Assets::default(),
+        collage,
+    )
+    .instrument(span)
+    .await;
+    let rendered = match rendered {
+        Ok(rendered) => rendered,
+        Err(e) => return bad_request(&e.to_string()),
+    };
+    let output_bytes = rendered.images.iter().map(Vec::len).sum();
+    api.stats.record_render(started.elapsed(), output_bytes);
+
+    match output {
+        ApiOutput::Ansi => (
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            rendered.ascii_art,
+        )
+            .into_response(),
+        // Tiles are only useful as Discord attachments
+        ApiOutput::Image if rendered.images.len() > 1 => {
+            bad_request("The render is too large, try a smaller max_size")
+        }
+        ApiOutput::Image => {
+            let mime = rendered.format.mime_type();
+            let image = rendered.images.into_iter().next().unwrap_or_default();
+            ([(header::CONTENT_TYPE, mime)], image).into_response()
+        }
+    }
+}
+
+fn bad_request(message: &str) -> Response {
+    (StatusCode::BAD_REQUEST, message.to_string()).into_response()
+}
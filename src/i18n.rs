@@ -0,0 +1,102 @@
+use std::{collections::HashMap, sync::LazyLock};
+
+use serde::Deserialize;
+
+use crate::{Data, Error};
+
+/// Translations shipped with the bot, the English text in the code being
+/// the source they're keyed by. Parsed once, they're checked at startup
+static LOCALES: LazyLock<HashMap<&str, Locale>> = LazyLock::new(|| {
+    [
+        ("pt-BR", include_str!("../locales/pt-BR.toml")),
+        ("es", include_str!("../locales/es.toml")),
+    ]
+    .into_iter()
+    .map(|(name, text)| {
+        let locale = toml::from_str(text).unwrap_or_else(|e| {
+            panic!("Invalid translation file locales/{name}.toml: {e}")
+        });
+        (name, locale)
+    })
+    .collect()
+});
+
+/// Discord locale codes, and the translation used for each
+const DISCORD_LOCALES: [(&str, &str); 3] =
+    [("pt-BR", "pt-BR"), ("es-ES", "es"), ("es-419", "es")];
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Locale {
+    help: String,
+    #[serde(default)]
+    commands: HashMap<String, CommandText>,
+    /// Replies and errors, by their English text
+    #[serde(default)]
+    messages: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CommandText {
+    name: Option<String>,
+    description: Option<String>,
+    /// Descriptions, by parameter name
+    #[serde(default)]
+    parameters: HashMap<String, String>,
+}
+
+fn locale(discord_locale: Option<&str>) -> Option<&'static Locale> {
+    let (_, name) = DISCORD_LOCALES
+        .iter()
+        .find(|(code, _)| Some(*code) == discord_locale)?;
+    LOCALES.get(name)
+}
+
+/// Fill in the localized names and descriptions Discord shows for
+/// `commands`
+pub fn localize_commands(commands: &mut [poise::Command<Data, Error>]) {
+    for (code, file) in DISCORD_LOCALES {
+        let Some(text) = LOCALES.get(file).map(|l| &l.commands) else {
+            continue;
+        };
+
+        for command in commands.iter_mut() {
+            let Some(text) = text.get(&command.name) else {
+                continue;
+            };
+            if let Some(name) = &text.name {
+                command.name_localizations.insert(code.into(), name.clone());
+            }
+            if let Some(description) = &text.description {
+                command
+                    .description_localizations
+                    .insert(code.into(), description.clone());
+            }
+            for parameter in &mut command.parameters {
+                if let Some(description) = text.parameters.get(&parameter.name)
+                {
+                    parameter
+                        .description_localizations
+                        .insert(code.into(), description.clone());
+                }
+            }
+        }
+    }
+}
+
+/// `message` in the language of `discord_locale`, or as is when there's
+/// no translation of it
+pub fn translate<'a>(
+    discord_locale: Option<&str>,
+    message: &'a str,
+) -> &'a str {
+    locale(discord_locale)
+        .and_then(|l| l.messages.get(message))
+        .map_or(message, String::as_str)
+}
+
+/// The `/help` text in the language of `discord_locale`, if there's one
+pub fn help_text(discord_locale: Option<&str>) -> Option<&'static str> {
+    locale(discord_locale).map(|l| l.help.as_str())
+}
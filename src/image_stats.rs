@@ -0,0 +1,162 @@
+use std::collections::HashSet;
+
+use image::{DynamicImage, GenericImageView, Rgba, imageops::FilterType};
+
+/// Longest side images are measured at, nearest neighbor keeps the colors
+/// of pixel art exact
+const SAMPLE_SIZE: u32 = 256;
+/// Luminance steps between neighbors, out of 255, below which they count
+/// as flat and above which they count as an edge
+const FLAT_STEP: f32 = 8.0;
+const EDGE_STEP: f32 = 48.0;
+/// Pixel art has few colors, and most neighbors share theirs exactly
+const PIXEL_ART_COLORS: usize = 64;
+const PIXEL_ART_IDENTICAL: f32 = 0.6;
+/// Drawings are mostly flat and pale in color, with some strong lines
+/// standing out from the paper
+const DRAWING_FLAT: f32 = 0.75;
+const DRAWING_EDGES: f32 = 0.01;
+const DRAWING_CONTRAST: f32 = 0.2;
+const DRAWING_SATURATION: f32 = 0.25;
+/// Step, out of 1, kept between glyphs of the median luminance and their
+/// background by the automatic background brightness, and its highest
+/// pick
+const GLYPH_CONTRAST: f32 = 0.35;
+const MAX_AUTO_BACKGROUND: f32 = 0.6;
+
+/// What an image looks like, for picking how to draw it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageKind {
+    Photo,
+    PixelArt,
+    Drawing,
+}
+
+/// Figures an image is classified by, shares of neighboring pixel pairs
+/// and averages from 0 to 1
+#[derive(Debug)]
+pub struct ImageStats {
+    /// Distinct opaque colors
+    pub colors: usize,
+    /// Neighbors of the exact same color
+    pub identical: f32,
+    /// Neighbors of about the same luminance
+    pub flat: f32,
+    /// Neighbors across a strong luminance step
+    pub edges: f32,
+    /// Standard deviation of the luminance, relative to its largest
+    pub contrast: f32,
+    /// Mean saturation, how far colors are from grays
+    pub saturation: f32,
+    /// Luminance half of the pixels are brighter than
+    pub median_luma: f32,
+}
+
+impl ImageStats {
+    pub fn measure(image: &DynamicImage) -> Self {
+        let (width, height) = image.dimensions();
+        let scale = (SAMPLE_SIZE as f32 / width.max(height) as f32).min(1.0);
+        let sample = image
+            .resize_exact(
+                ((width as f32 * scale).round() as u32).max(1),
+                ((height as f32 * scale).round() as u32).max(1),
+                FilterType::Nearest,
+            )
+            .to_rgba8();
+        let (width, height) = sample.dimensions();
+
+        let mut colors = HashSet::new();
+        let mut histogram = [0u32; 256];
+        let (mut pairs, mut identical, mut flat, mut edges) = (0, 0, 0, 0);
+        let (mut luma_sum, mut luma_squares, mut saturation) = (0.0, 0.0, 0.0);
+        for (x, y, pixel) in sample.enumerate_pixels() {
+            if pixel[3] > 0 {
+                colors.insert([pixel[0], pixel[1], pixel[2]]);
+            }
+            let brightness = luma(pixel);
+            histogram[brightness as usize] += 1;
+            luma_sum += brightness;
+            luma_squares += brightness * brightness;
+            saturation += pixel_saturation(pixel);
+
+            for (nx, ny) in [(x + 1, y), (x, y + 1)] {
+                if nx >= width || ny >= height {
+                    continue;
+                }
+                let neighbor = sample.get_pixel(nx, ny);
+                pairs += 1;
+                if neighbor == pixel {
+                    identical += 1;
+                }
+                match (brightness - luma(neighbor)).abs() {
+                    step if step < FLAT_STEP => flat += 1,
+                    step if step > EDGE_STEP => edges += 1,
+                    _ => {}
+                }
+            }
+        }
+
+        let pixels = (width * height) as f32;
+        let pairs = pairs.max(1) as f32;
+        let mean = luma_sum / pixels;
+        let mut below = 0;
+        let median = histogram
+            .iter()
+            .position(|&count| {
+                below += count;
+                below * 2 >= width * height
+            })
+            .unwrap_or(0);
+        let variance = (luma_squares / pixels - mean * mean).max(0.0);
+        Self {
+            colors: colors.len(),
+            identical: identical as f32 / pairs,
+            flat: flat as f32 / pairs,
+            edges: edges as f32 / pairs,
+            contrast: (variance.sqrt() / 127.5).min(1.0),
+            saturation: saturation / pixels,
+            median_luma: median as f32 / 255.0,
+        }
+    }
+
+    /// Background brightness, in percent, that keeps glyphs of the median
+    /// luminance a clear step above their background. Bright images can
+    /// bleed more of their colors into it than dark ones, which get none
+    pub fn background_brightness(&self) -> u32 {
+        let bleed = 1.0 - GLYPH_CONTRAST / self.median_luma.max(f32::EPSILON);
+        (bleed.clamp(0.0, MAX_AUTO_BACKGROUND) * 100.0).round() as u32
+    }
+
+    /// Pixel art has a small palette of exactly repeated colors, drawings
+    /// are flat, contrasted and pale, everything else is treated as a
+    /// photo
+    pub fn kind(&self) -> ImageKind {
+        if self.colors <= PIXEL_ART_COLORS
+            && self.identical >= PIXEL_ART_IDENTICAL
+        {
+            ImageKind::PixelArt
+        } else if self.flat >= DRAWING_FLAT
+            && self.edges >= DRAWING_EDGES
+            && self.contrast >= DRAWING_CONTRAST
+            && self.saturation <= DRAWING_SATURATION
+        {
+            ImageKind::Drawing
+        } else {
+            ImageKind::Photo
+        }
+    }
+}
+
+fn luma(pixel: &Rgba<u8>) -> f32 {
+    0.299 * pixel[0] as f32 + 0.587 * pixel[1] as f32 + 0.114 * pixel[2] as f32
+}
+
+/// HSV saturation, 0 for grays
+fn pixel_saturation(pixel: &Rgba<u8>) -> f32 {
+    let max = pixel.0[..3].iter().max().copied().unwrap_or(0);
+    let min = pixel.0[..3].iter().min().copied().unwrap_or(0);
+    if max == 0 {
+        return 0.0;
+    }
+    (max - min) as f32 / max as f32
+}
@@ -1,77 +1,907 @@
-use ab_glyph::{FontRef, PxScale};
-use image::{GenericImageView, ImageBuffer, Rgba, RgbaImage};
+use ab_glyph::{Font, FontArc, FontRef, PxScale, ScaleFont};
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::codecs::jpeg::JpegEncoder;
+use image::{
+    Delay, DynamicImage, Frame, GenericImageView, ImageBuffer, ImageFormat,
+    ImageReader, Limits, Rgba, RgbaImage,
+    imageops::{self, colorops},
+};
 use imageproc::drawing::{draw_filled_rect_mut, draw_text_mut};
+use imageproc::filter::median_filter;
+use imageproc::gradients;
 use imageproc::rect::Rect;
-use std::io::Cursor;
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io::{Cursor, Write as _};
+use std::sync::Arc;
 
-use crate::Res;
+use crate::{
+    Res,
+    ansi::{self, Cell, ParseMode},
+    blocks::{BlockMode, make_blocks},
+    color_filter::ColorFilter,
+    glyph_atlas::GlyphAtlas,
+    glyph_density,
+    glyph_shapes::GlyphShapes,
+};
+
+/// Characters used when none are picked, from darkest to brightest
+pub const DEFAULT_CHARSET: &str = ".:-+=#@";
+/// Characters the shapes mode picks from when none are picked: lines and
+/// corners in every direction, then denser fills. No longer than a charset
+/// users may type, as it's saved like one by inverting or embedding it
+pub const SHAPES_CHARSET: &str = " .,':-_=+/\\|<>^v*#%@";
+/// Pixel size the glyphs are rendered at by default
+pub const FONT_SIZE: u32 = 18;
+/// Range of pixel sizes users may pick
+pub const MIN_FONT_SIZE: u32 = 12;
+pub const MAX_FONT_SIZE: u32 = 36;
+/// Text rows rendered per parallel job
+const BAND_ROWS: usize = 4;
+/// Widest and tallest art, in characters. The tiers set the limits users
+/// actually get, up to this
+pub const MAX_COLUMNS: u32 = 400;
+/// Largest character or line spacing, in pixels
+pub const MAX_SPACING: u32 = 32;
+/// Most levels per color channel when posterizing
+pub const MAX_POSTERIZE: u32 = 32;
+/// Range of the gamma correction
+pub const MIN_GAMMA: f32 = 0.1;
+pub const MAX_GAMMA: f32 = 5.0;
+/// Largest possible Sobel gradient magnitude of 8 bit images
+const SOBEL_MAX: f32 = 4.0 * 255.0;
+/// Gradient strength, from 0 to 1, above which cells draw a line
+const EDGE_THRESHOLD: f32 = 0.25;
+/// Higher one of the shaded edges mode, photos are full of weak gradients
+/// that would otherwise all get outlined
+const OUTLINE_THRESHOLD: f32 = 0.4;
+/// Samples across each cell the shapes mode compares with glyphs, and
+/// most down, whose count follows the cell's proportions
+const SHAPE_SAMPLES: u32 = 4;
+const MAX_SHAPE_SAMPLES: u32 = 16;
+/// Steps the typewriter animation reveals the art in by default and at
+/// most, and how long each one and the finished art are shown
+pub const TYPEWRITER_FRAMES: u32 = 24;
+const MAX_TYPEWRITER_FRAMES: u32 = 120;
+const TYPEWRITER_DELAY_MS: u32 = 80;
+const TYPEWRITER_HOLD_MS: u32 = 2000;
+/// Steps of the rain animation and how long each one and the finished art
+/// are shown, about 2 seconds of falling. Columns start falling up to a
+/// third of the steps late
+const RAIN_FRAMES: u32 = 24;
+const RAIN_DELAY_MS: u32 = 80;
+const RAIN_HOLD_MS: u32 = 2000;
+const RAIN_STAGGER: u32 = RAIN_FRAMES / 3;
+/// Color of the glyph leading each falling column
+const RAIN_HEAD: Rgba<u8> = Rgba([210, 255, 210, 255]);
+/// How long the before and after animation shows the input and the art,
+/// and the steps and step length of the fades between them
+const BEFORE_AFTER_HOLD_MS: u32 = 1500;
+const BEFORE_AFTER_FADE_FRAMES: u32 = 8;
+const BEFORE_AFTER_FADE_MS: u32 = 50;
+/// GIF quantization speed, from 1 (best colors) to 30 (fastest)
+const GIF_SPEED: i32 = 20;
+/// Blur radius and brightness factor of background images
+const BACKDROP_BLUR: f32 = 6.0;
+const BACKDROP_BRIGHTNESS: f32 = 0.45;
+/// Pixels per cell, across and down, the input is reduced to before
+/// denoising or sharpening. Both then work at about the scale cells
+/// sample, and huge photos aren't filtered at full size
+const FILTER_SCALE: u32 = 4;
+/// Blur radius of the unsharp mask, in pixels of the reduced input, and
+/// its largest strength in percent
+const SHARPEN_SIGMA: f32 = 1.0;
+pub const MAX_SHARPEN: u32 = 200;
+/// Quality of JPEG outputs, out of 100
+const JPEG_QUALITY: u8 = 85;
+/// Range of the width stretch factor
+pub const MIN_STRETCH: f32 = 0.25;
+pub const MAX_STRETCH: f32 = 4.0;
+/// Most pixels an input image may have, checked before decoding it
+pub const DEFAULT_PIXEL_BUDGET: u64 = 40_000_000;
+/// Largest font file users may upload
+pub const MAX_FONT_FILE_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Which way a background gradient runs
+#[derive(Clone, Copy, Default)]
+pub enum GradientDirection {
+    /// Top to bottom
+    #[default]
+    Vertical,
+    /// Left to right
+    Horizontal,
+    /// Top left to bottom right
+    Diagonal,
+}
+
+/// Two color gradient filling the canvas behind the glyphs
+#[derive(Clone, Copy)]
+pub struct Gradient {
+    pub from: [u8; 3],
+    pub to: [u8; 3],
+    pub direction: GradientDirection,
+}
+
+impl Gradient {
+    /// Color at `(x, y)` of a `width` by `height` canvas
+    fn color_at(&self, x: u32, y: u32, width: u32, height: u32) -> Rgba<u8> {
+        let progress = |position: u32, length: u32| {
+            position as f32 / length.saturating_sub(1).max(1) as f32
+        };
+        let t = match self.direction {
+            GradientDirection::Vertical => progress(y, height),
+            GradientDirection::Horizontal => progress(x, width),
+            GradientDirection::Diagonal => {
+                (progress(x, width) + progress(y, height)) / 2.0
+            }
+        };
+
+        let mix = |i: usize| {
+            let (from, to) = (self.from[i] as f32, self.to[i] as f32);
+            (from + (to - from) * t).round() as u8
+        };
+        Rgba([mix(0), mix(1), mix(2), 255])
+    }
+
+    /// End point attributes of an SVG `<linearGradient>`
+    fn svg_vector(&self) -> &'static str {
+        match self.direction {
+            GradientDirection::Vertical => "x2=\"0\" y2=\"1\"",
+            GradientDirection::Horizontal => "x2=\"1\" y2=\"0\"",
+            GradientDirection::Diagonal => "x2=\"1\" y2=\"1\"",
+        }
+    }
+
+    fn css_direction(&self) -> &'static str {
+        match self.direction {
+            GradientDirection::Vertical => "to bottom",
+            GradientDirection::Horizontal => "to right",
+            GradientDirection::Diagonal => "to bottom right",
+        }
+    }
+}
+
+/// Monospace fonts embedded in the binary, each shipped with its license
+/// in `fonts/`. Others are loaded with `load_font_file`
+#[derive(Clone, Copy, Default)]
+pub enum BundledFont {
+    #[default]
+    RobotoMono,
+    DejaVuSansMono,
+}
+
+impl BundledFont {
+    /// Family name of the font, as installed fonts are looked up by
+    pub fn family(self) -> &'static str {
+        match self {
+            BundledFont::RobotoMono => "Roboto Mono",
+            BundledFont::DejaVuSansMono => "DejaVu Sans Mono",
+        }
+    }
+
+    fn load(self) -> Res<FontArc> {
+        Ok(FontArc::try_from_slice(self.data())?)
+    }
+
+    fn data(self) -> &'static [u8] {
+        match self {
+            BundledFont::RobotoMono => {
+                include_bytes!("../fonts/RobotoMono-Regular.ttf")
+            }
+            BundledFont::DejaVuSansMono => {
+                include_bytes!("../fonts/DejaVuSansMono.ttf")
+            }
+        }
+    }
+}
 
 pub struct AsciiRenderer {
-    font: FontRef<'static>,
+    font: FontArc,
+    /// Family of the bundled font drawn with, unknown for custom fonts
+    font_family: Option<&'static str>,
+    scale: PxScale,
     char_width: u32,
     char_height: u32,
+    /// Extra pixels between characters and between lines
+    char_spacing: u32,
+    line_spacing: u32,
     background_color: Rgba<u8>,
     max_width_chars: u32,
+    max_height_chars: Option<u32>,
     background_brightness: f32,
+    charset: String,
+    style: libasciic::Style,
+    gamma: f32,
+    contrast: f32,
+    brightness: i32,
+    threshold: u8,
+    posterize: Option<u8>,
+    denoise: bool,
+    sharpen: f32,
+    palette: Vec<Rgba<u8>>,
+    color_filter: Option<ColorFilter>,
+    dither: bool,
+    pixel_budget: u64,
+    quarter_turns: u32,
+    flip_horizontal: bool,
+    flip_vertical: bool,
+    crop: Option<[u32; 4]>,
+    stretch: f32,
+    background_image: Option<Arc<RgbaImage>>,
+    background_gradient: Option<Gradient>,
+    typewriter_frames: u32,
 }
 
-impl AsciiRenderer {
-    pub fn new(background_brightness: f32, max_width: u32) -> Res<Self> {
-        let font_data = include_bytes!("../fonts/RobotoMono-Regular.ttf");
-        let font = FontRef::try_from_slice(font_data)?;
-        let background_color = Rgba([0, 0, 0, 255]);
-        let background_brightness = background_brightness.clamp(0.0, 1.0);
+/// Options of an [`AsciiRenderer`], every setter clamps its value to what
+/// the renderer supports
+pub struct AsciiRendererBuilder {
+    font: BundledFont,
+    custom_font: Option<FontArc>,
+    font_size: u32,
+    char_spacing: u32,
+    line_spacing: u32,
+    transparent: bool,
+    max_width: u32,
+    max_height: Option<u32>,
+    background_brightness: f32,
+    charset: String,
+    sort_charset: bool,
+    style: libasciic::Style,
+    gamma: f32,
+    contrast: i32,
+    brightness: i32,
+    threshold: u8,
+    posterize: Option<u8>,
+    denoise: bool,
+    sharpen: f32,
+    palette: Vec<[u8; 3]>,
+    color_filter: Option<ColorFilter>,
+    dither: bool,
+    pixel_budget: u64,
+    quarter_turns: u32,
+    flip_horizontal: bool,
+    flip_vertical: bool,
+    crop: Option<[u32; 4]>,
+    stretch: f32,
+    background_image: Option<Arc<RgbaImage>>,
+    background_gradient: Option<Gradient>,
+    typewriter_frames: u32,
+}
+
+impl Default for AsciiRendererBuilder {
+    fn default() -> Self {
+        Self {
+            font: BundledFont::default(),
+            custom_font: None,
+            font_size: FONT_SIZE,
+            char_spacing: 0,
+            line_spacing: 0,
+            transparent: false,
+            max_width: MAX_COLUMNS,
+            max_height: None,
+            background_brightness: 0.0,
+            charset: DEFAULT_CHARSET.to_string(),
+            sort_charset: false,
+            style: libasciic::Style::Mixed,
+            gamma: 1.0,
+            contrast: 0,
+            brightness: 0,
+            threshold: 0,
+            posterize: None,
+            denoise: false,
+            sharpen: 0.0,
+            palette: Vec::new(),
+            color_filter: None,
+            dither: false,
+            pixel_budget: DEFAULT_PIXEL_BUDGET,
+            quarter_turns: 0,
+            flip_horizontal: false,
+            flip_vertical: false,
+            crop: None,
+            stretch: 1.0,
+            background_image: None,
+            background_gradient: None,
+            typewriter_frames: TYPEWRITER_FRAMES,
+        }
+    }
+}
+
+impl AsciiRendererBuilder {
+    /// One of the fonts embedded in the binary
+    pub fn font(mut self, font: BundledFont) -> Self {
+        self.font = font;
+        self
+    }
+
+    /// Draw with a font other than the bundled ones, takes precedence over
+    /// [`Self::font`]
+    pub fn custom_font(mut self, font: Option<FontArc>) -> Self {
+        self.custom_font = font;
+        self
+    }
+
+    /// Pixel size of the glyphs, bigger cells give a crisper but larger image
+    pub fn font_size(mut self, size: u32) -> Self {
+        self.font_size = size.clamp(MIN_FONT_SIZE, MAX_FONT_SIZE);
+        self
+    }
+
+    /// Gaps between characters and between lines, in pixels
+    pub fn spacing(mut self, char_spacing: u32, line_spacing: u32) -> Self {
+        self.char_spacing = char_spacing.min(MAX_SPACING);
+        self.line_spacing = line_spacing.min(MAX_SPACING);
+        self
+    }
+
+    /// Leave the background fully transparent instead of solid black
+    pub fn transparent(mut self, transparent: bool) -> Self {
+        self.transparent = transparent;
+        self
+    }
+
+    /// Maximum number of columns
+    pub fn max_width(mut self, max_width: u32) -> Self {
+        self.max_width = max_width.clamp(1, MAX_COLUMNS);
+        self
+    }
+
+    /// Limit the number of rows, the width shrinks to keep proportions
+    pub fn max_height(mut self, max_height: Option<u32>) -> Self {
+        self.max_height = max_height.map(|h| h.clamp(1, MAX_COLUMNS));
+        self
+    }
+
+    /// How bright the background of each cell is painted, 0.0 to 1.0
+    pub fn background_brightness(mut self, brightness: f32) -> Self {
+        self.background_brightness = brightness.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Characters used from darkest to brightest
+    pub fn charset(mut self, charset: &str) -> Self {
+        self.charset = charset.to_string();
+        self
+    }
+
+    /// Order the charset by how much ink its glyphs use in the font drawn
+    /// with, instead of trusting the order it was given in
+    pub fn sort_charset(mut self, sort: bool) -> Self {
+        self.sort_charset = sort;
+        self
+    }
+
+    /// How the colors are painted on the characters
+    pub fn style(mut self, style: libasciic::Style) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// Gamma applied to the image before conversion, above 1.0 brightens
+    pub fn gamma(mut self, gamma: f32) -> Self {
+        self.gamma = gamma.clamp(MIN_GAMMA, MAX_GAMMA);
+        self
+    }
+
+    /// Contrast and brightness changes in percent, -100 to 100
+    pub fn adjustments(mut self, contrast: i32, brightness: i32) -> Self {
+        self.contrast = contrast.clamp(-100, 100);
+        self.brightness = brightness.clamp(-100, 100);
+        self
+    }
+
+    /// Brightness under which cells are left blank
+    pub fn threshold(mut self, threshold: u8) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Levels each color channel is reduced to before picking characters,
+    /// 2 to 32, for a flat color look. Fewer than 2 turns it off
+    pub fn posterize(mut self, levels: u32) -> Self {
+        self.posterize = (levels >= 2).then(|| levels.min(MAX_POSTERIZE) as u8);
+        self
+    }
+
+    /// Median filter the input before picking characters, so the stray
+    /// bright pixels of noisy photos don't each flip a cell
+    pub fn denoise(mut self, denoise: bool) -> Self {
+        self.denoise = denoise;
+        self
+    }
+
+    /// Unsharp mask strength in percent, 0 to 200, applied before the
+    /// input is reduced to the grid so fine edges survive it
+    pub fn sharpen(mut self, percent: u32) -> Self {
+        self.sharpen = percent.min(MAX_SHARPEN) as f32 / 100.0;
+        self
+    }
+
+    /// Error-diffuse the luminance before picking characters, smooths out
+    /// gradients drawn with short charsets
+    pub fn dither(mut self, dither: bool) -> Self {
+        self.dither = dither;
+        self
+    }
+
+    /// Colors every cell gets snapped to, empty keeps the original colors
+    pub fn palette(mut self, palette: &[[u8; 3]]) -> Self {
+        self.palette = palette.to_vec();
+        self
+    }
+
+    /// Applied to every cell after the palette, so it shows how the final
+    /// colors look through it
+    pub fn color_filter(mut self, filter: Option<ColorFilter>) -> Self {
+        self.color_filter = filter;
+        self
+    }
+
+    /// Inputs with more pixels than this are refused before being decoded
+    pub fn pixel_budget(mut self, pixels: u64) -> Self {
+        self.pixel_budget = pixels.max(1);
+        self
+    }
+
+    /// Rotate the input clockwise by this many quarter turns
+    pub fn rotate(mut self, quarter_turns: u32) -> Self {
+        self.quarter_turns = quarter_turns % 4;
+        self
+    }
+
+    /// Mirror the input, applied after the rotation
+    pub fn flip(mut self, horizontal: bool, vertical: bool) -> Self {
+        self.flip_horizontal = horizontal;
+        self.flip_vertical = vertical;
+        self
+    }
+
+    /// Region of the input converted, as `[x, y, width, height]` in
+    /// pixels of the rotated and flipped input. It's clamped to the image,
+    /// `None` converts all of it
+    pub fn crop(mut self, crop: Option<[u32; 4]>) -> Self {
+        self.crop = crop;
+        self
+    }
+
+    /// Widen the art by this factor, or squash it below 1.0, instead of
+    /// keeping the proportions of the input
+    pub fn stretch(mut self, stretch: f32) -> Self {
+        self.stretch = stretch.clamp(MIN_STRETCH, MAX_STRETCH);
+        self
+    }
+
+    /// Image drawn, blurred and darkened, behind the glyphs instead of the
+    /// flat background color
+    pub fn background_image(mut self, image: Option<Arc<RgbaImage>>) -> Self {
+        self.background_image = image;
+        self
+    }
+
+    /// Gradient filling the background instead of the flat color, under
+    /// [`Self::background_image`] if both are set
+    pub fn background_gradient(mut self, gradient: Option<Gradient>) -> Self {
+        self.background_gradient = gradient;
+        self
+    }
+
+    /// Steps [`AsciiRenderer::render_typewriter`] reveals the art in, more
+    /// make a smoother and longer animation
+    pub fn typewriter_frames(mut self, frames: u32) -> Self {
+        self.typewriter_frames = frames.clamp(2, MAX_TYPEWRITER_FRAMES);
+        self
+    }
 
-        Ok(Self {
+    /// Load the font and size the cells from it
+    pub fn build(self) -> Res<AsciiRenderer> {
+        let font_family =
+            self.custom_font.is_none().then(|| self.font.family());
+        let font = match self.custom_font {
+            Some(font) => font,
+            None => self.font.load()?,
+        };
+        let scale = PxScale::from(self.font_size as f32);
+        // Cells are the glyph size plus the spacing around them
+        let (width, height) = cell_size(&font, scale);
+        let background_color = if self.transparent {
+            Rgba([0, 0, 0, 0])
+        } else {
+            Rgba([0, 0, 0, 255])
+        };
+        let charset = if self.sort_charset {
+            glyph_density::sort_by_density(&font, &self.charset)
+        } else {
+            self.charset
+        };
+
+        Ok(AsciiRenderer {
             font,
-            char_width: 9,
-            char_height: 18,
+            font_family,
+            scale,
+            char_width: width + self.char_spacing,
+            char_height: height + self.line_spacing,
+            char_spacing: self.char_spacing,
+            line_spacing: self.line_spacing,
             background_color,
-            max_width_chars: max_width.min(200),
-            background_brightness,
+            max_width_chars: self.max_width,
+            max_height_chars: self.max_height,
+            background_brightness: self.background_brightness,
+            charset,
+            style: self.style,
+            gamma: self.gamma,
+            contrast: self.contrast as f32,
+            brightness: self.brightness * 255 / 100,
+            threshold: self.threshold,
+            posterize: self.posterize,
+            denoise: self.denoise,
+            sharpen: self.sharpen,
+            palette: self
+                .palette
+                .iter()
+                .map(|&[r, g, b]| Rgba([r, g, b, 255]))
+                .collect(),
+            color_filter: self.color_filter,
+            dither: self.dither,
+            pixel_budget: self.pixel_budget,
+            quarter_turns: self.quarter_turns,
+            flip_horizontal: self.flip_horizontal,
+            flip_vertical: self.flip_vertical,
+            crop: self.crop,
+            stretch: self.stretch,
+            background_image: self.background_image,
+            background_gradient: self.background_gradient,
+            typewriter_frames: self.typewriter_frames,
         })
     }
+}
+
+impl AsciiRenderer {
+    pub fn builder() -> AsciiRendererBuilder {
+        AsciiRendererBuilder::default()
+    }
+
+    /// Characters the art is drawn with, in the order they're used
+    pub fn charset(&self) -> &str {
+        &self.charset
+    }
 
     /// Convert image bytes to ASCII art with proper aspect ratio
-    pub fn process_image(
-        &self,
-        image_bytes: &[u8],
-        charset: &str,
-    ) -> Res<String> {
+    pub fn process_image(&self, image_bytes: &[u8]) -> Res<String> {
         // Load the image to get dimensions
-        let img = image::load_from_memory(image_bytes)?;
+        let img = self.load_image(image_bytes)?;
         let (img_width, img_height) = img.dimensions();
 
         let (target_width, target_height) =
             self.calculate_ascii_dimensions(img_width, img_height);
 
+        let image_bytes = if self.adjusts_colors()
+            || self.denoise
+            || self.sharpen > 0.0
+            || self.dither
+            || self.posterize.is_some()
+            || self.transforms_input()
+        {
+            let mut adjusted = self.prepare(&img, target_width, target_height);
+            // Dither and posterize at the final size, resizing would blur
+            // them away
+            if self.dither || self.posterize.is_some() {
+                adjusted = imageops::resize(
+                    &adjusted,
+                    target_width,
+                    target_height,
+                    imageops::FilterType::Lanczos3,
+                );
+            }
+            if let Some(levels) = self.posterize {
+                posterize(&mut adjusted, levels);
+            }
+            if self.dither {
+                dither_luminance(&mut adjusted, self.charset.chars().count());
+            }
+            Cow::Owned(encode_png(&adjusted)?)
+        } else {
+            Cow::Borrowed(image_bytes)
+        };
+
         // Convert to ASCII using libasciic
-        let cursor = Cursor::new(image_bytes);
+        let cursor = Cursor::new(image_bytes.as_ref());
         let ascii_art = libasciic::AsciiBuilder::new(cursor)
             .dimensions(target_width, target_height)
             .colorize(true)
-            .style(libasciic::Style::Mixed)
-            .threshold(0)
+            .style(self.style)
+            .threshold(self.threshold)
             .filter_type(libasciic::FilterType::Lanczos3)
-            .charset(charset)
+            .charset(&self.charset)
             .background_brightness(self.background_brightness)
             .make_ascii()?;
 
         Ok(ascii_art)
     }
 
+    /// Convert image bytes to colored block characters instead of a charset
+    pub fn process_blocks(
+        &self,
+        image_bytes: &[u8],
+        mode: BlockMode,
+    ) -> Res<String> {
+        let img = self.load_image(image_bytes)?;
+        let (columns, rows) =
+            self.calculate_ascii_dimensions(img.width(), img.height());
+
+        let mut img = self.prepare(&img, columns, rows);
+        if let Some(levels) = self.posterize {
+            // At the size the blocks sample, which then keeps it as is
+            let (across, down) = mode.pixels();
+            img = imageops::resize(
+                &img,
+                columns * across,
+                rows * down,
+                imageops::FilterType::Lanczos3,
+            );
+            posterize(&mut img, levels);
+        }
+
+        Ok(make_blocks(&img, columns, rows, mode))
+    }
+
+    /// Line art: strong edges become `/ \ | - _` following their direction,
+    /// weaker gradients pick a charset character by their strength. With
+    /// `shading`, only the strongest edges are outlined and the rest of
+    /// the cells are shaded by brightness, like the charset mode
+    pub fn process_edges(
+        &self,
+        image_bytes: &[u8],
+        shading: bool,
+    ) -> Res<String> {
+        let img = self.load_image(image_bytes)?;
+        let (columns, rows) =
+            self.calculate_ascii_dimensions(img.width(), img.height());
+
+        let img = self.prepare(&img, columns, rows);
+        let mut img = imageops::resize(
+            &img,
+            columns,
+            rows,
+            imageops::FilterType::Lanczos3,
+        );
+        if let Some(levels) = self.posterize {
+            posterize(&mut img, levels);
+        }
+        let gray = DynamicImage::ImageRgba8(img.clone()).to_luma8();
+        let gx = gradients::horizontal_sobel(&gray);
+        let gy = gradients::vertical_sobel(&gray);
+
+        let charset: Vec<char> = self.charset.chars().collect();
+        let threshold =
+            if shading { OUTLINE_THRESHOLD } else { EDGE_THRESHOLD };
+        let mut art = String::with_capacity((columns * rows * 20) as usize);
+
+        for y in 0..rows {
+            for x in 0..columns {
+                let dx = gx.get_pixel(x, y)[0] as f32;
+                let dy = gy.get_pixel(x, y)[0] as f32;
+                let magnitude = (dx.hypot(dy) / SOBEL_MAX).min(1.0);
+
+                let luma = gray.get_pixel(x, y)[0];
+                let ch = if magnitude >= threshold {
+                    edge_glyph(dx, dy)
+                } else if charset.is_empty()
+                    || (shading && luma < self.threshold)
+                {
+                    ' '
+                } else if shading {
+                    charset[luma as usize * (charset.len() - 1) / 255]
+                } else {
+                    let level = magnitude / EDGE_THRESHOLD;
+                    charset[(level * (charset.len() - 1) as f32) as usize]
+                };
+
+                let color = img.get_pixel(x, y);
+                let _ = write!(
+                    art,
+                    "\x1b[38;2;{};{};{}m{ch}",
+                    color[0], color[1], color[2]
+                );
+            }
+            art.push_str("\x1b[0m\n");
+        }
+
+        Ok(art)
+    }
+
+    /// Pick each character by its shape: the glyph whose ink best matches
+    /// the brightness of its cell, sample by sample, rather than the one
+    /// as bright on average. Sharper on line art and text, and much slower
+    pub fn process_shapes(&self, image_bytes: &[u8]) -> Res<String> {
+        let img = self.load_image(image_bytes)?;
+        let (columns, rows) =
+            self.calculate_ascii_dimensions(img.width(), img.height());
+
+        let img = self.prepare(&img, columns, rows);
+        let across = SHAPE_SAMPLES;
+        let down = (SHAPE_SAMPLES as f32 * self.char_height as f32
+            / self.char_width as f32)
+            .round()
+            .clamp(1.0, MAX_SHAPE_SAMPLES as f32) as u32;
+        let shapes = GlyphShapes::new(
+            &self.font,
+            self.scale,
+            &self.charset,
+            (self.char_width, self.char_height),
+            (across, down),
+        );
+        let luma = DynamicImage::ImageRgba8(imageops::resize(
+            &img,
+            columns * across,
+            rows * down,
+            imageops::FilterType::Triangle,
+        ))
+        .to_luma8();
+        let mut colors = imageops::resize(
+            &img,
+            columns,
+            rows,
+            imageops::FilterType::Lanczos3,
+        );
+        if let Some(levels) = self.posterize {
+            posterize(&mut colors, levels);
+        }
+
+        let lines: Vec<String> = (0..rows)
+            .into_par_iter()
+            .map(|row| {
+                let mut line = String::with_capacity(columns as usize * 20);
+                let mut cell = Vec::with_capacity((across * down) as usize);
+                for column in 0..columns {
+                    cell.clear();
+                    for y in row * down..(row + 1) * down {
+                        for x in column * across..(column + 1) * across {
+                            cell.push(luma.get_pixel(x, y)[0] as f32 / 255.0);
+                        }
+                    }
+                    let ch = shapes.best_match(&cell);
+                    let color = colors.get_pixel(column, row);
+                    let _ = write!(
+                        line,
+                        "\x1b[38;2;{};{};{}m{ch}",
+                        color[0], color[1], color[2]
+                    );
+                }
+                line.push_str("\x1b[0m\n");
+                line
+            })
+            .collect();
+
+        Ok(lines.concat())
+    }
+
+    /// The input at the size of the character grid, each cell a flat
+    /// square of its color, drawn as big as the art would be. Shows what
+    /// the grid keeps of the image before any character is picked
+    pub fn pixelate(&self, image_bytes: &[u8]) -> Res<RgbaImage> {
+        let img = self.load_image(image_bytes)?;
+        let (columns, rows) =
+            self.calculate_ascii_dimensions(img.width(), img.height());
+
+        let img = self.prepare(&img, columns, rows);
+        let mut cells = imageops::resize(
+            &img,
+            columns,
+            rows,
+            imageops::FilterType::Lanczos3,
+        );
+        if let Some(levels) = self.posterize {
+            posterize(&mut cells, levels);
+        }
+
+        Ok(imageops::resize(
+            &cells,
+            columns * self.char_width,
+            rows * self.char_height,
+            imageops::FilterType::Nearest,
+        ))
+    }
+
+    /// Decode the image within the pixel budget, see [`decode_image`].
+    /// Rotation, flips and the crop region are applied right after
+    pub fn load_image(&self, image_bytes: &[u8]) -> Res<DynamicImage> {
+        let image = decode_image(image_bytes, self.pixel_budget)?;
+        let mut image = match self.quarter_turns {
+            1 => image.rotate90(),
+            2 => image.rotate180(),
+            3 => image.rotate270(),
+            _ => image,
+        };
+        if self.flip_horizontal {
+            image = image.fliph();
+        }
+        if self.flip_vertical {
+            image = image.flipv();
+        }
+
+        let Some([x, y, crop_width, crop_height]) = self.crop else {
+            return Ok(image);
+        };
+        let (width, height) = image.dimensions();
+        if x >= width || y >= height || crop_width == 0 || crop_height == 0 {
+            return Err(format!(
+                "The crop is outside of the {width}x{height} image"
+            )
+            .into());
+        }
+        Ok(image.crop_imm(
+            x,
+            y,
+            crop_width.min(width - x),
+            crop_height.min(height - y),
+        ))
+    }
+
+    /// Whether the decoded input differs from the original in shape
+    fn transforms_input(&self) -> bool {
+        self.quarter_turns != 0
+            || self.flip_horizontal
+            || self.flip_vertical
+            || self.crop.is_some()
+    }
+
+    fn adjusts_colors(&self) -> bool {
+        (self.gamma - 1.0).abs() > f32::EPSILON
+            || self.contrast != 0.0
+            || self.brightness != 0
+    }
+
+    /// The loaded input, denoised then sharpened for a `columns` by `rows`
+    /// grid if asked, then with its colors adjusted
+    fn prepare(
+        &self,
+        image: &DynamicImage,
+        columns: u32,
+        rows: u32,
+    ) -> RgbaImage {
+        let mut image = if self.denoise || self.sharpen > 0.0 {
+            reduce_for_filters(image, columns, rows)
+        } else {
+            image.to_rgba8()
+        };
+        if self.denoise {
+            image = median_filter(&image, 1, 1);
+        }
+        if self.sharpen > 0.0 {
+            sharpen(&mut image, self.sharpen);
+        }
+        self.adjust_colors(&mut image);
+        image
+    }
+
+    /// Apply brightness, contrast and gamma, in that order
+    fn adjust_colors(&self, image: &mut RgbaImage) {
+        if self.brightness != 0 {
+            colorops::brighten_in_place(image, self.brightness);
+        }
+        if self.contrast != 0.0 {
+            colorops::contrast_in_place(image, self.contrast);
+        }
+        if (self.gamma - 1.0).abs() > f32::EPSILON {
+            apply_gamma(image, self.gamma);
+        }
+    }
+
     /// Calculate ASCII dimensions maintaining aspect ratio
-    /// Width is clamped to max_width_chars (200)
+    /// Width is clamped to max_width_chars, height to max_height_chars
     fn calculate_ascii_dimensions(
         &self,
         img_width: u32,
         img_height: u32,
     ) -> (u32, u32) {
-        let aspect_ratio = img_width as f32 / img_height as f32;
+        // Stretching is the same as converting a wider or narrower input
+        let aspect_ratio = img_width as f32 / img_height as f32 * self.stretch;
 
-        // Characters are roughly 2x taller than wide in most monospace fonts
-        // So we need to compensate for this in our aspect ratio calculation
-        let char_aspect_correction = 2.0;
+        // Cells are taller than wide, by how much depends on the font and
+        // its size. Use the cells the image will actually be drawn with
+        let char_aspect_correction =
+            self.char_height as f32 / self.char_width as f32;
 
         let target_width = self.max_width_chars;
 
@@ -80,6 +910,16 @@ impl AsciiRenderer {
             / (aspect_ratio * char_aspect_correction))
             as u32;
 
+        // Too tall, fit the height instead and derive the width from it
+        if let Some(max_height) = self.max_height_chars
+            && target_height > max_height
+        {
+            let target_width = (max_height as f32
+                * aspect_ratio
+                * char_aspect_correction) as u32;
+            return (target_width.clamp(1, self.max_width_chars), max_height);
+        }
+
         // Ensure at least 1 row
         (target_width, target_height.max(1))
     }
@@ -87,170 +927,881 @@ impl AsciiRenderer {
     /// Render ASCII art with ANSI RGB color codes back to an image
     /// Now supports both foreground and background colors
     pub fn render_to_image(&self, ascii_text: &str) -> Res<RgbaImage> {
-        let lines: Vec<&str> = ascii_text.lines().collect();
-        let height = lines.len() as u32;
+        let (parsed, widths, img_width) = self.layout(ascii_text);
+        let img_height = parsed.len() as u32 * self.char_height;
+        let atlas = self.atlas(&parsed);
+        let backdrop = self.image_backdrop(img_width, img_height);
 
-        // Get max width by stripping ANSI codes
-        let width = lines
-            .iter()
-            .map(|l| self.count_visible_chars(l))
-            .max()
-            .unwrap_or(0) as u32;
+        // Bands span the whole width, so stacking them is just
+        // concatenating their pixels
+        let band_height = BAND_ROWS as u32 * self.char_height;
+        let bands: Vec<RgbaImage> = parsed
+            .par_chunks(BAND_ROWS)
+            .enumerate()
+            .map(|(i, rows)| {
+                let area = (img_width, img_height, i as u32 * band_height);
+                self.render_band(rows, area, &atlas, &widths, backdrop.as_ref())
+            })
+            .collect();
+        let pixels = bands.into_iter().flat_map(|b| b.into_raw()).collect();
 
-        let img_width = width * self.char_width;
-        let img_height = height * self.char_height;
+        ImageBuffer::from_raw(img_width, img_height, pixels)
+            .ok_or_else(|| "Rendered bands don't match the image size".into())
+    }
 
-        let mut image = ImageBuffer::from_pixel(
-            img_width,
-            img_height,
-            self.background_color,
-        );
+    /// Render straight to PNG bytes, a few bands at a time, so the whole
+    /// image never sits in memory at once. Except for a background image,
+    /// whose blurred backdrop is prepared at full size first, gradients
+    /// are drawn band by band too
+    pub fn render_to_png(&self, ascii_text: &str) -> Res<Vec<u8>> {
+        let (parsed, widths, img_width) = self.layout(ascii_text);
+        let img_height = parsed.len() as u32 * self.char_height;
+        let atlas = self.atlas(&parsed);
+        let backdrop = self.image_backdrop(img_width, img_height);
 
-        let scale = PxScale::from(self.char_height as f32);
+        let mut png_bytes = Vec::new();
+        let mut encoder =
+            png::Encoder::new(&mut png_bytes, img_width, img_height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut stream = encoder.write_header()?.into_stream_writer()?;
 
-        for (line_idx, line) in lines.iter().enumerate() {
-            let parsed = self.parse_colored_line(line);
+        // One band per thread is drawn at once, then compressed in order
+        let batch_rows = BAND_ROWS * rayon::current_num_threads();
+        for (b, batch) in parsed.chunks(batch_rows).enumerate() {
+            let bands: Vec<RgbaImage> = batch
+                .par_chunks(BAND_ROWS)
+                .enumerate()
+                .map(|(i, rows)| {
+                    let row = b * batch_rows + i * BAND_ROWS;
+                    let top = row as u32 * self.char_height;
+                    let area = (img_width, img_height, top);
+                    let backdrop = backdrop.as_ref();
+                    self.render_band(rows, area, &atlas, &widths, backdrop)
+                })
+                .collect();
+            for band in bands {
+                stream.write_all(band.as_raw())?;
+            }
+        }
+        stream.finish()?;
 
-            for (col_idx, (ch, fg_color, bg_color)) in parsed.iter().enumerate()
-            {
-                let x = col_idx as u32 * self.char_width;
-                let y = line_idx as u32 * self.char_height;
-
-                // Draw background rectangle first if background color is set
-                if let Some(bg) = bg_color {
-                    draw_filled_rect_mut(
-                        &mut image,
-                        Rect::at(x as i32, y as i32)
-                            .of_size(self.char_width, self.char_height),
-                        *bg,
-                    );
-                }
+        Ok(png_bytes)
+    }
 
-                // Draw character with foreground color
-                draw_text_mut(
-                    &mut image,
-                    *fg_color,
-                    x as i32,
-                    y as i32,
-                    scale,
-                    &self.font,
-                    &ch.to_string(),
-                );
+    /// Looping GIF of the art being typed in, left to right and top to
+    /// bottom, in `typewriter_frames` steps. Only the canvas being typed on
+    /// is kept in memory, every frame is encoded as soon as it's drawn
+    pub fn render_typewriter(&self, ascii_text: &str) -> Res<Vec<u8>> {
+        let (parsed, widths, img_width) = self.layout(ascii_text);
+        let img_height = parsed.len() as u32 * self.char_height;
+        let atlas = self.atlas(&parsed);
+
+        let total: usize = parsed.iter().map(Vec::len).sum();
+        let per_frame = total.div_ceil(self.typewriter_frames as usize).max(1);
+        let frame = |canvas: &RgbaImage, delay_ms| {
+            let delay = Delay::from_numer_denom_ms(delay_ms, 1);
+            Frame::from_parts(canvas.clone(), 0, 0, delay)
+        };
+
+        let mut gif_bytes = Vec::new();
+        let mut encoder = GifEncoder::new_with_speed(&mut gif_bytes, GIF_SPEED);
+        encoder.set_repeat(Repeat::Infinite)?;
+
+        let mut canvas = match self.backdrop(img_width, img_height) {
+            Some(backdrop) => backdrop,
+            None => ImageBuffer::from_pixel(
+                img_width,
+                img_height,
+                self.background_color,
+            ),
+        };
+        let mut typed = 0;
+        for (line_idx, cells) in parsed.iter().enumerate() {
+            let y = line_idx as u32 * self.char_height;
+            let mut x = 0;
+
+            for cell in cells {
+                let cell_width = cell_columns(cell, &widths) * self.char_width;
+                self.draw_cell(&mut canvas, cell, x, y, cell_width, &atlas);
+                x += cell_width;
+
+                typed += 1;
+                if typed % per_frame == 0 && typed < total {
+                    encoder
+                        .encode_frame(frame(&canvas, TYPEWRITER_DELAY_MS))?;
+                }
             }
         }
+        // The finished art stays up a while before the loop starts over
+        encoder.encode_frame(frame(&canvas, TYPEWRITER_HOLD_MS))?;
+        drop(encoder);
 
-        Ok(image)
+        Ok(gif_bytes)
     }
 
-    /// Count visible characters (excluding ANSI escape sequences)
-    fn count_visible_chars(&self, line: &str) -> usize {
-        let mut count = 0;
-        let mut chars = line.chars();
+    /// Looping GIF of the art raining into place: every column falls from
+    /// the top after its own short delay, its leading glyph lit up, and
+    /// each cell stays where it lands
+    pub fn render_rain(&self, ascii_text: &str) -> Res<Vec<u8>> {
+        let (parsed, widths, img_width) = self.layout(ascii_text);
+        let img_height = parsed.len() as u32 * self.char_height;
+        let atlas = self.atlas(&parsed);
 
-        while let Some(ch) = chars.next() {
-            if ch == '\x1b' {
-                // Skip ANSI escape sequence
-                if chars.next() == Some('[') {
-                    loop {
-                        match chars.next() {
-                            Some('m') => break,
-                            Some(_) => continue,
-                            None => break,
-                        }
+        // Terminal column, x and width of every cell
+        let placed: Vec<Vec<(u32, u32, u32)>> = parsed
+            .iter()
+            .map(|cells| {
+                let mut column = 0;
+                cells
+                    .iter()
+                    .map(|cell| {
+                        let span = cell_columns(cell, &widths);
+                        let at = (column, column * self.char_width, span);
+                        column += span;
+                        at
+                    })
+                    .collect()
+            })
+            .collect();
+        let rows_per_frame =
+            (parsed.len() as u32).div_ceil(RAIN_FRAMES - RAIN_STAGGER).max(1);
+        // Rows of `column` landed after `steps` steps. Columns start
+        // scattered, but the same way on every render
+        let landed = |column: u32, steps: u32| {
+            let start =
+                (column.wrapping_mul(2_654_435_761) >> 16) % (RAIN_STAGGER + 1);
+            steps.saturating_sub(start) * rows_per_frame
+        };
+
+        let mut gif_bytes = Vec::new();
+        let mut encoder = GifEncoder::new_with_speed(&mut gif_bytes, GIF_SPEED);
+        encoder.set_repeat(Repeat::Infinite)?;
+
+        let mut canvas = match self.backdrop(img_width, img_height) {
+            Some(backdrop) => backdrop,
+            None => ImageBuffer::from_pixel(
+                img_width,
+                img_height,
+                self.background_color,
+            ),
+        };
+        for step in 0..RAIN_FRAMES {
+            let mut heads = Vec::new();
+            for (row, (cells, places)) in parsed.iter().zip(&placed).enumerate()
+            {
+                let row = row as u32;
+                let y = row * self.char_height;
+                for (cell, &(column, x, span)) in cells.iter().zip(places) {
+                    let width = span * self.char_width;
+                    let now = landed(column, step + 1);
+                    if (landed(column, step)..now).contains(&row) {
+                        self.draw_cell(&mut canvas, cell, x, y, width, &atlas);
+                    } else if row == now {
+                        heads.push((cell, x, y, width));
                     }
                 }
+            }
+
+            // Heads fall on, they're drawn on a copy of the landed cells
+            let mut image = canvas.clone();
+            for (cell, x, y, width) in heads {
+                let head = Cell { fg: RAIN_HEAD, bg: None, ..cell.clone() };
+                self.draw_cell(&mut image, &head, x, y, width, &atlas);
+            }
+            let delay_ms = if step + 1 == RAIN_FRAMES {
+                RAIN_HOLD_MS
             } else {
-                count += 1;
+                RAIN_DELAY_MS
+            };
+            let delay = Delay::from_numer_denom_ms(delay_ms, 1);
+            encoder.encode_frame(Frame::from_parts(image, 0, 0, delay))?;
+        }
+        drop(encoder);
+
+        Ok(gif_bytes)
+    }
+
+    /// Looping GIF fading from the input to its art and back, the way
+    /// conversions get shown off. The input, cropped and oriented like for
+    /// the conversion, is fit into the size of the drawn art
+    pub fn render_before_after(
+        &self,
+        ascii_text: &str,
+        image_bytes: &[u8],
+    ) -> Res<Vec<u8>> {
+        let art = self.render_to_image(ascii_text)?;
+        let (width, height) = art.dimensions();
+        let source = self
+            .load_image(image_bytes)?
+            .resize(width, height, imageops::FilterType::Lanczos3)
+            .to_rgba8();
+        let mut original =
+            ImageBuffer::from_pixel(width, height, self.background_color);
+        let x = (width - source.width()) / 2;
+        let y = (height - source.height()) / 2;
+        imageops::overlay(&mut original, &source, x as i64, y as i64);
+
+        let frame = |image: RgbaImage, delay_ms| {
+            let delay = Delay::from_numer_denom_ms(delay_ms, 1);
+            Frame::from_parts(image, 0, 0, delay)
+        };
+        let mut gif_bytes = Vec::new();
+        let mut encoder = GifEncoder::new_with_speed(&mut gif_bytes, GIF_SPEED);
+        encoder.set_repeat(Repeat::Infinite)?;
+
+        for (from, to) in [(&original, &art), (&art, &original)] {
+            encoder.encode_frame(frame(from.clone(), BEFORE_AFTER_HOLD_MS))?;
+            for step in 1..BEFORE_AFTER_FADE_FRAMES {
+                let t = step as f32 / BEFORE_AFTER_FADE_FRAMES as f32;
+                let faded = crossfade(from, to, t);
+                encoder.encode_frame(frame(faded, BEFORE_AFTER_FADE_MS))?;
             }
         }
+        drop(encoder);
+
+        Ok(gif_bytes)
+    }
+
+    /// The background image covering `width` by `height`, or else the
+    /// background gradient
+    fn backdrop(&self, width: u32, height: u32) -> Option<RgbaImage> {
+        if self.background_image.is_some() {
+            return self.image_backdrop(width, height);
+        }
+        let gradient = self.background_gradient?;
+        (width > 0 && height > 0).then(|| {
+            ImageBuffer::from_fn(width, height, |x, y| {
+                gradient.color_at(x, y, width, height)
+            })
+        })
+    }
+
+    /// The background image covering `width` by `height`, blurred and
+    /// darkened so the glyphs stay readable on top of it
+    fn image_backdrop(&self, width: u32, height: u32) -> Option<RgbaImage> {
+        if width == 0 || height == 0 {
+            return None;
+        }
+        let source = self.background_image.as_deref()?;
 
-        count
+        // Scale to cover the whole canvas, cutting off what sticks out
+        let scale = f32::max(
+            width as f32 / source.width() as f32,
+            height as f32 / source.height() as f32,
+        );
+        let cover = imageops::resize(
+            source,
+            ((source.width() as f32 * scale).ceil() as u32).max(width),
+            ((source.height() as f32 * scale).ceil() as u32).max(height),
+            imageops::FilterType::Triangle,
+        );
+        let left = (cover.width() - width) / 2;
+        let top = (cover.height() - height) / 2;
+        let cropped =
+            imageops::crop_imm(&cover, left, top, width, height).to_image();
+
+        let mut backdrop = imageops::fast_blur(&cropped, BACKDROP_BLUR);
+        for pixel in backdrop.pixels_mut() {
+            for channel in pixel.0.iter_mut().take(3) {
+                *channel = (*channel as f32 * BACKDROP_BRIGHTNESS) as u8;
+            }
+            pixel[3] = 255;
+        }
+        Some(backdrop)
     }
 
-    /// Parse a line with RGB ANSI escape codes
-    /// Format: \x1b[38;2;R;G;Bm (foreground) or \x1b[48;2;R;G;Bm (background)
-    /// Returns: Vec<(char, foreground_color, optional_background_color)>
-    fn parse_colored_line(
+    /// Cells of every line, the width in cells of each glyph and the width
+    /// of the image in pixels
+    fn layout(
         &self,
-        line: &str,
-    ) -> Vec<(char, Rgba<u8>, Option<Rgba<u8>>)> {
-        let mut result = Vec::new();
-        let mut current_fg = Rgba([255, 255, 255, 255]); // Default white
-        let mut current_bg: Option<Rgba<u8>> = None; // Default no background
-        let mut chars = line.chars();
-
-        while let Some(ch) = chars.next() {
-            if ch == '\x1b' {
-                // Start of ANSI escape sequence
-                if chars.next() == Some('[') {
-                    let mut code = String::new();
-
-                    // Read until 'm' (end of color code)
-                    loop {
-                        match chars.next() {
-                            Some('m') => break,
-                            Some(c) => code.push(c),
-                            None => break,
-                        }
+        ascii_text: &str,
+    ) -> (Vec<Vec<Cell>>, HashMap<char, u32>, u32) {
+        let parsed: Vec<_> = ascii_text
+            .lines()
+            .map(|line| self.parse_colored_line(line))
+            .collect();
+        let widths = self.cell_widths(&parsed);
+
+        let width = parsed
+            .iter()
+            .map(|cells| row_columns(cells, &widths))
+            .max()
+            .unwrap_or(0);
+
+        (parsed, widths, width * self.char_width)
+    }
+
+    fn atlas(&self, parsed: &[Vec<Cell>]) -> GlyphAtlas {
+        GlyphAtlas::new(
+            &self.font,
+            self.scale,
+            // The rest of a cluster is only drawn where the font has it,
+            // so joiners and variation selectors don't show up as boxes
+            parsed.iter().flatten().flat_map(|cell| {
+                cell.chars()
+                    .filter(|&c| c == cell.ch || self.font.glyph_id(c).0 != 0)
+            }),
+        )
+    }
+
+    /// Render the art as an SVG, one `<rect>` per background and one `<text>`
+    /// per glyph, so it scales freely and stays editable
+    pub fn render_to_svg(&self, ascii_text: &str) -> String {
+        let rows: Vec<_> = ascii_text
+            .lines()
+            .map(|line| self.parse_colored_line(line))
+            .collect();
+        let widths = self.cell_widths(&rows);
+        let columns = rows
+            .iter()
+            .map(|cells| row_columns(cells, &widths))
+            .max()
+            .unwrap_or(0);
+        let (cw, ch) = (self.char_width, self.char_height);
+        let (width, height) = (columns * cw, rows.len() as u32 * ch);
+        let ascent = self.font.as_scaled(self.scale).ascent();
+
+        let mut svg = String::with_capacity(rows.len() * columns as usize * 64);
+        let _ = write!(
+            svg,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" \
+             height=\"{height}\" viewBox=\"0 0 {width} {height}\" \
+             font-family=\"{}\" font-size=\"{}\" xml:space=\"preserve\">",
+            self.css_font_family(),
+            self.scale.y
+        );
+        if let Some(gradient) = self.background_gradient {
+            let vector = gradient.svg_vector();
+            let _ = write!(
+                svg,
+                "<defs><linearGradient id=\"bg\" {vector}>\
+                 <stop offset=\"0\" stop-color=\"{}\"/>\
+                 <stop offset=\"1\" stop-color=\"{}\"/>\
+                 </linearGradient></defs>\
+                 <rect width=\"100%\" height=\"100%\" fill=\"url(#bg)\"/>",
+                hex(rgb(gradient.from)),
+                hex(rgb(gradient.to))
+            );
+        } else if self.background_color[3] > 0 {
+            let _ = write!(
+                svg,
+                "<rect width=\"100%\" height=\"100%\" fill=\"{}\"/>",
+                hex(self.background_color)
+            );
+        }
+
+        for (row, cells) in rows.iter().enumerate() {
+            let y = row as u32 * ch;
+            let mut x = 0;
+            for cell in cells {
+                let cell_width = cell_columns(cell, &widths) * cw;
+
+                if let Some(bg) = cell.bg {
+                    let _ = write!(
+                        svg,
+                        "<rect x=\"{x}\" y=\"{y}\" width=\"{cell_width}\" \
+                         height=\"{ch}\" fill=\"{}\"/>",
+                        hex(bg)
+                    );
+                }
+                if !cell.ch.is_whitespace() || cell.underline {
+                    let mut attributes = String::new();
+                    if cell.bold {
+                        attributes.push_str(" font-weight=\"bold\"");
+                    }
+                    if cell.underline {
+                        attributes.push_str(" text-decoration=\"underline\"");
                     }
 
-                    // Parse RGB code or reset
-                    match self.parse_ansi_rgb(&code) {
-                        Some(AnsiColor::Foreground(color)) => {
-                            current_fg = color;
-                        }
-                        Some(AnsiColor::Background(color)) => {
-                            current_bg = Some(color);
-                        }
-                        Some(AnsiColor::Reset) => {
-                            current_fg = Rgba([255, 255, 255, 255]);
-                            current_bg = None;
-                        }
-                        None => {}
+                    let _ = write!(
+                        svg,
+                        "<text x=\"{x}\" y=\"{:.1}\" \
+                         fill=\"{}\"{attributes}>{}</text>",
+                        y as f32 + ascent,
+                        hex(cell.fg),
+                        cell.chars().map(xml_escape).collect::<String>()
+                    );
+                }
+                x += cell_width;
+            }
+        }
+
+        svg.push_str("</svg>");
+        svg
+    }
+
+    /// CSS font list naming the font drawn with, when it's a bundled one,
+    /// falling back to any monospace font
+    fn css_font_family(&self) -> String {
+        match self.font_family {
+            Some(family) => format!("'{family}', monospace"),
+            None => "monospace".to_string(),
+        }
+    }
+
+    /// A standalone HTML page showing the art in a `<pre>` block, runs of
+    /// equally colored cells share one `<span>`
+    pub fn render_to_html(&self, ascii_text: &str) -> String {
+        let mut html = String::with_capacity(ascii_text.len() * 2);
+        let background = match self.background_gradient {
+            Some(gradient) => format!(
+                "linear-gradient({}, {}, {})",
+                gradient.css_direction(),
+                hex(rgb(gradient.from)),
+                hex(rgb(gradient.to))
+            ),
+            None if self.background_color[3] == 0 => "transparent".to_string(),
+            None => hex(self.background_color),
+        };
+        let _ = write!(
+            html,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>ASCII art</title>\n</head>\n\
+             <body style=\"margin: 0; background: {background}\">\n\
+             <pre style=\"margin: 0; padding: 8px; line-height: 1.2; \
+             font-family: {}\">",
+            self.css_font_family()
+        );
+
+        for line in ascii_text.lines() {
+            let mut span = None;
+
+            for cell in self.parse_colored_line(line) {
+                let style = (cell.fg, cell.bg, cell.bold, cell.underline);
+                if span != Some(style) {
+                    if span.is_some() {
+                        html.push_str("</span>");
+                    }
+                    let _ =
+                        write!(html, "<span style=\"color: {}", hex(cell.fg));
+                    if let Some(bg) = cell.bg {
+                        let _ = write!(html, "; background: {}", hex(bg));
+                    }
+                    if cell.bold {
+                        html.push_str("; font-weight: bold");
                     }
+                    if cell.underline {
+                        html.push_str("; text-decoration: underline");
+                    }
+                    html.push_str("\">");
+                    span = Some(style);
                 }
-            } else {
-                // Regular character - use current colors
-                result.push((ch, current_fg, current_bg));
+                html.extend(cell.chars().map(xml_escape));
+            }
+
+            if span.is_some() {
+                html.push_str("</span>");
+            }
+            html.push('\n');
+        }
+
+        html.push_str("</pre>\n</body>\n</html>\n");
+        html
+    }
+
+    /// Render a horizontal band of text rows into its own image. `area` is
+    /// the width and height of the whole image and the top of the band in
+    /// it, for the part of the backdrop or gradient behind the band
+    fn render_band(
+        &self,
+        rows: &[Vec<Cell>],
+        (img_width, img_height, top): (u32, u32, u32),
+        atlas: &GlyphAtlas,
+        widths: &HashMap<char, u32>,
+        backdrop: Option<&RgbaImage>,
+    ) -> RgbaImage {
+        let band_height = rows.len() as u32 * self.char_height;
+        let mut band = match (backdrop, self.background_gradient) {
+            (Some(backdrop), _) => {
+                imageops::crop_imm(backdrop, 0, top, img_width, band_height)
+                    .to_image()
+            }
+            (None, Some(gradient)) => {
+                ImageBuffer::from_fn(img_width, band_height, |x, y| {
+                    gradient.color_at(x, top + y, img_width, img_height)
+                })
+            }
+            (None, None) => ImageBuffer::from_pixel(
+                img_width,
+                band_height,
+                self.background_color,
+            ),
+        };
+
+        for (line_idx, cells) in rows.iter().enumerate() {
+            let y = line_idx as u32 * self.char_height;
+            let mut x = 0;
+
+            for cell in cells {
+                let cell_width = cell_columns(cell, widths) * self.char_width;
+                self.draw_cell(&mut band, cell, x, y, cell_width, atlas);
+                x += cell_width;
             }
         }
 
-        result
+        band
     }
 
-    /// Parse ANSI RGB color codes
-    /// Formats: 38;2;R;G;B (foreground) or 48;2;R;G;B (background) or 0 (reset)
-    fn parse_ansi_rgb(&self, code: &str) -> Option<AnsiColor> {
-        let parts: Vec<&str> = code.split(';').collect();
+    /// Draw a single cell with its top left corner at `x`, `y`
+    fn draw_cell(
+        &self,
+        image: &mut RgbaImage,
+        cell: &Cell,
+        x: u32,
+        y: u32,
+        cell_width: u32,
+        atlas: &GlyphAtlas,
+    ) {
+        // Draw background rectangle first if background color is set
+        if let Some(bg) = cell.bg {
+            draw_filled_rect_mut(
+                image,
+                Rect::at(x as i32, y as i32)
+                    .of_size(cell_width, self.char_height),
+                bg,
+            );
+        }
 
-        // RGB foreground: 38;2;R;G;B
-        if parts.len() >= 5 && parts[0] == "38" && parts[1] == "2" {
-            let r = parts[2].parse().ok()?;
-            let g = parts[3].parse().ok()?;
-            let b = parts[4].parse().ok()?;
-            return Some(AnsiColor::Foreground(Rgba([r, g, b, 255])));
+        // Draw character with foreground color, bold strikes it a second
+        // time one pixel to the right. Combining marks go over it
+        for ch in cell.chars() {
+            atlas.draw(image, ch, cell.fg, x as i32, y as i32);
+            if cell.bold {
+                atlas.draw(image, ch, cell.fg, x as i32 + 1, y as i32);
+            }
         }
 
-        // RGB background: 48;2;R;G;B
-        if parts.len() >= 5 && parts[0] == "48" && parts[1] == "2" {
-            let r = parts[2].parse().ok()?;
-            let g = parts[3].parse().ok()?;
-            let b = parts[4].parse().ok()?;
-            return Some(AnsiColor::Background(Rgba([r, g, b, 255])));
+        if cell.underline {
+            let thickness = (self.scale.y / 14.0).ceil().max(1.0) as u32;
+            let glyph_height = self.char_height - self.line_spacing;
+            draw_filled_rect_mut(
+                image,
+                Rect::at(x as i32, (y + glyph_height - thickness) as i32)
+                    .of_size(cell_width, thickness),
+                cell.fg,
+            );
         }
+    }
+
+    /// Cells taken by every character of the art: 2 for glyphs much wider
+    /// than a regular cell, like CJK or emoji, 1 otherwise
+    fn cell_widths(&self, rows: &[Vec<Cell>]) -> HashMap<char, u32> {
+        let scaled = self.font.as_scaled(self.scale);
+        let cell = (self.char_width - self.char_spacing) as f32;
+        let mut widths = HashMap::new();
 
-        // Reset code: 0
-        if parts.len() == 1 && parts[0] == "0" {
-            return Some(AnsiColor::Reset);
+        for Cell { ch, .. } in rows.iter().flatten() {
+            widths.entry(*ch).or_insert_with(|| {
+                let advance = scaled.h_advance(self.font.glyph_id(*ch));
+                if advance > cell * 1.5 { 2 } else { 1 }
+            });
         }
 
-        None
+        widths
+    }
+
+    /// Draw a line of plain white text, used for labels around the art
+    pub fn draw_label(
+        &self,
+        image: &mut RgbaImage,
+        x: i32,
+        y: i32,
+        text: &str,
+    ) {
+        draw_text_mut(
+            image,
+            Rgba([255, 255, 255, 255]),
+            x,
+            y,
+            self.scale,
+            &self.font,
+            text,
+        );
+    }
+
+    /// Most columns and rows the art is converted to, after clamping
+    pub fn max_width(&self) -> u32 {
+        self.max_width_chars
     }
+
+    pub fn max_height(&self) -> Option<u32> {
+        self.max_height_chars
+    }
+
+    /// Columns taken by a line of art, see [`ansi::visible_width`]
+    pub fn visible_width(&self, line: &str) -> usize {
+        ansi::visible_width(line)
+    }
+
+    /// Parse a line of ANSI colored text, skipping malformed escapes, with
+    /// its colors snapped to the palette and filtered
+    fn parse_colored_line(&self, line: &str) -> Vec<Cell> {
+        // Lossy parsing never fails
+        let cells =
+            ansi::parse_line(line, ParseMode::Lossy).unwrap_or_default();
+        cells
+            .into_iter()
+            .map(|cell| Cell {
+                fg: self.cell_color(cell.fg),
+                bg: cell.bg.map(|bg| self.cell_color(bg)),
+                ..cell
+            })
+            .collect()
+    }
+
+    /// Color a cell is drawn with
+    fn cell_color(&self, color: Rgba<u8>) -> Rgba<u8> {
+        let color = self.snap_to_palette(color);
+        self.color_filter.map_or(color, |filter| filter.apply(color))
+    }
+
+    /// Nearest palette color, by squared RGB distance
+    fn snap_to_palette(&self, color: Rgba<u8>) -> Rgba<u8> {
+        let distance = |p: &Rgba<u8>| {
+            (0..3).map(|i| (p[i] as i32 - color[i] as i32).pow(2)).sum::<i32>()
+        };
+
+        self.palette
+            .iter()
+            .min_by_key(|p| distance(p))
+            .copied()
+            .unwrap_or(color)
+    }
+}
+
+/// Width of a row of cells, in cells
+fn row_columns(cells: &[Cell], widths: &HashMap<char, u32>) -> u32 {
+    cells.iter().map(|cell| cell_columns(cell, widths)).sum()
+}
+
+/// Cells taken by a cell of text: its terminal width, or more when the
+/// font draws its glyph wider
+fn cell_columns(cell: &Cell, widths: &HashMap<char, u32>) -> u32 {
+    widths[&cell.ch].max(cell.width)
+}
+
+/// Cell size of a monospace font at the given scale: the advance width of
+/// a glyph by the line height, rounded up so glyphs never get clipped
+fn cell_size(font: &impl Font, scale: PxScale) -> (u32, u32) {
+    let scaled = font.as_scaled(scale);
+    let advance = scaled.h_advance(font.glyph_id('M'));
+    let line_height = scaled.height() + scaled.line_gap();
+
+    (advance.ceil().max(1.0) as u32, line_height.ceil().max(1.0) as u32)
+}
+
+/// `#rrggbb` notation of a color, alpha is left out
+fn rgb([r, g, b]: [u8; 3]) -> Rgba<u8> {
+    Rgba([r, g, b, 255])
+}
+
+fn hex(color: Rgba<u8>) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+fn xml_escape(ch: char) -> Cow<'static, str> {
+    match ch {
+        '&' => Cow::Borrowed("&amp;"),
+        '<' => Cow::Borrowed("&lt;"),
+        '>' => Cow::Borrowed("&gt;"),
+        _ => Cow::Owned(ch.to_string()),
+    }
+}
+
+/// Decode an image, refusing it from its header alone when it claims more
+/// than `pixel_budget` pixels, so a tiny file can't expand into gigabytes
+/// of pixels
+pub fn decode_image(
+    image_bytes: &[u8],
+    pixel_budget: u64,
+) -> Res<DynamicImage> {
+    let reader =
+        || ImageReader::new(Cursor::new(image_bytes)).with_guessed_format();
+
+    let (width, height) = reader()?.into_dimensions()?;
+    if width as u64 * height as u64 > pixel_budget {
+        return Err(format!(
+            "The image is {width}x{height}, inputs are limited to {} \
+             megapixels",
+            pixel_budget / 1_000_000
+        )
+        .into());
+    }
+
+    // Decoders may need more than the final pixels, 16 bit channels for
+    // instance, but never more than that
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(pixel_budget * 8);
+    let mut reader = reader()?;
+    reader.limits(limits);
+
+    Ok(reader.decode()?)
+}
+
+/// Validate an uploaded TTF/OTF file and load it
+pub fn load_font_file(bytes: Vec<u8>) -> Res<FontArc> {
+    let font = FontRef::try_from_slice(&bytes)
+        .map_err(|_| "That file isn't a valid TTF/OTF font")?;
+    if font.glyph_id('M').0 == 0 {
+        return Err("That font has no latin characters".into());
+    }
+
+    Ok(FontArc::try_from_vec(bytes)?)
+}
+
+/// Gamma correct every color channel through a lookup table
+fn apply_gamma(image: &mut RgbaImage, gamma: f32) {
+    let lut: Vec<u8> = (0..=255u8)
+        .map(|v| ((v as f32 / 255.0).powf(1.0 / gamma) * 255.0).round() as u8)
+        .collect();
+
+    for pixel in image.pixels_mut() {
+        for channel in &mut pixel.0[..3] {
+            *channel = lut[*channel as usize];
+        }
+    }
+}
+
+/// `from` faded `t` of the way, from 0 to 1, into `to` of the same size
+fn crossfade(from: &RgbaImage, to: &RgbaImage, t: f32) -> RgbaImage {
+    let mut faded = from.clone();
+    for (pixel, target) in faded.pixels_mut().zip(to.pixels()) {
+        for (a, b) in pixel.0.iter_mut().zip(target.0) {
+            *a = (*a as f32 + (b as f32 - *a as f32) * t).round() as u8;
+        }
+    }
+    faded
+}
+
+/// `image` reduced to [`FILTER_SCALE`] pixels per cell of a `columns` by
+/// `rows` grid, or as is when it's smaller
+fn reduce_for_filters(
+    image: &DynamicImage,
+    columns: u32,
+    rows: u32,
+) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    let scale = (columns * FILTER_SCALE) as f32 / width as f32;
+    let scale = scale.min((rows * FILTER_SCALE) as f32 / height as f32);
+    if scale >= 1.0 {
+        return image.to_rgba8();
+    }
+    image
+        .resize_exact(
+            ((width as f32 * scale).round() as u32).max(1),
+            ((height as f32 * scale).round() as u32).max(1),
+            imageops::FilterType::Triangle,
+        )
+        .to_rgba8()
+}
+
+/// Unsharp mask: push every pixel away from its blurred surroundings by
+/// `amount` of the difference
+fn sharpen(image: &mut RgbaImage, amount: f32) {
+    let blurred = imageops::blur(image, SHARPEN_SIGMA);
+    for (pixel, around) in image.pixels_mut().zip(blurred.pixels()) {
+        for (channel, &blur) in pixel.0[..3].iter_mut().zip(&around.0) {
+            let value = *channel as f32;
+            *channel = (value + (value - blur as f32) * amount)
+                .round()
+                .clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+/// Reduce each color channel to `levels` evenly spaced values
+fn posterize(image: &mut RgbaImage, levels: u8) {
+    let step = 255.0 / (levels - 1) as f32;
+    let lut: Vec<u8> = (0..=255u8)
+        .map(|v| ((v as f32 / step).round() * step).round() as u8)
+        .collect();
+
+    for pixel in image.pixels_mut() {
+        for channel in &mut pixel.0[..3] {
+            *channel = lut[*channel as usize];
+        }
+    }
+}
+
+/// Line glyph following an edge, from the Sobel gradient across it
+fn edge_glyph(dx: f32, dy: f32) -> char {
+    // The edge runs perpendicular to the gradient, flip y to get the
+    // usual counterclockwise angle
+    let angle = (-dx).atan2(-dy).to_degrees().rem_euclid(180.0);
+
+    match angle {
+        a if (22.5..67.5).contains(&a) => '/',
+        a if (67.5..112.5).contains(&a) => '|',
+        a if (112.5..157.5).contains(&a) => '\\',
+        // Horizontal, `_` when the bright side is on top
+        _ if dy < 0.0 => '_',
+        _ => '-',
+    }
+}
+
+/// Floyd-Steinberg dithering of the luminance down to `levels` steps, one
+/// per character of the charset. Colors are scaled to keep their hue
+fn dither_luminance(image: &mut RgbaImage, levels: usize) {
+    let steps = levels.saturating_sub(1).max(1) as f32;
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let mut luma: Vec<f32> = image
+        .pixels()
+        .map(|p| {
+            0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32
+        })
+        .collect();
+
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            let old = luma[i].clamp(0.0, 255.0);
+            let new = (old / 255.0 * steps).round() / steps * 255.0;
+            let error = old - new;
+
+            let mut spread = |dx: isize, dy: usize, weight: f32| {
+                let nx = x as isize + dx;
+                if nx >= 0 && (nx as usize) < width && y + dy < height {
+                    luma[(y + dy) * width + nx as usize] += error * weight;
+                }
+            };
+            spread(1, 0, 7.0 / 16.0);
+            spread(-1, 1, 3.0 / 16.0);
+            spread(0, 1, 5.0 / 16.0);
+            spread(1, 1, 1.0 / 16.0);
+
+            let pixel = image.get_pixel_mut(x as u32, y as u32);
+            let factor = if old > 0.0 { new / old } else { 0.0 };
+            for channel in &mut pixel.0[..3] {
+                *channel = (*channel as f32 * factor).round().min(255.0) as u8;
+            }
+        }
+    }
+}
+
+/// Encode a rendered image as PNG bytes, ready to be attached
+pub fn encode_png(image: &RgbaImage) -> Res<Vec<u8>> {
+    let mut png_bytes = Vec::new();
+    image.write_to(&mut Cursor::new(&mut png_bytes), ImageFormat::Png)?;
+    Ok(png_bytes)
+}
+
+/// Encode the final image in the given format
+pub fn encode_image(image: &RgbaImage, format: ImageFormat) -> Res<Vec<u8>> {
+    if format == ImageFormat::Jpeg {
+        return encode_jpeg(image, JPEG_QUALITY);
+    }
+
+    let mut bytes = Vec::new();
+    image.write_to(&mut Cursor::new(&mut bytes), format)?;
+    Ok(bytes)
 }
 
-/// Represents the type of ANSI color code
-enum AnsiColor {
-    Foreground(Rgba<u8>),
-    Background(Rgba<u8>),
-    Reset,
+/// JPEG has no alpha, transparent pixels end up black
+pub fn encode_jpeg(image: &RgbaImage, quality: u8) -> Res<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let flattened = DynamicImage::ImageRgba8(image.clone()).to_rgb8();
+    JpegEncoder::new_with_quality(&mut Cursor::new(&mut bytes), quality)
+        .encode_image(&flattened)?;
+    Ok(bytes)
 }
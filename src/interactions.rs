@@ -0,0 +1,622 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+
+use tracing::instrument;
+
+use poise::{
+    ChoiceParameter, Modal,
+    serenity_prelude::{
+        Builder, ButtonStyle, ComponentInteraction,
+        ComponentInteractionDataKind, Context as SerenityContext,
+        CreateActionRow, CreateAttachment, CreateButton,
+        CreateInteractionResponse, CreateInteractionResponseMessage,
+        CreateSelectMenu, CreateSelectMenuKind, CreateSelectMenuOption,
+        EditInteractionResponse, Entitlement, GuildId, ModalInteraction,
+        UserId,
+    },
+};
+
+use crate::{
+    Data, Res, ansi,
+    commands::{DOWNSCALED_NOTE, TILED_NOTE},
+    i18n::translate,
+    image_to_ascii::{MAX_GAMMA, MIN_GAMMA},
+    moderation,
+    options::{
+        Assets, Filter, PRESETS, Preferences, clean_charset, format_crop,
+        format_palette, parse_crop, parse_palette,
+    },
+    queue::{Ticket, Worker},
+    quota::Reservation,
+    render::{
+        Rendered, drawn_prefs, output_name, pixel_preview, render_blocking,
+        tile_name,
+    },
+    shutdown::RESTARTING,
+    tiers::tier,
+};
+
+/// Input and options of a posted render, kept around for the buttons
+pub struct CachedRender {
+    pub image_bytes: Arc<[u8]>,
+    pub prefs: Preferences,
+    /// File name of the input, used to name the outputs
+    pub source_name: String,
+    pub spoiler: bool,
+    pub collage: bool,
+    /// Files uploaded along with the image
+    pub assets: Assets,
+    pub ascii_art: String,
+    /// Who asked for the render, the only one allowed to change it
+    pub owner: UserId,
+}
+
+/// Bounded cache of the most recent renders, oldest evicted first
+pub struct RenderCache {
+    capacity: usize,
+    next_id: AtomicU64,
+    entries: Mutex<VecDeque<(u64, Arc<CachedRender>)>>,
+}
+
+impl RenderCache {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            capacity,
+            next_id: AtomicU64::new(0),
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn insert(&self, render: CachedRender) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back((id, Arc::new(render)));
+
+        id
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<CachedRender>> {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().find(|(i, _)| *i == id).map(|(_, r)| r.clone())
+    }
+
+    /// Replace a render in place, so its buttons act on the new version
+    pub fn update(&self, id: u64, render: CachedRender) {
+        let mut entries = self.entries.lock().unwrap();
+
+        if let Some(entry) = entries.iter_mut().find(|(i, _)| *i == id) {
+            entry.1 = Arc::new(render);
+        }
+    }
+}
+
+/// Buttons, preset and filter menus attached under every render
+pub fn render_buttons(id: u64) -> Vec<CreateActionRow> {
+    let button = |action: &str, label: &str| {
+        CreateButton::new(format!("ascii:{id}:{action}"))
+            .label(label)
+            .style(ButtonStyle::Secondary)
+    };
+
+    let buttons = CreateActionRow::Buttons(vec![
+        button("invert", "Invert"),
+        button("text", "Text version"),
+        button("style", "Different style"),
+        button("configure", "Configure"),
+        button("pixels", "Pixel preview"),
+    ]);
+    let presets = PRESETS
+        .iter()
+        .enumerate()
+        .map(|(i, preset)| {
+            CreateSelectMenuOption::new(preset.name, i.to_string())
+        })
+        .collect();
+    let menu = CreateSelectMenu::new(
+        format!("ascii:{id}:preset"),
+        CreateSelectMenuKind::String { options: presets },
+    )
+    .placeholder("Try another look");
+    let filters = (0..)
+        .map_while(Filter::from_index)
+        .enumerate()
+        .map(|(i, filter)| {
+            CreateSelectMenuOption::new(filter.name(), i.to_string())
+        })
+        .collect();
+    let filter_menu = CreateSelectMenu::new(
+        format!("ascii:{id}:filter"),
+        CreateSelectMenuKind::String { options: filters },
+    )
+    .placeholder("See it through a filter");
+
+    vec![
+        buttons,
+        CreateActionRow::SelectMenu(menu),
+        CreateActionRow::SelectMenu(filter_menu),
+    ]
+}
+
+/// Refusal for someone changing another user's render
+const NOT_YOURS: &str = "Only who asked for this render can change it";
+
+/// Index picked in a select menu whose values are indices
+fn selected(interaction: &ComponentInteraction) -> Option<usize> {
+    let ComponentInteractionDataKind::StringSelect { values } =
+        &interaction.data.kind
+    else {
+        return None;
+    };
+    values.first()?.parse().ok()
+}
+
+pub async fn handle_component(
+    ctx: &SerenityContext,
+    interaction: &ComponentInteraction,
+    data: &Data,
+) -> Res<()> {
+    let mut parts = interaction.data.custom_id.split(':');
+    let (Some("ascii"), Some(id), Some(action)) =
+        (parts.next(), parts.next(), parts.next())
+    else {
+        return Ok(());
+    };
+    let id: u64 = id.parse()?;
+
+    let Some(cached) = data.render_cache.get(id) else {
+        interaction
+            .create_response(&ctx.http, expired(&interaction.locale))
+            .await?;
+        return Ok(());
+    };
+    // Anyone can look at a render, only its owner can change it
+    if !matches!(action, "text" | "pixels")
+        && interaction.user.id != cached.owner
+    {
+        interaction
+            .create_response(
+                &ctx.http,
+                ephemeral_message(&interaction.locale, NOT_YOURS),
+            )
+            .await?;
+        return Ok(());
+    }
+
+    let mut prefs = cached.prefs.clone();
+    match action {
+        "text" => {
+            let text = strip_ansi(&cached.ascii_art);
+            let message = CreateInteractionResponseMessage::new()
+                .add_file(CreateAttachment::bytes(text, "ascii.txt"))
+                .ephemeral(true);
+            interaction
+                .create_response(
+                    &ctx.http,
+                    CreateInteractionResponse::Message(message),
+                )
+                .await?;
+            return Ok(());
+        }
+        "pixels" => {
+            let Ok(_job) = data.shutdown.start() else {
+                interaction
+                    .create_response(
+                        &ctx.http,
+                        ephemeral_message(&interaction.locale, RESTARTING),
+                    )
+                    .await?;
+                return Ok(());
+            };
+            interaction.defer_ephemeral(&ctx.http).await?;
+            let png = pixel_preview(
+                cached.image_bytes.clone(),
+                prefs,
+                cached.assets.clone(),
+            )
+            .await?;
+            let name = if cached.spoiler {
+                "SPOILER_pixels.png"
+            } else {
+                "pixels.png"
+            };
+            let edit = EditInteractionResponse::new()
+                .new_attachment(CreateAttachment::bytes(png, name));
+            interaction.edit_response(&ctx.http, edit).await?;
+            return Ok(());
+        }
+        "invert" => {
+            // Inverts what was drawn, sorting would undo it
+            let image_bytes = cached.image_bytes.clone();
+            prefs =
+                drawn_prefs(image_bytes, prefs, cached.assets.clone()).await?;
+            prefs.charset = Some(prefs.charset().chars().rev().collect());
+        }
+        "style" => prefs.style = Some(prefs.style().next()),
+        "configure" => {
+            let modal = AdvancedOptions::create(
+                Some(AdvancedOptions::from_prefs(&prefs)),
+                format!("ascii:{id}:advanced"),
+            );
+            interaction.create_response(&ctx.http, modal).await?;
+            return Ok(());
+        }
+        "preset" => {
+            let preset = selected(interaction)
+                .and_then(|i| PRESETS.get(i))
+                .ok_or("Unknown preset")?;
+
+            preset.apply(&mut prefs);
+        }
+        "filter" => {
+            let filter = selected(interaction)
+                .and_then(Filter::from_index)
+                .ok_or("Unknown filter")?;
+            prefs.filter = Some(filter);
+        }
+        _ => return Ok(()),
+    }
+
+    let Ok(_job) = data.shutdown.start() else {
+        interaction
+            .create_response(
+                &ctx.http,
+                ephemeral_message(&interaction.locale, RESTARTING),
+            )
+            .await?;
+        return Ok(());
+    };
+    let joined = join_queue(
+        ctx,
+        data,
+        interaction.guild_id,
+        interaction.user.id,
+        &interaction.entitlements,
+    );
+    let (ticket, reservation) = match joined.await {
+        Ok(joined) => joined,
+        Err(e) => {
+            interaction
+                .create_response(
+                    &ctx.http,
+                    ephemeral_message(&interaction.locale, &e.to_string()),
+                )
+                .await?;
+            return Ok(());
+        }
+    };
+    interaction.defer(&ctx.http).await?;
+    let edit =
+        rerender(ctx, &interaction.token, data, id, &cached, prefs, ticket)
+            .await;
+    if edit.is_err() {
+        data.storage.refund_quota(reservation).await?;
+    }
+    let edit = edit?;
+    interaction.edit_response(&ctx.http, edit).await?;
+    Ok(())
+}
+
+pub async fn handle_modal(
+    ctx: &SerenityContext,
+    interaction: &ModalInteraction,
+    data: &Data,
+) -> Res<()> {
+    let Some(id) = interaction
+        .data
+        .custom_id
+        .strip_prefix("ascii:")
+        .and_then(|rest| rest.strip_suffix(":advanced"))
+    else {
+        return Ok(());
+    };
+    let id: u64 = id.parse()?;
+
+    let Some(cached) = data.render_cache.get(id) else {
+        interaction
+            .create_response(&ctx.http, expired(&interaction.locale))
+            .await?;
+        return Ok(());
+    };
+    if interaction.user.id != cached.owner {
+        interaction
+            .create_response(
+                &ctx.http,
+                ephemeral_message(&interaction.locale, NOT_YOURS),
+            )
+            .await?;
+        return Ok(());
+    }
+
+    let mut prefs = cached.prefs.clone();
+    let modal = AdvancedOptions::parse(interaction.data.clone())?;
+
+    if let Err(e) = modal.apply(&mut prefs) {
+        interaction
+            .create_response(
+                &ctx.http,
+                ephemeral_message(&interaction.locale, &e.to_string()),
+            )
+            .await?;
+        return Ok(());
+    }
+
+    let Ok(_job) = data.shutdown.start() else {
+        interaction
+            .create_response(
+                &ctx.http,
+                ephemeral_message(&interaction.locale, RESTARTING),
+            )
+            .await?;
+        return Ok(());
+    };
+    let joined = join_queue(
+        ctx,
+        data,
+        interaction.guild_id,
+        interaction.user.id,
+        &interaction.entitlements,
+    );
+    let (ticket, reservation) = match joined.await {
+        Ok(joined) => joined,
+        Err(e) => {
+            interaction
+                .create_response(
+                    &ctx.http,
+                    ephemeral_message(&interaction.locale, &e.to_string()),
+                )
+                .await?;
+            return Ok(());
+        }
+    };
+    interaction.defer(&ctx.http).await?;
+    let edit =
+        rerender(ctx, &interaction.token, data, id, &cached, prefs, ticket)
+            .await;
+    if edit.is_err() {
+        data.storage.refund_quota(reservation).await?;
+    }
+    let edit = edit?;
+    interaction.edit_response(&ctx.http, edit).await?;
+    Ok(())
+}
+
+/// Form behind the "Configure" button, for options that don't fit the
+/// slash command
+#[derive(Modal)]
+#[name = "Advanced options"]
+struct AdvancedOptions {
+    #[name = "Charset"]
+    #[placeholder = ".:-+=#@"]
+    #[max_length = 20]
+    charset: Option<String>,
+    #[name = "Palette (comma separated hex colors)"]
+    #[placeholder = "#282828, #cc241d, #98971a, #ebdbb2"]
+    palette: Option<String>,
+    #[name = "Gamma (0.1 - 5.0, above 1 brightens)"]
+    #[placeholder = "1.0"]
+    gamma: Option<String>,
+    #[name = "Threshold (0 - 255)"]
+    #[placeholder = "0"]
+    threshold: Option<String>,
+    #[name = "Crop (x, y, width, height in pixels)"]
+    #[placeholder = "0, 0, 640, 360"]
+    crop: Option<String>,
+}
+
+impl AdvancedOptions {
+    fn from_prefs(prefs: &Preferences) -> Self {
+        Self {
+            charset: prefs.charset.clone(),
+            palette: prefs.palette.as_deref().map(format_palette),
+            gamma: Some(prefs.gamma().to_string()),
+            threshold: Some(prefs.threshold().to_string()),
+            crop: prefs.crop.map(format_crop),
+        }
+    }
+
+    /// Validate the form, emptied fields reset the option
+    fn apply(self, prefs: &mut Preferences) -> Res<()> {
+        prefs.charset = match self.charset.filter(|c| !c.is_empty()) {
+            Some(charset) => Some(clean_charset(&charset)?),
+            None => None,
+        };
+        prefs.palette = match self.palette.filter(|p| !p.trim().is_empty()) {
+            Some(palette) => Some(parse_palette(&palette)?),
+            None => None,
+        };
+        prefs.gamma = match self.gamma {
+            Some(gamma) => Some(
+                gamma
+                    .trim()
+                    .parse::<f32>()
+                    .map_err(|_| "Gamma must be a number")?
+                    .clamp(MIN_GAMMA, MAX_GAMMA),
+            ),
+            None => None,
+        };
+        prefs.threshold = match self.threshold {
+            Some(threshold) => Some(
+                threshold
+                    .trim()
+                    .parse()
+                    .map_err(|_| "Threshold must be between 0 and 255")?,
+            ),
+            None => None,
+        };
+        prefs.crop = match self.crop.filter(|c| !c.trim().is_empty()) {
+            Some(crop) => Some(parse_crop(&crop)?),
+            None => None,
+        };
+
+        Ok(())
+    }
+}
+
+/// Take a place in the render queue, by the user's tier, unless the
+/// moderation checks or the server's daily quotas refuse them. The render
+/// is counted against the quotas right away, refund it if it fails
+async fn join_queue<'a>(
+    ctx: &SerenityContext,
+    data: &'a Data,
+    guild: Option<GuildId>,
+    user: UserId,
+    entitlements: &[Entitlement],
+) -> Res<(Ticket<'a>, Option<Reservation>)> {
+    moderation::check(&data.storage, user, guild).await?;
+    let reservation = match guild {
+        Some(guild) => {
+            let settings = data.storage.guild(guild).await?;
+            data.storage.reserve_quota(guild, user, &settings).await?
+        }
+        None => None,
+    };
+    let tier = tier(ctx, user, entitlements).await;
+    match data.queue.join(tier) {
+        Ok(ticket) => Ok((ticket, reservation)),
+        Err(e) => {
+            data.storage.refund_quota(reservation).await?;
+            Err(e)
+        }
+    }
+}
+
+/// Wait for a render worker, showing the place in line on the deferred
+/// message meanwhile. Also returns whether the render had to wait
+async fn wait_for_worker<'a>(
+    ctx: &SerenityContext,
+    token: &str,
+    ticket: Ticket<'a>,
+) -> Res<(Worker<'a>, bool)> {
+    let Some(position) = ticket.position() else {
+        return Ok((ticket.start().await, false));
+    };
+
+    let status = |text: String| EditInteractionResponse::new().content(text);
+    status(format!("-# Queued, position {position}"))
+        .execute(ctx, token)
+        .await?;
+    let worker = ticket.start().await;
+    status("-# Rendering…".to_string()).execute(ctx, token).await?;
+
+    Ok((worker, true))
+}
+
+/// Render the cached image again, once `ticket` gets a worker unless the
+/// result cache has it, and build the edit replacing the old one
+#[instrument(skip_all, fields(id = id))]
+async fn rerender(
+    ctx: &SerenityContext,
+    token: &str,
+    data: &Data,
+    id: u64,
+    cached: &CachedRender,
+    prefs: Preferences,
+    ticket: Ticket<'_>,
+) -> Res<EditInteractionResponse> {
+    let prefs = ticket.tier().limit(prefs);
+    let key = data.result_cache.key(
+        &cached.image_bytes,
+        &prefs,
+        &cached.assets,
+        cached.collage,
+    );
+    let hit = match key {
+        Some(key) => data.result_cache.get(key).await,
+        None => None,
+    };
+
+    let mut queued = false;
+    let rendered = match hit {
+        Some(rendered) => rendered,
+        None => {
+            let (_worker, waited) = wait_for_worker(ctx, token, ticket).await?;
+            queued = waited;
+
+            let started = Instant::now();
+            let rendered = render_blocking(
+                cached.image_bytes.clone(),
+                prefs.clone(),
+                cached.assets.clone(),
+                cached.collage,
+            )
+            .await?;
+            let output_bytes = rendered.images.iter().map(Vec::len).sum();
+            data.stats.record_render(started.elapsed(), output_bytes);
+
+            if let Some(key) = key {
+                data.result_cache.insert(key, &rendered).await;
+            }
+            rendered
+        }
+    };
+    let Rendered { ascii_art, images, format, downscaled, .. } = rendered;
+
+    let name = output_name(
+        &cached.source_name,
+        &prefs,
+        cached.collage,
+        cached.spoiler,
+        format.extension(),
+    );
+    let count = images.len();
+    let mut edit =
+        EditInteractionResponse::new().components(render_buttons(id));
+    for (i, image) in images.into_iter().enumerate() {
+        edit = edit.new_attachment(CreateAttachment::bytes(
+            image,
+            tile_name(&name, i, count),
+        ));
+    }
+    if count > 1 {
+        edit = edit.content(TILED_NOTE);
+    } else if downscaled {
+        edit = edit.content(DOWNSCALED_NOTE);
+    } else if queued {
+        edit = edit.content("");
+    }
+
+    data.render_cache.update(
+        id,
+        CachedRender {
+            image_bytes: cached.image_bytes.clone(),
+            prefs,
+            source_name: cached.source_name.clone(),
+            spoiler: cached.spoiler,
+            collage: cached.collage,
+            assets: cached.assets.clone(),
+            ascii_art,
+            owner: cached.owner,
+        },
+    );
+    Ok(edit)
+}
+
+/// Reply only the user sees, in their language when there's a translation
+fn ephemeral_message(locale: &str, content: &str) -> CreateInteractionResponse {
+    CreateInteractionResponse::Message(
+        CreateInteractionResponseMessage::new()
+            .content(translate(Some(locale), content))
+            .ephemeral(true),
+    )
+}
+
+fn expired(locale: &str) -> CreateInteractionResponse {
+    ephemeral_message(locale, "This render expired, run the command again")
+}
+
+/// Remove every ANSI escape sequence, leaving only the characters. Line
+/// by line, so a broken sequence can't swallow the next lines
+fn strip_ansi(ascii_art: &str) -> String {
+    ascii_art.lines().map(ansi::strip_escapes).collect::<Vec<_>>().join("\n")
+}
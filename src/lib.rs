@@ -0,0 +1,23 @@
+//! Rendering engine of the bot, free of any Discord code: images to
+//! colored ASCII art, and ANSI colored text back to images
+
+pub mod ans_file;
+pub mod ansi;
+pub mod blocks;
+pub mod color_filter;
+pub mod effects;
+mod glyph_atlas;
+mod glyph_density;
+mod glyph_shapes;
+pub mod image_stats;
+pub mod image_to_ascii;
+pub mod palette;
+pub mod png_metadata;
+
+pub use image_to_ascii::{
+    AsciiRenderer, AsciiRendererBuilder, BundledFont, Gradient,
+    GradientDirection,
+};
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+pub type Res<T> = Result<T, Error>;
@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use poise::serenity_prelude::{Attachment, Message};
+use reqwest::Url;
+
+use crate::Res;
+
+/// Largest page or GIF downloaded from a link
+const MAX_DOWNLOAD: usize = 25 * 1024 * 1024;
+/// Sites whose links are pages around a GIF rather than the GIF itself
+const GIF_SITES: [&str; 2] = ["tenor.com", "giphy.com"];
+
+/// The image of a message: an attached one, or a Tenor or Giphy link
+pub enum MessageImage<'a> {
+    Attachment(&'a Attachment),
+    Gif(Url),
+}
+
+/// The first image attached to `message` or, without one, the first GIF
+/// link in its text
+pub fn message_image(message: &Message) -> Option<MessageImage<'_>> {
+    let attached = message.attachments.iter().find(|file| {
+        file.content_type.as_deref().is_some_and(|t| t.starts_with("image/"))
+    });
+    if let Some(file) = attached {
+        return Some(MessageImage::Attachment(file));
+    }
+
+    message
+        .content
+        .split_whitespace()
+        // Links wrapped in <> have their embed suppressed
+        .map(|word| word.trim_start_matches('<').trim_end_matches('>'))
+        .filter_map(|word| Url::parse(word).ok())
+        .find(is_gif_site)
+        .map(MessageImage::Gif)
+}
+
+fn is_gif_site(url: &Url) -> bool {
+    url.host_str().is_some_and(|host| {
+        GIF_SITES
+            .iter()
+            .any(|site| host == *site || host.ends_with(&format!(".{site}")))
+    })
+}
+
+impl MessageImage<'_> {
+    /// File name the outputs are named after
+    pub fn name(&self) -> String {
+        match self {
+            MessageImage::Attachment(file) => file.filename.clone(),
+            MessageImage::Gif(url) => {
+                let slug = url
+                    .path_segments()
+                    .and_then(|mut segments| segments.rfind(|s| !s.is_empty()))
+                    .unwrap_or("gif");
+                let stem = slug.rsplit_once('.').map_or(slug, |(stem, _)| stem);
+                format!("{stem}.gif")
+            }
+        }
+    }
+
+    pub async fn download(&self) -> Res<Vec<u8>> {
+        match self {
+            MessageImage::Attachment(file) => Ok(file.download().await?),
+            MessageImage::Gif(url) => {
+                let client = reqwest::Client::builder()
+                    .connect_timeout(Duration::from_secs(10))
+                    .read_timeout(Duration::from_secs(30))
+                    .build()?;
+                let gif = resolve_gif(&client, url).await?;
+                fetch(&client, gif).await
+            }
+        }
+    }
+}
+
+/// The GIF file behind a Tenor or Giphy link, read from the `og:image`
+/// tag of its page. Links to the media servers are the file already
+async fn resolve_gif(client: &reqwest::Client, url: &Url) -> Res<Url> {
+    if url.path().ends_with(".gif") {
+        return Ok(url.clone());
+    }
+
+    let page = String::from_utf8_lossy(&fetch(client, url.clone()).await?)
+        .into_owned();
+    let gif = og_image(&page).ok_or("Couldn't find the GIF of that link")?;
+    let gif = Url::parse(&gif)?;
+    if !is_gif_site(&gif) {
+        return Err("Couldn't find the GIF of that link".into());
+    }
+    Ok(gif)
+}
+
+async fn fetch(client: &reqwest::Client, url: Url) -> Res<Vec<u8>> {
+    let mut response = client.get(url).send().await?.error_for_status()?;
+    if response.content_length().is_some_and(|len| len > MAX_DOWNLOAD as u64) {
+        return Err("The linked file is too large".into());
+    }
+
+    // Servers may leave the length out, so it's checked while reading too
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        bytes.extend_from_slice(&chunk);
+        if bytes.len() > MAX_DOWNLOAD {
+            return Err("The linked file is too large".into());
+        }
+    }
+    Ok(bytes)
+}
+
+/// `content` of the `og:image` meta tag of an HTML page
+fn og_image(html: &str) -> Option<String> {
+    html.split("<meta").skip(1).find_map(|tag| {
+        let tag = &tag[..tag.find('>')?];
+        let is_image = ["property", "name"].iter().any(|key| {
+            attribute(tag, key).is_some_and(|value| value == "og:image")
+        });
+        is_image
+            .then(|| attribute(tag, "content"))
+            .flatten()
+            .map(|url| url.replace("&amp;", "&"))
+    })
+}
+
+/// Value of the double quoted `key` attribute of an HTML tag
+fn attribute<'a>(tag: &'a str, key: &str) -> Option<&'a str> {
+    let start = tag.find(&format!("{key}=\""))? + key.len() + 2;
+    let len = tag[start..].find('"')?;
+    Some(&tag[start..start + len])
+}
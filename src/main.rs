@@ -1,48 +1,262 @@
+use std::{sync::Arc, time::Duration};
+
+use tracing::{error, info, warn};
+use tracing_subscriber::{EnvFilter, fmt::format::FmtSpan};
+
 use poise::{
-    Framework, FrameworkError, FrameworkOptions,
-    samples::register_globally,
-    serenity_prelude::{ClientBuilder, GatewayIntents},
+    CreateReply, Framework, FrameworkContext, FrameworkError, FrameworkOptions,
+    samples::{register_globally, register_in_guild},
+    serenity_prelude::{
+        ChannelId, ClientBuilder, Context as SerenityContext, FullEvent,
+        GatewayIntents, GuildId, Interaction, ShardManager, UserId,
+    },
 };
 
-use crate::commands::{attachment_to_ascii, avatar_to_ascii, image_to_ascii};
+// The engine modules are re-imported at the root, so the bot modules reach
+// them through `crate::` like their own
+use ascii_bot::{
+    Error, Res, ans_file, ansi, blocks, color_filter, effects, image_stats,
+    image_to_ascii, palette, png_metadata,
+};
 
-struct Data;
-type Error = Box<dyn std::error::Error + Send + Sync>;
-type Context<'a> = poise::Context<'a, Data, Error>;
+use crate::{
+    auto::Cooldowns,
+    commands::{
+        admin, apply_cooldowns, ascii_to_image, attachment_to_ascii,
+        avatar_to_ascii, help, image_to_ascii, paste_ansi, restore_original,
+        same_as_last, same_as_render, server_settings, settings, stats,
+        watch_channel, webhook_output,
+    },
+    config::{Args, Config, config, discord_token},
+    help::{HelpExample, render_examples},
+    http::{Health, RenderApi},
+    i18n::{localize_commands, translate},
+    interactions::{RenderCache, handle_component, handle_modal},
+    moderation::{REFUSED, Refused},
+    queue::RenderQueue,
+    quota::QuotaExceeded,
+    report::report_error,
+    result_cache::ResultCache,
+    shutdown::{RESTARTING, Shutdown},
+    stats::Stats,
+    storage::Storage,
+};
+
+/// Log levels used when `RUST_LOG` isn't set
+const DEFAULT_LOG_FILTER: &str = "warn,ascii_bot=info";
+/// How often each shard's heartbeat latency is logged
+const LATENCY_LOG_INTERVAL: Duration = Duration::from_secs(300);
 
-type Res<T> = Result<T, Error>;
+struct Data {
+    storage: Arc<Storage>,
+    shutdown: Arc<Shutdown>,
+    stats: Arc<Stats>,
+    help_examples: Vec<HelpExample>,
+    render_cache: RenderCache,
+    /// Shared with the HTTP render API
+    queue: Arc<RenderQueue>,
+    result_cache: ResultCache,
+    channel_cooldowns: Cooldowns<ChannelId>,
+    reaction_cooldowns: Cooldowns<UserId>,
+}
+type Context<'a> = poise::Context<'a, Data, Error>;
+type ApplicationContext<'a> = poise::ApplicationContext<'a, Data, Error>;
 
+mod auto;
 mod commands;
-mod image_to_ascii;
+mod config;
+mod help;
+mod http;
+mod i18n;
+mod interactions;
+mod links;
 mod macros;
+mod metadata;
+mod moderation;
+mod options;
+mod queue;
+mod quota;
+mod render;
+mod report;
+mod result_cache;
+mod shutdown;
+mod stats;
+mod storage;
+mod tiers;
 
 #[tokio::main]
 async fn main() -> Res<()> {
-    let intents = GatewayIntents::non_privileged();
+    let args = Args::parse()?;
+    Config::load()?;
+    init_logging();
+    let mut intents = GatewayIntents::non_privileged();
+    if config().watch_channels || config().reaction_trigger.is_some() {
+        // Attachments of messages not mentioning the bot come with it
+        intents |= GatewayIntents::MESSAGE_CONTENT;
+    }
+
+    let token = discord_token(&args)?;
+    let dev_guild = args.dev_guild.or(config().dev_guild).map(GuildId::from);
+
+    // Installed first, so no metric recorded at startup is lost
+    let metrics =
+        config().http_listen.map(|_| http::install_metrics()).transpose()?;
+
+    let storage = Arc::new(Storage::open(&config().database).await?);
+    let shutdown = Arc::new(Shutdown::default());
+    let stats = Arc::new(Stats::new(storage.stat_totals().await?));
+    let queue = Arc::new(RenderQueue::new(
+        config().render_workers,
+        config().queue_size,
+    ));
+
+    let mut client = ClientBuilder::new(token, intents)
+        .framework(framework(
+            storage.clone(),
+            shutdown.clone(),
+            stats.clone(),
+            queue.clone(),
+            dev_guild,
+        ))
+        .await?;
+
+    if let (Some(addr), Some(metrics)) = (config().http_listen, metrics) {
+        let health = Health {
+            shard_manager: client.shard_manager.clone(),
+            stats: stats.clone(),
+        };
+        let api = config().render_api.then(|| RenderApi {
+            queue,
+            shutdown: shutdown.clone(),
+            stats: stats.clone(),
+        });
+        tokio::spawn(async move {
+            if let Err(e) = http::serve(addr, metrics, health, api).await {
+                error!("The HTTP listener stopped: {e}");
+            }
+        });
+    }
 
-    // Token file is generated through
-    // ./xor_token.sh "MTQTHISIS.ANEXAMPLE.TOKEN"
-    let token = String::from_utf8(
-        include_bytes!("../.token.xor")
-            .iter()
-            .map(|b| b ^ 66)
-            .collect::<Vec<_>>(),
-    )?;
+    let shard_manager = client.shard_manager.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            result = shutdown::signal() => {
+                if let Err(e) = result {
+                    error!("Couldn't listen for shutdown signals: {e}");
+                    return;
+                }
+            }
+            () = shutdown.requested() => {}
+        }
 
-    let mut client =
-        ClientBuilder::new(token, intents).framework(framework()).await?;
+        info!("Shutting down, waiting for running renders");
+        let timeout = Duration::from_secs(config().shutdown_timeout);
+        if !shutdown.close(timeout).await {
+            warn!("Renders still running after {timeout:?}, stopping");
+        }
+        if let Err(e) = storage.save_stat_totals(stats.totals()).await {
+            error!("Couldn't save the stats: {e}");
+        }
+        storage.close().await;
+        shard_manager.shutdown_all().await;
+    });
 
-    client.start().await?;
+    tokio::spawn(log_latencies(client.shard_manager.clone()));
+
+    // Discord's recommended shard count, so the bot keeps working as it
+    // joins more guilds
+    client.start_autosharded().await?;
+    info!("Disconnected");
     Ok(())
 }
 
+/// Log to stdout, filtered by `RUST_LOG`, as JSON lines when the config
+/// asks for it. Spans log their duration when they close
+fn init_logging() {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_FILTER));
+    let logs = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE);
+
+    if config().json_logs {
+        logs.json().init();
+    } else {
+        logs.init();
+    }
+}
+
+/// Log the heartbeat latency of every shard, every few minutes
+async fn log_latencies(shard_manager: Arc<ShardManager>) {
+    let mut interval = tokio::time::interval(LATENCY_LOG_INTERVAL);
+    // The first tick is immediate, before any shard connected
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        for (id, runner) in shard_manager.runners.lock().await.iter() {
+            match runner.latency {
+                Some(latency) => info!(shard = id.0, ?latency, "Heartbeat"),
+                None => {
+                    info!(shard = id.0, stage = %runner.stage, "No heartbeat")
+                }
+            }
+        }
+    }
+}
+
 async fn on_error(error: FrameworkError<'_, Data, Error>) {
-    if let FrameworkError::Command { error, ctx, .. } = error {
+    stats::record_error(error_kind(&error));
+    report_error(&error).await;
+    if let FrameworkError::CooldownHit { remaining_cooldown, ctx, .. } = error {
+        let secs = remaining_cooldown.as_secs().max(1).to_string();
         ctx.send(embed!(
-            title: format!("Error in command `/{}`", ctx.command().name),
+            title: translate(ctx.locale(), "Slow down!"),
+            description: translate(ctx.locale(), "Try again in {secs}s")
+                .replace("{secs}", &secs),
+            ephemeral: true,
+        ))
+        .await
+        .ok();
+    } else if let FrameworkError::Command { error, ctx, .. } = &error
+        && let Some(exceeded) = error.downcast_ref::<QuotaExceeded>()
+    {
+        ctx.send(embed!(
+            title: translate(ctx.locale(), "Quota exceeded"),
+            description: exceeded.to_string(),
+            ephemeral: true,
+        ))
+        .await
+        .ok();
+    } else if let FrameworkError::CommandCheckFailed {
+        error: Some(error),
+        ctx,
+        ..
+    } = &error
+        && error.is::<Refused>()
+    {
+        // A plain notice, refused users don't need the details
+        ctx.send(
+            CreateReply::default()
+                .content(translate(ctx.locale(), REFUSED))
+                .ephemeral(true),
+        )
+        .await
+        .ok();
+    } else if let FrameworkError::Command { error, ctx, .. }
+    | FrameworkError::CommandCheckFailed {
+        error: Some(error),
+        ctx,
+        ..
+    } = error
+    {
+        warn!(command = %ctx.command().name, "{error}");
+        let message = error.to_string();
+        ctx.send(embed!(
+            title: translate(ctx.locale(), "Error in command `/{command}`")
+                .replace("{command}", &ctx.command().name),
             description: format!(
                 "```diff\n- {}```",
-                error.to_string().replace('\n', "\n- ").trim()
+                translate(ctx.locale(), &message).replace('\n', "\n- ").trim()
             ),
             ephemeral: true,
             mentions: None,
@@ -55,24 +269,150 @@ async fn on_error(error: FrameworkError<'_, Data, Error>) {
     }
 }
 
-fn framework() -> Framework<Data, Error> {
+/// Label of the error counter for `error`
+fn error_kind(error: &FrameworkError<'_, Data, Error>) -> &'static str {
+    match error {
+        FrameworkError::Command { .. } => "command",
+        FrameworkError::CooldownHit { .. } => "cooldown",
+        FrameworkError::CommandCheckFailed { .. } => "check",
+        FrameworkError::ArgumentParse { .. } => "argument",
+        FrameworkError::MissingBotPermissions { .. }
+        | FrameworkError::MissingUserPermissions { .. } => "permissions",
+        FrameworkError::EventHandler { .. } => "event_handler",
+        _ => "other",
+    }
+}
+
+async fn event_handler(
+    ctx: &SerenityContext,
+    event: &FullEvent,
+    _framework: FrameworkContext<'_, Data, Error>,
+    data: &Data,
+) -> Res<()> {
+    if let FullEvent::Ready { data_about_bot } = event
+        && let Some(shard) = data_about_bot.shard
+    {
+        info!(shard = shard.id.0, total = shard.total, "Shard ready");
+    }
+
+    if let FullEvent::Message { new_message } = event {
+        auto::on_message(ctx, data, new_message).await?;
+    }
+    if let FullEvent::ReactionAdd { add_reaction } = event {
+        auto::on_reaction(ctx, data, add_reaction).await?;
+    }
+
+    if let FullEvent::InteractionCreate { interaction } = event {
+        match interaction {
+            Interaction::Component(component) => {
+                handle_component(ctx, component, data).await?;
+            }
+            Interaction::Modal(modal) => {
+                handle_modal(ctx, modal, data).await?;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Run before every command: nothing new starts once the bot is shutting
+/// down, admin commands are for the owners, and everyone else goes through
+/// the moderation checks
+async fn command_check(ctx: Context<'_>) -> Res<bool> {
+    if ctx.data().shutdown.is_closing() {
+        return Err(RESTARTING.into());
+    }
+
+    let owner = ctx.framework().options().owners.contains(&ctx.author().id);
+    let admin = ctx
+        .parent_commands()
+        .iter()
+        .chain([ctx.command()])
+        .any(|command| command.category.as_deref() == Some("Admin"));
+    if admin {
+        return Ok(owner);
+    }
+
+    if !owner {
+        let storage = &ctx.data().storage;
+        moderation::check(storage, ctx.author().id, ctx.guild_id()).await?;
+    }
+    Ok(true)
+}
+
+fn framework(
+    storage: Arc<Storage>,
+    shutdown: Arc<Shutdown>,
+    stats: Arc<Stats>,
+    queue: Arc<RenderQueue>,
+    dev_guild: Option<GuildId>,
+) -> Framework<Data, Error> {
+    let mut commands = vec![
+        image_to_ascii(),
+        same_as_last(),
+        same_as_render(),
+        attachment_to_ascii(),
+        avatar_to_ascii(),
+        restore_original(),
+        ascii_to_image(),
+        paste_ansi(),
+        settings(),
+        server_settings(),
+        webhook_output(),
+        watch_channel(),
+        help(),
+        stats(),
+        admin(),
+    ];
+
+    apply_cooldowns(&commands);
+    localize_commands(&mut commands);
+
     let options = FrameworkOptions {
-        commands: vec![
-            image_to_ascii(),
-            attachment_to_ascii(),
-            avatar_to_ascii(),
-        ],
+        commands,
         on_error: |e| Box::pin(on_error(e)),
+        pre_command: |ctx| {
+            Box::pin(async move {
+                stats::record_command(&ctx.command().qualified_name);
+            })
+        },
+        command_check: Some(|ctx| Box::pin(command_check(ctx))),
+        event_handler: |ctx, event, framework, data| {
+            Box::pin(event_handler(ctx, event, framework, data))
+        },
         ..Default::default()
     };
 
     Framework::builder()
         .options(options)
-        .setup(|ctx, ready, framework| {
+        .setup(move |ctx, ready, framework| {
             Box::pin(async move {
-                println!("{} is on!", ready.user.name);
-                register_globally(ctx, &framework.options().commands).await?;
-                Ok(Data)
+                info!("{} is on!", ready.user.name);
+                let commands = &framework.options().commands;
+                match dev_guild {
+                    Some(guild) => {
+                        register_in_guild(ctx, commands, guild).await?;
+                        info!(%guild, "Registered the commands in the guild");
+                    }
+                    None => register_globally(ctx, commands).await?,
+                }
+                Ok(Data {
+                    storage,
+                    shutdown,
+                    help_examples: render_examples()?,
+                    stats,
+                    render_cache: RenderCache::new(config().render_cache_size),
+                    queue,
+                    result_cache: ResultCache::new(
+                        config().result_cache_size,
+                        config().result_cache_dir.clone(),
+                        config().result_cache_disk_size,
+                    )?,
+                    channel_cooldowns: Cooldowns::new(),
+                    reaction_cooldowns: Cooldowns::new(),
+                })
             })
         })
         .build()
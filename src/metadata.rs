@@ -0,0 +1,50 @@
+use crate::{Res, options::Preferences, png_metadata};
+
+/// PNG text keyword holding every option of a render, as JSON. The other
+/// keywords are for people looking at the file, only this one is read back
+const OPTIONS_KEYWORD: &str = "ASCII-Bot options";
+
+/// Private PNG chunk the original image of a render is kept in
+const ORIGINAL_CHUNK: [u8; 4] = *b"orGn";
+
+/// `png` with the options it was rendered with written into text chunks
+pub fn embed_options(png: &[u8], prefs: &Preferences) -> Res<Vec<u8>> {
+    let version = env!("CARGO_PKG_VERSION");
+    let brightness = (prefs.background_brightness() * 100.0).round();
+    let entries = [
+        ("Software", format!("ASCII-Bot {version}")),
+        ("ASCII-Bot style", prefs.style().name().to_string()),
+        ("ASCII-Bot charset", prefs.charset().to_string()),
+        ("ASCII-Bot size", prefs.size().to_string()),
+        ("ASCII-Bot background brightness", format!("{brightness}%")),
+        (OPTIONS_KEYWORD, serde_json::to_string(prefs)?),
+    ];
+    png_metadata::with_text(png, &entries)
+}
+
+/// Options a render of the bot was made with, read back from its PNG.
+/// They come from a file anyone could have edited, so they're validated
+/// like typed ones
+pub fn embedded_options(bytes: &[u8]) -> Res<Option<Preferences>> {
+    let Some(json) = png_metadata::read_text(bytes).and_then(|entries| {
+        entries.into_iter().find_map(|(keyword, text)| {
+            (keyword == OPTIONS_KEYWORD).then_some(text)
+        })
+    }) else {
+        return Ok(None);
+    };
+
+    let prefs: Preferences = serde_json::from_str(&json)
+        .map_err(|_| "The options in that render couldn't be read")?;
+    Ok(Some(prefs.validated()?))
+}
+
+/// `png` with `original`, the image it was converted from, embedded
+pub fn embed_original(png: &[u8], original: &[u8]) -> Res<Vec<u8>> {
+    png_metadata::with_chunk(png, ORIGINAL_CHUNK, original)
+}
+
+/// The original image embedded in a render of the bot, if it kept one
+pub fn embedded_original(png: &[u8]) -> Option<&[u8]> {
+    png_metadata::read_chunk(png, ORIGINAL_CHUNK)
+}
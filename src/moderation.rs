@@ -0,0 +1,43 @@
+use std::fmt;
+
+use poise::serenity_prelude::{GuildId, UserId};
+
+use crate::{
+    Res,
+    config::config,
+    storage::{List, Storage},
+};
+
+/// Notice shown to refused users, vague on purpose
+pub const REFUSED: &str = "You can't use this bot here";
+
+/// A user or server turned away by the blacklist or the allowlist. Gets a
+/// quiet notice instead of an error report
+#[derive(Debug)]
+pub struct Refused;
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(REFUSED)
+    }
+}
+
+impl std::error::Error for Refused {}
+
+/// Refuse `user` in `guild` when either is blacklisted, or when the config
+/// only serves the allowlist and neither is on it
+pub async fn check(
+    storage: &Storage,
+    user: UserId,
+    guild: Option<GuildId>,
+) -> Res<()> {
+    if storage.is_listed(List::Blacklist, user, guild).await? {
+        return Err(Refused.into());
+    }
+    if config().allowlist_only
+        && !storage.is_listed(List::Allowlist, user, guild).await?
+    {
+        return Err(Refused.into());
+    }
+    Ok(())
+}
@@ -0,0 +1,1068 @@
+use std::{collections::HashSet, sync::Arc};
+
+use ab_glyph::FontArc;
+use image::RgbaImage;
+use poise::{
+    ChoiceParameter,
+    serenity_prelude::{ChannelId, WebhookId},
+};
+use serde::{Deserialize, Serialize};
+use unicode_width::UnicodeWidthChar;
+
+use crate::{
+    Res,
+    blocks::BlockMode,
+    color_filter::ColorFilter,
+    config::config,
+    image_to_ascii::{
+        self, BundledFont, FONT_SIZE, Gradient, MAX_COLUMNS, MAX_FONT_SIZE,
+        MAX_GAMMA, MAX_POSTERIZE, MAX_SHARPEN, MAX_SPACING, MAX_STRETCH,
+        MIN_FONT_SIZE, MIN_GAMMA, MIN_STRETCH, SHAPES_CHARSET,
+        TYPEWRITER_FRAMES,
+    },
+};
+
+pub const DEFAULT_SIZE: u32 = 150;
+pub const DEFAULT_BACKGROUND_BRIGHTNESS: u32 = 20;
+pub const MAX_CHARSET_LEN: usize = 20;
+pub const MAX_CAPTION_LEN: usize = 100;
+/// Most colors of a custom palette, every cell is compared with each
+pub const MAX_PALETTE_LEN: usize = 32;
+/// Charset that picks one from the look of each image
+pub const AUTO_CHARSET: &str = "auto";
+/// Background brightness picked from the look of each image
+pub const AUTO_BACKGROUND_BRIGHTNESS: &str = "auto";
+/// Shadow and highlight colors of the duotone filter when none are picked
+pub const DEFAULT_DUOTONE: [[u8; 3]; 2] = [[32, 24, 72], [255, 208, 128]];
+
+#[derive(ChoiceParameter, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum Style {
+    #[default]
+    #[name = "Mixed"]
+    Mixed,
+    #[name = "Foreground paint"]
+    FgPaint,
+    #[name = "Background paint"]
+    BgPaint,
+    #[name = "Background only"]
+    BgOnly,
+}
+
+impl Style {
+    /// The following style, wrapping around, for the "Different style" button
+    pub fn next(self) -> Self {
+        match self {
+            Style::Mixed => Style::FgPaint,
+            Style::FgPaint => Style::BgPaint,
+            Style::BgPaint => Style::BgOnly,
+            Style::BgOnly => Style::Mixed,
+        }
+    }
+}
+
+impl From<Style> for libasciic::Style {
+    fn from(style: Style) -> Self {
+        match style {
+            Style::Mixed => libasciic::Style::Mixed,
+            Style::FgPaint => libasciic::Style::FgPaint,
+            Style::BgPaint => libasciic::Style::BgPaint,
+            Style::BgOnly => libasciic::Style::BgOnly,
+        }
+    }
+}
+
+#[derive(ChoiceParameter, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum FontChoice {
+    #[default]
+    #[name = "Roboto Mono"]
+    RobotoMono,
+    #[name = "DejaVu Sans Mono"]
+    DejaVuSansMono,
+}
+
+impl From<FontChoice> for BundledFont {
+    fn from(font: FontChoice) -> Self {
+        match font {
+            FontChoice::RobotoMono => BundledFont::RobotoMono,
+            FontChoice::DejaVuSansMono => BundledFont::DejaVuSansMono,
+        }
+    }
+}
+
+/// What the art is drawn with
+#[derive(ChoiceParameter, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum Mode {
+    #[default]
+    #[name = "Charset"]
+    Charset,
+    #[name = "Half blocks (double vertical resolution)"]
+    HalfBlocks,
+    #[name = "Quadrant blocks (2x2 pixels per character)"]
+    Quadrants,
+    #[name = "Edges (line art, for logos and drawings)"]
+    Edges,
+    #[name = "Shaded edges (shading with outlines, for photos)"]
+    ShadedEdges,
+    #[name = "Shapes (best matching glyph, slower)"]
+    Shapes,
+}
+
+impl Mode {
+    /// Block characters drawn by the mode, `None` uses the charset
+    pub fn block_mode(self) -> Option<BlockMode> {
+        match self {
+            Mode::Charset | Mode::Edges | Mode::ShadedEdges | Mode::Shapes => {
+                None
+            }
+            Mode::HalfBlocks => Some(BlockMode::Half),
+            Mode::Quadrants => Some(BlockMode::Quadrant),
+        }
+    }
+}
+
+/// How the art is sized within `max_size` and `max_height`
+#[derive(ChoiceParameter, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum Fit {
+    #[default]
+    #[name = "Contain (keep proportions)"]
+    Contain,
+    #[name = "Exact (pad to the exact size)"]
+    Exact,
+}
+
+/// Corner a thumbnail of the source image is inset into
+#[derive(ChoiceParameter, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum Thumbnail {
+    #[default]
+    #[name = "Off"]
+    Off,
+    #[name = "Top left"]
+    TopLeft,
+    #[name = "Top right"]
+    TopRight,
+    #[name = "Bottom left"]
+    BottomLeft,
+    #[name = "Bottom right"]
+    BottomRight,
+}
+
+/// Which way the background gradient runs
+#[derive(ChoiceParameter, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum GradientDirection {
+    #[default]
+    #[name = "Top to bottom"]
+    Vertical,
+    #[name = "Left to right"]
+    Horizontal,
+    #[name = "Diagonal"]
+    Diagonal,
+}
+
+impl From<GradientDirection> for image_to_ascii::GradientDirection {
+    fn from(direction: GradientDirection) -> Self {
+        match direction {
+            GradientDirection::Vertical => Self::Vertical,
+            GradientDirection::Horizontal => Self::Horizontal,
+            GradientDirection::Diagonal => Self::Diagonal,
+        }
+    }
+}
+
+/// Rotation or mirroring applied to the input before converting it
+#[derive(ChoiceParameter, Clone, Copy, Serialize, Deserialize)]
+pub enum Orientation {
+    #[name = "Rotate 90° clockwise"]
+    Rotate90,
+    #[name = "Rotate 180°"]
+    Rotate180,
+    #[name = "Rotate 90° counterclockwise"]
+    Rotate270,
+    #[name = "Flip horizontally"]
+    FlipHorizontal,
+    #[name = "Flip vertically"]
+    FlipVertical,
+}
+
+impl Orientation {
+    /// Clockwise quarter turns
+    pub fn quarter_turns(self) -> u32 {
+        match self {
+            Orientation::Rotate90 => 1,
+            Orientation::Rotate180 => 2,
+            Orientation::Rotate270 => 3,
+            Orientation::FlipHorizontal | Orientation::FlipVertical => 0,
+        }
+    }
+
+    /// Horizontal and vertical mirroring
+    pub fn flips(self) -> (bool, bool) {
+        match self {
+            Orientation::FlipHorizontal => (true, false),
+            Orientation::FlipVertical => (false, true),
+            _ => (false, false),
+        }
+    }
+}
+
+/// Text files attached along with the image
+#[derive(ChoiceParameter, Clone, Copy)]
+pub enum TextFile {
+    #[name = "ANSI (.ans)"]
+    Ansi,
+    #[name = "Web page (.html)"]
+    Html,
+    #[name = "Both"]
+    Both,
+}
+
+impl TextFile {
+    pub fn ansi(self) -> bool {
+        matches!(self, TextFile::Ansi | TextFile::Both)
+    }
+
+    pub fn html(self) -> bool {
+        matches!(self, TextFile::Html | TextFile::Both)
+    }
+}
+
+/// Who gets to see a render
+#[derive(ChoiceParameter, Clone, Copy)]
+pub enum Visibility {
+    #[name = "Public"]
+    Public,
+    #[name = "Spoiler"]
+    Spoiler,
+    #[name = "Only me"]
+    OnlyMe,
+}
+
+/// Post-processing applied to the finished image
+#[derive(ChoiceParameter, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum Effect {
+    #[default]
+    #[name = "None"]
+    None,
+    #[name = "CRT"]
+    Crt,
+    /// Animated GIF of the art being typed in
+    #[name = "Typewriter (GIF)"]
+    Typewriter,
+    /// Animated GIF fading between the input and the art
+    #[name = "Before and after (GIF)"]
+    BeforeAfter,
+    /// Animated GIF of the art falling into place, column by column
+    #[name = "Rain (GIF)"]
+    Rain,
+}
+
+/// Color filter the art is drawn through
+#[derive(ChoiceParameter, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum Filter {
+    #[default]
+    #[name = "None"]
+    None,
+    #[name = "Sepia"]
+    Sepia,
+    /// Shadows and highlights get the two `duotone` colors
+    #[name = "Duotone"]
+    Duotone,
+    #[name = "Protanopia (red-blind)"]
+    Protanopia,
+    #[name = "Deuteranopia (green-blind)"]
+    Deuteranopia,
+    #[name = "Tritanopia (blue-blind)"]
+    Tritanopia,
+}
+
+/// Encoding of the rendered image
+#[derive(ChoiceParameter, Clone, Copy, Serialize, Deserialize)]
+pub enum OutputFormat {
+    #[name = "PNG"]
+    Png,
+    #[name = "JPEG"]
+    Jpeg,
+    #[name = "WebP"]
+    WebP,
+    #[name = "SVG (vector)"]
+    Svg,
+    #[name = "GIF"]
+    Gif,
+}
+
+impl OutputFormat {
+    /// Photos compress badly as PNG, so they default to WebP
+    pub fn for_input(image_bytes: &[u8]) -> Self {
+        match image::guess_format(image_bytes) {
+            Ok(image::ImageFormat::Jpeg | image::ImageFormat::WebP) => {
+                OutputFormat::WebP
+            }
+            _ => OutputFormat::Png,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Svg => "svg",
+            OutputFormat::Gif => "gif",
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::Svg => "image/svg+xml",
+            OutputFormat::Gif => "image/gif",
+        }
+    }
+
+    /// Encoder of raster formats, `None` for vector ones
+    pub fn image_format(self) -> Option<image::ImageFormat> {
+        match self {
+            OutputFormat::Png => Some(image::ImageFormat::Png),
+            OutputFormat::Jpeg => Some(image::ImageFormat::Jpeg),
+            OutputFormat::WebP => Some(image::ImageFormat::WebP),
+            OutputFormat::Svg => None,
+            OutputFormat::Gif => Some(image::ImageFormat::Gif),
+        }
+    }
+}
+
+/// How much of each cell's color bleeds into its background
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(from = "SavedBrightness", into = "SavedBrightness")]
+pub enum BackgroundBrightness {
+    /// From 0 to 100
+    Percent(u32),
+    /// Picked from the luminance of each image
+    Auto,
+}
+
+/// Saved form of [`BackgroundBrightness`]: settings saved before `auto`
+/// hold a bare number
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum SavedBrightness {
+    Percent(u32),
+    Keyword(String),
+}
+
+impl From<SavedBrightness> for BackgroundBrightness {
+    fn from(saved: SavedBrightness) -> Self {
+        match saved {
+            SavedBrightness::Percent(percent) => Self::Percent(percent),
+            SavedBrightness::Keyword(_) => Self::Auto,
+        }
+    }
+}
+
+impl From<BackgroundBrightness> for SavedBrightness {
+    fn from(brightness: BackgroundBrightness) -> Self {
+        match brightness {
+            BackgroundBrightness::Percent(percent) => Self::Percent(percent),
+            BackgroundBrightness::Auto => {
+                Self::Keyword(AUTO_BACKGROUND_BRIGHTNESS.to_string())
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for BackgroundBrightness {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BackgroundBrightness::Percent(percent) => write!(f, "{percent}%"),
+            BackgroundBrightness::Auto => {
+                f.write_str(AUTO_BACKGROUND_BRIGHTNESS)
+            }
+        }
+    }
+}
+
+/// A named look offered by the select menu under every render
+pub struct Preset {
+    pub name: &'static str,
+    pub style: Style,
+    /// Keeps the current charset when unset
+    pub charset: Option<&'static str>,
+}
+
+pub const PRESETS: &[Preset] = &[
+    Preset { name: "Mixed", style: Style::Mixed, charset: None },
+    Preset { name: "Foreground paint", style: Style::FgPaint, charset: None },
+    Preset { name: "Background paint", style: Style::BgPaint, charset: None },
+    Preset { name: "Background only", style: Style::BgOnly, charset: None },
+    Preset {
+        name: "Blocks", style: Style::FgPaint, charset: Some(" ░▒▓█")
+    },
+    Preset { name: "Dense", style: Style::Mixed, charset: Some(" .:-=+*#%@") },
+    Preset { name: "Binary", style: Style::FgPaint, charset: Some("01") },
+];
+
+impl Preset {
+    pub fn apply(&self, prefs: &mut Preferences) {
+        prefs.style = Some(self.style);
+        if let Some(charset) = self.charset {
+            // Already in order, measuring could shuffle glyphs inked alike
+            prefs.charset = Some(charset.to_string());
+            prefs.sort_charset = Some(false);
+        }
+    }
+}
+
+/// Options that can be omitted and filled in from saved defaults
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Preferences {
+    pub charset: Option<String>,
+    /// Order the charset by how much ink its glyphs use rather than as
+    /// typed, see [`Preferences::sort_charset`]
+    #[serde(default)]
+    pub sort_charset: Option<bool>,
+    pub size: Option<u32>,
+    pub style: Option<Style>,
+    pub background_brightness: Option<BackgroundBrightness>,
+    #[serde(default)]
+    pub gamma: Option<f32>,
+    #[serde(default)]
+    pub threshold: Option<u8>,
+    /// Levels each color channel is reduced to, 0 turns it off
+    #[serde(default)]
+    pub posterize: Option<u32>,
+    /// Median filter the input, for noisy photos
+    #[serde(default)]
+    pub denoise: Option<bool>,
+    /// Unsharp mask strength in percent, 0 turns it off
+    #[serde(default)]
+    pub sharpen: Option<u32>,
+    /// Embed the input into PNG renders, for "Restore original"
+    #[serde(default)]
+    pub keep_original: Option<bool>,
+    #[serde(default)]
+    pub palette: Option<Vec<[u8; 3]>>,
+    #[serde(default)]
+    pub filter: Option<Filter>,
+    /// Shadow and highlight colors of the duotone filter
+    #[serde(default)]
+    pub duotone: Option<[[u8; 3]; 2]>,
+    #[serde(default)]
+    pub transparent: Option<bool>,
+    #[serde(default)]
+    pub font: Option<FontChoice>,
+    #[serde(default)]
+    pub font_size: Option<u32>,
+    #[serde(default)]
+    pub effect: Option<Effect>,
+    /// Picked from the input when unset, see [`OutputFormat::for_input`]
+    #[serde(default)]
+    pub format: Option<OutputFormat>,
+    #[serde(default)]
+    pub mode: Option<Mode>,
+    #[serde(default)]
+    pub dither: Option<bool>,
+    #[serde(default)]
+    pub contrast: Option<i32>,
+    #[serde(default)]
+    pub brightness: Option<i32>,
+    /// Quantize the output to the 16 classic terminal colors
+    #[serde(default)]
+    pub ansi16: Option<bool>,
+    #[serde(default)]
+    pub max_height: Option<u32>,
+    #[serde(default)]
+    pub fit: Option<Fit>,
+    #[serde(default)]
+    pub char_spacing: Option<u32>,
+    #[serde(default)]
+    pub line_spacing: Option<u32>,
+    /// Line of text drawn under the art
+    #[serde(default)]
+    pub caption: Option<String>,
+    /// Add a small "made with ASCII-Bot" line under the art
+    #[serde(default)]
+    pub credit: Option<bool>,
+    #[serde(default)]
+    pub thumbnail: Option<Thumbnail>,
+    /// Region of the input converted, `[x, y, width, height]` in pixels
+    #[serde(default)]
+    pub crop: Option<[u32; 4]>,
+    #[serde(default)]
+    pub orientation: Option<Orientation>,
+    /// Width factor of the character grid, 1.0 keeps the proportions
+    #[serde(default)]
+    pub stretch: Option<f32>,
+    /// Start and end colors of the background gradient
+    #[serde(default)]
+    pub background_gradient: Option<[[u8; 3]; 2]>,
+    #[serde(default)]
+    pub gradient_direction: Option<GradientDirection>,
+    /// Steps of the typewriter animation, set from the user's tier rather
+    /// than picked
+    #[serde(default)]
+    pub animation_frames: Option<u32>,
+}
+
+impl Preferences {
+    pub fn new(
+        charset: Option<String>,
+        size: Option<u32>,
+        style: Option<Style>,
+        background_brightness: Option<BackgroundBrightness>,
+    ) -> Self {
+        Self {
+            charset: charset.map(|c| c.chars().take(MAX_CHARSET_LEN).collect()),
+            size,
+            style,
+            background_brightness,
+            ..Default::default()
+        }
+    }
+
+    /// Fill every unset option from `fallback`
+    pub fn or(self, fallback: Preferences) -> Self {
+        Self {
+            charset: self.charset.or(fallback.charset),
+            sort_charset: self.sort_charset.or(fallback.sort_charset),
+            size: self.size.or(fallback.size),
+            style: self.style.or(fallback.style),
+            background_brightness: self
+                .background_brightness
+                .or(fallback.background_brightness),
+            gamma: self.gamma.or(fallback.gamma),
+            threshold: self.threshold.or(fallback.threshold),
+            posterize: self.posterize.or(fallback.posterize),
+            denoise: self.denoise.or(fallback.denoise),
+            sharpen: self.sharpen.or(fallback.sharpen),
+            keep_original: self.keep_original.or(fallback.keep_original),
+            palette: self.palette.or(fallback.palette),
+            filter: self.filter.or(fallback.filter),
+            duotone: self.duotone.or(fallback.duotone),
+            transparent: self.transparent.or(fallback.transparent),
+            font: self.font.or(fallback.font),
+            font_size: self.font_size.or(fallback.font_size),
+            effect: self.effect.or(fallback.effect),
+            format: self.format.or(fallback.format),
+            mode: self.mode.or(fallback.mode),
+            dither: self.dither.or(fallback.dither),
+            contrast: self.contrast.or(fallback.contrast),
+            brightness: self.brightness.or(fallback.brightness),
+            ansi16: self.ansi16.or(fallback.ansi16),
+            max_height: self.max_height.or(fallback.max_height),
+            fit: self.fit.or(fallback.fit),
+            char_spacing: self.char_spacing.or(fallback.char_spacing),
+            line_spacing: self.line_spacing.or(fallback.line_spacing),
+            caption: self.caption.or(fallback.caption),
+            credit: self.credit.or(fallback.credit),
+            thumbnail: self.thumbnail.or(fallback.thumbnail),
+            crop: self.crop.or(fallback.crop),
+            orientation: self.orientation.or(fallback.orientation),
+            stretch: self.stretch.or(fallback.stretch),
+            background_gradient: self
+                .background_gradient
+                .or(fallback.background_gradient),
+            gradient_direction: self
+                .gradient_direction
+                .or(fallback.gradient_direction),
+            animation_frames: self
+                .animation_frames
+                .or(fallback.animation_frames),
+        }
+    }
+
+    /// Options read from somewhere anyone could have edited, like the
+    /// metadata of a render, checked and clamped like typed ones
+    pub fn validated(mut self) -> Res<Self> {
+        self.charset =
+            self.charset.as_deref().map(clean_charset).transpose()?;
+        self.caption = self.caption.as_deref().map(clean_caption);
+        if self.palette.as_ref().is_some_and(|p| p.len() > MAX_PALETTE_LEN) {
+            return Err(PALETTE_TOO_LONG.into());
+        }
+        if let Some(BackgroundBrightness::Percent(percent)) =
+            &mut self.background_brightness
+        {
+            *percent = (*percent).min(100);
+        }
+        // Same ranges as the slash command options
+        self.size = self.size.map(|s| s.clamp(1, MAX_COLUMNS));
+        self.max_height = self.max_height.map(|h| h.clamp(1, MAX_COLUMNS));
+        self.font_size =
+            self.font_size.map(|s| s.clamp(MIN_FONT_SIZE, MAX_FONT_SIZE));
+        self.contrast = self.contrast.map(|c| c.clamp(-100, 100));
+        self.brightness = self.brightness.map(|b| b.clamp(-100, 100));
+        self.char_spacing = self.char_spacing.map(|s| s.min(MAX_SPACING));
+        self.line_spacing = self.line_spacing.map(|s| s.min(MAX_SPACING));
+        self.posterize = self.posterize.map(|p| p.min(MAX_POSTERIZE));
+        self.sharpen = self.sharpen.map(|s| s.min(MAX_SHARPEN));
+        self.gamma = self.gamma.map(|g| g.clamp(MIN_GAMMA, MAX_GAMMA));
+        self.stretch = self.stretch.map(|s| s.clamp(MIN_STRETCH, MAX_STRETCH));
+        // Set from the tier of whoever renders, never picked
+        self.animation_frames = None;
+        Ok(self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.charset.is_none()
+            && self.sort_charset.is_none()
+            && self.size.is_none()
+            && self.style.is_none()
+            && self.background_brightness.is_none()
+            && self.gamma.is_none()
+            && self.threshold.is_none()
+            && self.posterize.is_none()
+            && self.denoise.is_none()
+            && self.sharpen.is_none()
+            && self.keep_original.is_none()
+            && self.palette.is_none()
+            && self.filter.is_none()
+            && self.duotone.is_none()
+            && self.transparent.is_none()
+            && self.font.is_none()
+            && self.font_size.is_none()
+            && self.effect.is_none()
+            && self.format.is_none()
+            && self.mode.is_none()
+            && self.dither.is_none()
+            && self.contrast.is_none()
+            && self.brightness.is_none()
+            && self.ansi16.is_none()
+            && self.max_height.is_none()
+            && self.fit.is_none()
+            && self.char_spacing.is_none()
+            && self.line_spacing.is_none()
+            && self.caption.is_none()
+            && self.credit.is_none()
+            && self.thumbnail.is_none()
+            && self.crop.is_none()
+            && self.orientation.is_none()
+            && self.stretch.is_none()
+            && self.background_gradient.is_none()
+            && self.gradient_direction.is_none()
+            && self.animation_frames.is_none()
+    }
+
+    /// The shapes mode has line glyphs to pick from by default, where the
+    /// configured charset only has shades
+    pub fn charset(&self) -> &str {
+        match (&self.charset, self.mode()) {
+            (Some(charset), _) => charset,
+            (None, Mode::Shapes) => SHAPES_CHARSET,
+            (None, _) => &config().default_charset,
+        }
+    }
+
+    /// Charsets people typed are sorted unless they turned it off, the
+    /// configured default is drawn in its own order
+    pub fn sort_charset(&self) -> bool {
+        self.sort_charset.unwrap_or(self.charset.is_some())
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size.unwrap_or(config().default_size)
+    }
+
+    pub fn style(&self) -> Style {
+        self.style.unwrap_or_default()
+    }
+
+    /// Background brightness as a 0.0 - 1.0 factor. `auto` is replaced
+    /// before rendering, the default stands in for it until then
+    pub fn background_brightness(&self) -> f32 {
+        let percent = match self.background_brightness {
+            Some(BackgroundBrightness::Percent(percent)) => percent,
+            _ => DEFAULT_BACKGROUND_BRIGHTNESS,
+        };
+        percent as f32 / 100.0
+    }
+
+    pub fn gamma(&self) -> f32 {
+        self.gamma.unwrap_or(1.0)
+    }
+
+    pub fn threshold(&self) -> u8 {
+        self.threshold.unwrap_or(0)
+    }
+
+    pub fn posterize(&self) -> u32 {
+        self.posterize.unwrap_or(0)
+    }
+
+    pub fn denoise(&self) -> bool {
+        self.denoise.unwrap_or(false)
+    }
+
+    pub fn sharpen(&self) -> u32 {
+        self.sharpen.unwrap_or(0)
+    }
+
+    pub fn keep_original(&self) -> bool {
+        self.keep_original.unwrap_or(false)
+    }
+
+    pub fn palette(&self) -> &[[u8; 3]] {
+        self.palette.as_deref().unwrap_or_default()
+    }
+
+    pub fn filter(&self) -> Filter {
+        self.filter.unwrap_or_default()
+    }
+
+    pub fn color_filter(&self) -> Option<ColorFilter> {
+        let [shadows, highlights] = self.duotone.unwrap_or(DEFAULT_DUOTONE);
+        Some(match self.filter() {
+            Filter::None => return None,
+            Filter::Sepia => ColorFilter::Sepia,
+            Filter::Duotone => ColorFilter::Duotone { shadows, highlights },
+            Filter::Protanopia => ColorFilter::Protanopia,
+            Filter::Deuteranopia => ColorFilter::Deuteranopia,
+            Filter::Tritanopia => ColorFilter::Tritanopia,
+        })
+    }
+
+    pub fn transparent(&self) -> bool {
+        self.transparent.unwrap_or(false)
+    }
+
+    pub fn font(&self) -> FontChoice {
+        self.font.unwrap_or_default()
+    }
+
+    pub fn font_size(&self) -> u32 {
+        self.font_size.unwrap_or(FONT_SIZE)
+    }
+
+    pub fn effect(&self) -> Effect {
+        self.effect.unwrap_or_default()
+    }
+
+    pub fn animation_frames(&self) -> u32 {
+        self.animation_frames.unwrap_or(TYPEWRITER_FRAMES)
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode.unwrap_or_default()
+    }
+
+    pub fn dither(&self) -> bool {
+        self.dither.unwrap_or(false)
+    }
+
+    pub fn contrast(&self) -> i32 {
+        self.contrast.unwrap_or(0)
+    }
+
+    pub fn brightness(&self) -> i32 {
+        self.brightness.unwrap_or(0)
+    }
+
+    pub fn fit(&self) -> Fit {
+        self.fit.unwrap_or_default()
+    }
+
+    pub fn ansi16(&self) -> bool {
+        self.ansi16.unwrap_or(false)
+    }
+
+    pub fn credit(&self) -> bool {
+        self.credit.unwrap_or(false)
+    }
+
+    pub fn stretch(&self) -> f32 {
+        self.stretch.unwrap_or(1.0)
+    }
+
+    pub fn thumbnail(&self) -> Thumbnail {
+        self.thumbnail.unwrap_or_default()
+    }
+
+    pub fn background_gradient(&self) -> Option<Gradient> {
+        let [from, to] = self.background_gradient?;
+        let direction = self.gradient_direction.unwrap_or_default().into();
+        Some(Gradient { from, to, direction })
+    }
+
+    /// Human readable summary, used by the settings embeds
+    pub fn describe(&self) -> String {
+        fn field<T: std::fmt::Display>(value: Option<T>) -> String {
+            value.map_or("*not set*".to_string(), |v| format!("`{v}`"))
+        }
+
+        format!(
+            "**Charset:** {}\n**Sorted charset:** {}\n**Max size:** {}\n\
+             **Style:** {}\n**Background brightness:** {}\n\
+             **Character spacing:** {}\n**Line spacing:** {}\n\
+             **Credit line:** {}\n**Thumbnail:** {}\n**Stretch:** {}\n\
+             **Background gradient:** {}\n**Filter:** {}\n**Posterize:** {}\n\
+             **Denoise:** {}\n**Sharpen:** {}\n\
+             **Keep original:** {}",
+            field(self.charset.as_deref()),
+            field(self.sort_charset),
+            field(self.size),
+            field(self.style.map(|s| s.name())),
+            field(self.background_brightness),
+            field(self.char_spacing.map(|s| format!("{s}px"))),
+            field(self.line_spacing.map(|s| format!("{s}px"))),
+            field(self.credit),
+            field(self.thumbnail.map(|t| t.name())),
+            field(self.stretch.map(|s| format!("{s}x"))),
+            field(self.background_gradient().map(|g| format!(
+                "{} to {}, {}",
+                format_palette(&[g.from]),
+                format_palette(&[g.to]),
+                self.gradient_direction.unwrap_or_default().name()
+            ))),
+            field(self.filter.map(|filter| match filter {
+                Filter::Duotone => format!(
+                    "{}, {}",
+                    filter.name(),
+                    format_palette(&self.duotone.unwrap_or(DEFAULT_DUOTONE))
+                ),
+                _ => filter.name().to_string(),
+            })),
+            field(self.posterize.map(|levels| format!("{levels} levels"))),
+            field(self.denoise),
+            field(self.sharpen.map(|amount| format!("{amount}%"))),
+            field(self.keep_original),
+        )
+    }
+}
+
+/// Parse a `#RRGGBB` or `RRGGBB` hex color
+pub fn parse_hex_color(color: &str) -> Option<[u8; 3]> {
+    let hex = color.trim().trim_start_matches('#');
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+const PALETTE_TOO_LONG: &str = "Palettes are limited to 32 colors";
+
+/// Parse a comma separated list of hex colors
+pub fn parse_palette(palette: &str) -> Res<Vec<[u8; 3]>> {
+    let colors = palette
+        .split(',')
+        .filter(|c| !c.trim().is_empty())
+        .map(|c| {
+            parse_hex_color(c)
+                .ok_or_else(|| format!("Invalid color `{}`", c.trim()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if colors.is_empty() {
+        return Err("The palette needs at least one color".into());
+    }
+    if colors.len() > MAX_PALETTE_LEN {
+        return Err(PALETTE_TOO_LONG.into());
+    }
+
+    Ok(colors)
+}
+
+/// Split the palette option into a custom palette or, for `ansi16`, the
+/// 16 color switch
+pub fn parse_palette_option(
+    palette: Option<&str>,
+) -> Res<(Option<Vec<[u8; 3]>>, Option<bool>)> {
+    Ok(match palette.map(str::trim) {
+        Some(p) if p.eq_ignore_ascii_case("ansi16") => (None, Some(true)),
+        Some(p) => (Some(parse_palette(p)?), None),
+        None => (None, None),
+    })
+}
+
+/// Parse a background brightness percentage, `%` optional, or `auto`
+pub fn parse_background_brightness(
+    brightness: &str,
+) -> Res<BackgroundBrightness> {
+    let brightness = brightness.trim();
+    if brightness.eq_ignore_ascii_case(AUTO_BACKGROUND_BRIGHTNESS) {
+        return Ok(BackgroundBrightness::Auto);
+    }
+    let percent =
+        brightness.trim_end_matches('%').trim().parse::<u32>().map_err(
+            |_| "The background brightness is a percentage, or auto",
+        )?;
+    Ok(BackgroundBrightness::Percent(percent.min(100)))
+}
+
+/// Escapes would restyle the footer, so only printable text is kept
+pub fn clean_caption(caption: &str) -> String {
+    caption.chars().filter(|c| !c.is_control()).take(MAX_CAPTION_LEN).collect()
+}
+
+/// Drop the control characters, zero width characters and repeats of a
+/// custom charset, refusing it when nothing is left
+pub fn clean_charset(charset: &str) -> Res<String> {
+    if charset.trim().eq_ignore_ascii_case(AUTO_CHARSET) {
+        return Ok(AUTO_CHARSET.to_string());
+    }
+
+    let mut seen = HashSet::new();
+    let charset: String = charset
+        .chars()
+        .filter(|&c| !c.is_control() && c.width() != Some(0))
+        .filter(|&c| seen.insert(c))
+        .take(MAX_CHARSET_LEN)
+        .collect();
+
+    if charset.is_empty() {
+        return Err("The charset has no usable characters".into());
+    }
+    Ok(charset)
+}
+
+/// Parse a crop region written as `x, y, width, height`
+pub fn parse_crop(crop: &str) -> Res<[u32; 4]> {
+    let values = crop
+        .split(',')
+        .map(|v| v.trim().parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| "Crop values must be whole numbers of pixels")?;
+
+    values.try_into().map_err(|_| {
+        "A crop is written as `x, y, width, height`, in pixels".into()
+    })
+}
+
+/// Parse the two hex colors of a background gradient
+pub fn parse_gradient(gradient: &str) -> Res<[[u8; 3]; 2]> {
+    parse_palette(gradient)?.try_into().map_err(|_| {
+        "A gradient is written as two hex colors, like `#101030, #503070`"
+            .into()
+    })
+}
+
+/// Parse the shadow and highlight colors of the duotone filter
+pub fn parse_duotone(duotone: &str) -> Res<[[u8; 3]; 2]> {
+    parse_palette(duotone)?.try_into().map_err(|_| {
+        "Duotone is written as two hex colors, shadows then highlights, \
+         like `#202048, #ffd080`"
+            .into()
+    })
+}
+
+/// Inverse of [`parse_crop`]
+pub fn format_crop([x, y, width, height]: [u32; 4]) -> String {
+    format!("{x}, {y}, {width}, {height}")
+}
+
+/// Inverse of [`parse_palette`]
+pub fn format_palette(palette: &[[u8; 3]]) -> String {
+    palette
+        .iter()
+        .map(|[r, g, b]| format!("#{r:02x}{g:02x}{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Server-wide defaults and limits, managed by the server admins
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct GuildSettings {
+    #[serde(default)]
+    pub defaults: Preferences,
+    /// Largest `max_size` members of the server may request
+    pub max_size_cap: Option<u32>,
+    /// Renders the whole server may run per day
+    pub daily_quota: Option<u32>,
+    /// Renders each member may run per day
+    pub user_daily_quota: Option<u32>,
+    /// Webhook public renders are posted through, instead of replies
+    #[serde(default)]
+    pub webhook: Option<WebhookOutput>,
+}
+
+/// Webhook created by `/webhook_output`, with the name and avatar it was
+/// given there
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WebhookOutput {
+    pub id: WebhookId,
+    pub token: String,
+    pub channel: ChannelId,
+}
+
+impl GuildSettings {
+    pub fn is_empty(&self) -> bool {
+        self.defaults.is_empty()
+            && self.max_size_cap.is_none()
+            && self.daily_quota.is_none()
+            && self.user_daily_quota.is_none()
+            && self.webhook.is_none()
+    }
+
+    /// Clamp options to the limits of this server
+    pub fn apply_caps(&self, mut prefs: Preferences) -> Preferences {
+        if let Some(cap) = self.max_size_cap {
+            prefs.size = Some(prefs.size().min(cap));
+        }
+
+        prefs
+    }
+
+    pub fn describe(&self) -> String {
+        let value = |v: Option<u32>| {
+            v.map_or("*not set*".to_string(), |v| format!("`{v}`"))
+        };
+        let webhook =
+            self.webhook.as_ref().map_or("*not set*".to_string(), |webhook| {
+                format!("<#{}>", webhook.channel)
+            });
+        format!(
+            "{}\n**Max size cap:** {}\n**Daily quota:** {}\n\
+             **Daily quota per member:** {}\n**Webhook output:** {}",
+            self.defaults.describe(),
+            value(self.max_size_cap),
+            value(self.daily_quota),
+            value(self.user_daily_quota),
+            webhook,
+        )
+    }
+}
+
+/// Everything the conversion pipeline needs to know besides the image
+#[derive(Default)]
+pub struct RenderOptions {
+    /// Options explicitly passed to the command
+    pub overrides: Preferences,
+    /// Per-command fallbacks, used when neither the command nor the
+    /// saved settings provide a value
+    pub defaults: Preferences,
+    pub ansi_file: bool,
+    /// Also attach the art as a self-contained HTML page
+    pub html_file: bool,
+    pub ephemeral: bool,
+    pub spoiler: bool,
+    /// File name of the input, used to name the outputs
+    pub source_name: String,
+    /// The source attachment was itself sent as a spoiler
+    pub spoilered_source: bool,
+    /// Render four looks side by side instead of one
+    pub collage: bool,
+    pub assets: Assets,
+}
+
+/// Files uploaded along with the image
+#[derive(Clone, Default)]
+pub struct Assets {
+    /// Replaces the bundled font picked in the preferences
+    pub font: Option<FontArc>,
+    /// Drawn behind the glyphs instead of the flat background
+    pub background: Option<Arc<RgbaImage>>,
+}
+
+impl RenderOptions {
+    /// Defaults used by the context menu commands
+    pub fn context_menu() -> Self {
+        Self {
+            defaults: Preferences {
+                background_brightness: Some(BackgroundBrightness::Percent(40)),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+}
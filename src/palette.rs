@@ -0,0 +1,126 @@
+/// The classic 16 terminal colors, VGA flavored, in SGR order
+const ANSI16: [[u8; 3]; 16] = [
+    [0, 0, 0],
+    [170, 0, 0],
+    [0, 170, 0],
+    [170, 85, 0],
+    [0, 0, 170],
+    [170, 0, 170],
+    [0, 170, 170],
+    [170, 170, 170],
+    [85, 85, 85],
+    [255, 85, 85],
+    [85, 255, 85],
+    [255, 255, 85],
+    [85, 85, 255],
+    [255, 85, 255],
+    [85, 255, 255],
+    [255, 255, 255],
+];
+
+/// Color of a 16 color SGR code, with whether it's a foreground color
+pub fn sgr_color(code: u8) -> Option<(bool, [u8; 3])> {
+    match code {
+        30..=37 => Some((true, ANSI16[(code - 30) as usize])),
+        90..=97 => Some((true, ANSI16[(code - 90 + 8) as usize])),
+        40..=47 => Some((false, ANSI16[(code - 40) as usize])),
+        100..=107 => Some((false, ANSI16[(code - 100 + 8) as usize])),
+        _ => None,
+    }
+}
+
+/// Color of an entry of the xterm 256 color palette: the 16 classic
+/// colors, a 6x6x6 color cube, then 24 grays
+pub fn xterm_color(index: u8) -> [u8; 3] {
+    const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    match index {
+        0..=15 => ANSI16[index as usize],
+        16..=231 => {
+            let i = (index - 16) as usize;
+            [CUBE_LEVELS[i / 36], CUBE_LEVELS[i / 6 % 6], CUBE_LEVELS[i % 6]]
+        }
+        _ => [8 + 10 * (index - 232); 3],
+    }
+}
+
+/// Rewrite every truecolor escape of the art into its closest 16 color one
+pub fn quantize_ansi16(ascii_art: &str) -> String {
+    rewrite_truecolor(ascii_art, |foreground, rgb| {
+        let index = nearest(&ANSI16, rgb) as u8;
+        let base = match (foreground, index < 8) {
+            (true, true) => 30,
+            (true, false) => 90 - 8,
+            (false, true) => 40,
+            (false, false) => 100 - 8,
+        };
+        (base + index).to_string()
+    })
+}
+
+/// Snap every truecolor escape of the art to the closest palette color
+pub fn snap_to_palette(ascii_art: &str, palette: &[[u8; 3]]) -> String {
+    rewrite_truecolor(ascii_art, |foreground, rgb| {
+        let [r, g, b] = palette[nearest(palette, rgb)];
+        let kind = if foreground { 38 } else { 48 };
+        format!("{kind};2;{r};{g};{b}")
+    })
+}
+
+/// Replace the parameters of every `38;2;R;G;B` and `48;2;R;G;B` escape,
+/// other escapes are kept as they are
+fn rewrite_truecolor(
+    ascii_art: &str,
+    rewrite: impl Fn(bool, [u8; 3]) -> String,
+) -> String {
+    let mut out = String::with_capacity(ascii_art.len());
+    let mut rest = ascii_art;
+
+    while let Some(start) = rest.find("\x1b[") {
+        out.push_str(&rest[..start]);
+        rest = &rest[start + 2..];
+
+        let Some(end) = rest.find('m') else {
+            out.push_str("\x1b[");
+            break;
+        };
+        let code = &rest[..end];
+        rest = &rest[end + 1..];
+
+        out.push_str("\x1b[");
+        match parse_truecolor(code) {
+            Some((foreground, rgb)) => out.push_str(&rewrite(foreground, rgb)),
+            None => out.push_str(code),
+        }
+        out.push('m');
+    }
+
+    out.push_str(rest);
+    out
+}
+
+fn parse_truecolor(code: &str) -> Option<(bool, [u8; 3])> {
+    let parts: Vec<&str> = code.split(';').collect();
+    let [kind, "2", r, g, b] = parts[..] else {
+        return None;
+    };
+    let foreground = match kind {
+        "38" => true,
+        "48" => false,
+        _ => return None,
+    };
+
+    Some((foreground, [r.parse().ok()?, g.parse().ok()?, b.parse().ok()?]))
+}
+
+/// Index of the closest palette color, by squared RGB distance
+fn nearest(palette: &[[u8; 3]], rgb: [u8; 3]) -> usize {
+    let distance = |color: &[u8; 3]| {
+        color
+            .iter()
+            .zip(rgb)
+            .map(|(&a, b)| (a as i32 - b as i32).pow(2))
+            .sum::<i32>()
+    };
+
+    (0..palette.len()).min_by_key(|&i| distance(&palette[i])).unwrap_or(0)
+}
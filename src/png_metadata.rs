@@ -0,0 +1,97 @@
+use std::io::Cursor;
+
+use png::text_metadata::{EncodableTextChunk, ITXtChunk};
+
+use crate::Res;
+
+/// Length of the signature and IHDR chunk every PNG starts with
+const HEADER_LEN: usize = 8 + 4 + 4 + 13 + 4;
+
+/// `png` with an iTXt chunk, UTF-8 text, for each `(keyword, text)` pair,
+/// inserted right after its header so readers find them before the image
+/// data
+pub fn with_text(png: &[u8], entries: &[(&str, String)]) -> Res<Vec<u8>> {
+    check_header(png)?;
+
+    let mut tagged = Vec::with_capacity(png.len() + 1024);
+    tagged.extend_from_slice(&png[..HEADER_LEN]);
+    for (keyword, text) in entries {
+        ITXtChunk::new(keyword.to_string(), text.clone())
+            .encode(&mut tagged)?;
+    }
+    tagged.extend_from_slice(&png[HEADER_LEN..]);
+    Ok(tagged)
+}
+
+/// Text chunks found before the image data of `png`, as `(keyword, text)`
+/// pairs. `None` when it isn't a readable PNG
+pub fn read_text(png: &[u8]) -> Option<Vec<(String, String)>> {
+    let reader = png::Decoder::new(Cursor::new(png)).read_info().ok()?;
+    let info = reader.info();
+
+    let latin1 = info
+        .uncompressed_latin1_text
+        .iter()
+        .map(|chunk| (chunk.keyword.clone(), chunk.text.clone()));
+    let utf8 = info.utf8_text.iter().filter_map(|chunk| {
+        Some((chunk.keyword.clone(), chunk.get_text().ok()?))
+    });
+    Some(latin1.chain(utf8).collect())
+}
+
+/// `png` with a chunk of type `kind` holding `data`, inserted right after
+/// its header. Private chunks have a lowercase second letter, and readers
+/// skip the ancillary ones, lowercase first, they don't know
+pub fn with_chunk(png: &[u8], kind: [u8; 4], data: &[u8]) -> Res<Vec<u8>> {
+    check_header(png)?;
+    let len = u32::try_from(data.len()).map_err(|_| "The chunk is too big")?;
+
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&kind);
+    crc.update(data);
+
+    let mut tagged = Vec::with_capacity(png.len() + data.len() + 12);
+    tagged.extend_from_slice(&png[..HEADER_LEN]);
+    tagged.extend_from_slice(&len.to_be_bytes());
+    tagged.extend_from_slice(&kind);
+    tagged.extend_from_slice(data);
+    tagged.extend_from_slice(&crc.finalize().to_be_bytes());
+    tagged.extend_from_slice(&png[HEADER_LEN..]);
+    Ok(tagged)
+}
+
+/// Data of the first intact chunk of type `kind` in `png`
+pub fn read_chunk(png: &[u8], kind: [u8; 4]) -> Option<&[u8]> {
+    if check_header(png).is_err() {
+        return None;
+    }
+
+    let mut rest = &png[8..];
+    while rest.len() >= 12 {
+        let len = u32::from_be_bytes(rest[..4].try_into().ok()?) as usize;
+        let end = len.checked_add(12).filter(|&end| end <= rest.len())?;
+        let (chunk_kind, data) = (&rest[4..8], &rest[8..8 + len]);
+        if chunk_kind == kind {
+            let crc = u32::from_be_bytes(rest[8 + len..end].try_into().ok()?);
+            if crc32fast::hash(&rest[4..8 + len]) == crc {
+                return Some(data);
+            }
+        }
+        if chunk_kind == b"IEND" {
+            break;
+        }
+        rest = &rest[end..];
+    }
+    None
+}
+
+/// Refuse bytes that don't start with the PNG signature and header chunk
+fn check_header(png: &[u8]) -> Res<()> {
+    if png.len() < HEADER_LEN
+        || !png.starts_with(b"\x89PNG\r\n\x1a\n")
+        || &png[12..16] != b"IHDR"
+    {
+        return Err("Not a PNG file".into());
+    }
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use std::sync::Mutex;
+
+use tokio::sync::Notify;
+
+use crate::{Res, tiers::Tier};
+
+pub const BUSY: &str = "The bot is too busy right now, try again in a minute";
+
+/// Bounded line of renders, at most `workers` run at once and at most
+/// `max_waiting` wait for their turn. Supporters are let in ahead of the
+/// free tier, each tier in the order it came in
+pub struct RenderQueue {
+    state: Mutex<State>,
+    /// Woken whenever a worker frees up or someone leaves the line
+    changed: Notify,
+    max_waiting: usize,
+}
+
+struct State {
+    idle: usize,
+    /// Ids of the waiting tickets, next to start first
+    waiting: Vec<(u64, Tier)>,
+    next_id: u64,
+}
+
+/// A place in the queue, see [`Ticket::start`]
+pub struct Ticket<'a> {
+    queue: &'a RenderQueue,
+    id: u64,
+    tier: Tier,
+    /// Set when a worker was free right away
+    worker: Option<Worker<'a>>,
+    /// 1 for the next render to start
+    position: usize,
+}
+
+/// A running render, the worker is given back when it's dropped
+pub struct Worker<'a>(&'a RenderQueue);
+
+impl RenderQueue {
+    pub fn new(workers: usize, max_waiting: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                idle: workers.max(1),
+                waiting: Vec::new(),
+                next_id: 0,
+            }),
+            changed: Notify::new(),
+            max_waiting,
+        }
+    }
+
+    /// Take a free worker, or a place in line behind everyone of the same
+    /// tier or above. Refused when the line is full
+    pub fn join(&self, tier: Tier) -> Res<Ticket<'_>> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+
+        if state.idle > 0 && state.waiting.is_empty() {
+            state.idle -= 1;
+            return Ok(Ticket {
+                queue: self,
+                id,
+                tier,
+                worker: Some(Worker(self)),
+                position: 0,
+            });
+        }
+        if state.waiting.len() >= self.max_waiting {
+            return Err(BUSY.into());
+        }
+
+        let position = state
+            .waiting
+            .iter()
+            .position(|&(_, other)| other < tier)
+            .unwrap_or(state.waiting.len());
+        state.waiting.insert(position, (id, tier));
+        Ok(Ticket {
+            queue: self,
+            id,
+            tier,
+            worker: None,
+            position: position + 1,
+        })
+    }
+
+    fn release(&self) {
+        self.state.lock().unwrap().idle += 1;
+        self.changed.notify_waiters();
+    }
+}
+
+impl<'a> Ticket<'a> {
+    /// Place in the line when joining, `None` when a worker was free
+    pub fn position(&self) -> Option<usize> {
+        self.worker.is_none().then_some(self.position)
+    }
+
+    pub fn tier(&self) -> Tier {
+        self.tier
+    }
+
+    /// Wait for a worker, the render runs while it's held
+    pub async fn start(mut self) -> Worker<'a> {
+        if let Some(worker) = self.worker.take() {
+            return worker;
+        }
+
+        loop {
+            // Created before checking, so a worker freed in between isn't
+            // missed
+            let changed = self.queue.changed.notified();
+            {
+                let mut state = self.queue.state.lock().unwrap();
+                let next = state.waiting.first().map(|&(id, _)| id);
+                if state.idle > 0 && next == Some(self.id) {
+                    state.idle -= 1;
+                    state.waiting.remove(0);
+                    // Left the line, dropping the ticket has nothing to undo
+                    self.position = 0;
+                    return Worker(self.queue);
+                }
+            }
+            changed.await;
+        }
+    }
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        if self.position > 0 {
+            let mut state = self.queue.state.lock().unwrap();
+            state.waiting.retain(|&(id, _)| id != self.id);
+            drop(state);
+            // The next in line may be able to start now
+            self.queue.changed.notify_waiters();
+        }
+    }
+}
+
+impl Drop for Worker<'_> {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
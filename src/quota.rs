@@ -0,0 +1,75 @@
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use poise::serenity_prelude::{GuildId, UserId};
+
+use crate::options::GuildSettings;
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// A render refused because a daily quota is used up
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    pub limit: u32,
+    /// The quota is the member's own, not the whole server's
+    pub per_member: bool,
+    /// Unix time the quota resets at, in seconds
+    pub resets_at: u64,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let whose = if self.per_member { "You" } else { "This server" };
+        write!(
+            f,
+            "{whose} used all {} renders of today, the quota resets <t:{}:R>",
+            self.limit, self.resets_at
+        )
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// A render counted against the daily quotas before it ran, handed back
+/// to [`Storage::refund_quota`](crate::storage::Storage::refund_quota)
+/// when it fails
+pub struct Reservation {
+    pub day: u64,
+    pub guild: GuildId,
+    pub user: UserId,
+}
+
+/// Refuse a render once the `used` renders of the server on `day`, or the
+/// `member_used` ones of the member, reach one of the server's quotas
+pub fn check(
+    settings: &GuildSettings,
+    day: u64,
+    used: u32,
+    member_used: u32,
+) -> Result<(), QuotaExceeded> {
+    let exceeded = |limit, per_member| QuotaExceeded {
+        limit,
+        per_member,
+        resets_at: (day + 1) * SECS_PER_DAY,
+    };
+
+    if let Some(limit) = settings.daily_quota
+        && used >= limit
+    {
+        return Err(exceeded(limit, false));
+    }
+    if let Some(limit) = settings.user_daily_quota
+        && member_used >= limit
+    {
+        return Err(exceeded(limit, true));
+    }
+    Ok(())
+}
+
+/// Days since the unix epoch, quotas count renders per UTC day
+pub fn today() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH);
+    now.map_or(0, |d| d.as_secs()) / SECS_PER_DAY
+}
@@ -0,0 +1,733 @@
+use std::{borrow::Cow, fmt::Write, sync::Arc};
+
+use image::{ImageFormat, RgbaImage, imageops};
+
+use poise::ChoiceParameter;
+
+use tracing::{Span, debug, debug_span, instrument};
+
+use crate::{
+    Res,
+    config::config,
+    effects,
+    image_stats::{ImageKind, ImageStats},
+    image_to_ascii::{
+        AsciiRenderer, MIN_FONT_SIZE, decode_image, encode_image, encode_jpeg,
+    },
+    metadata::{embed_options, embed_original},
+    options::{
+        AUTO_CHARSET, Assets, BackgroundBrightness, Effect, Fit, Mode,
+        OutputFormat, PRESETS, Preferences, Thumbnail,
+    },
+    palette,
+};
+
+/// Presets shown side by side by the collage option
+const COLLAGE_LOOKS: [&str; 4] =
+    ["Mixed", "Foreground paint", "Background only", "Blocks"];
+/// Room above each collage tile for its label, in pixels
+const COLLAGE_LABEL_HEIGHT: u32 = 24;
+/// Default Discord attachment size limit, and the limit of all the
+/// attachments of one message together
+pub const UPLOAD_LIMIT: usize = 10 * 1024 * 1024;
+pub const MESSAGE_LIMIT: usize = 25 * 1024 * 1024;
+/// Credit line added under the art, and the colors of the footer lines
+const CREDIT: &str = "made with ASCII-Bot";
+const CAPTION_COLOR: [u8; 3] = [255, 255, 255];
+const CREDIT_COLOR: [u8; 3] = [128, 128, 128];
+/// Largest share of the output's width and height the thumbnail takes,
+/// and its distance to the edges, in pixels
+const THUMBNAIL_FRACTION: u32 = 4;
+const THUMBNAIL_MARGIN: u32 = 8;
+/// Most tiles an oversized render is split into
+const MAX_TILES: u32 = 4;
+/// JPEG qualities tried when even the smallest font is too big
+const FALLBACK_JPEG_QUALITIES: [u8; 3] = [70, 50, 30];
+
+/// Result of a single conversion
+#[derive(Clone)]
+pub struct Rendered {
+    pub ascii_art: String,
+    /// Encoded image, in `format`. Several numbered tiles, top to bottom,
+    /// when the whole image couldn't be uploaded as one
+    pub images: Vec<Vec<u8>>,
+    pub format: OutputFormat,
+    /// Size of the art in characters
+    pub columns: u32,
+    pub rows: u32,
+    /// The output had to be shrunk to fit the upload limit
+    pub downscaled: bool,
+}
+
+/// [`render`], or [`render_collage`] when `collage` is set, on the blocking
+/// thread pool, so a big render can't stall the gateway heartbeats and
+/// the other interactions
+pub async fn render_blocking(
+    image_bytes: Arc<[u8]>,
+    prefs: Preferences,
+    assets: Assets,
+    collage: bool,
+) -> Res<Rendered> {
+    let span = Span::current();
+    tokio::task::spawn_blocking(move || {
+        span.in_scope(|| {
+            let prefs = resolve_auto(&image_bytes, prefs)?;
+            let mut rendered = if collage {
+                render_collage(&image_bytes, &prefs, &assets)?
+            } else {
+                render(&image_bytes, &prefs, &assets)?
+            };
+            // Re-uploaded renders can then be made again, see
+            // `/same_as_render`. The images were already fitted to the
+            // limits, one right at them goes without the chunks
+            if let OutputFormat::Png = rendered.format {
+                let mut total: usize =
+                    rendered.images.iter().map(Vec::len).sum();
+                for image in &mut rendered.images {
+                    let embedded = embed_options(image, &prefs)?;
+                    let grown = total - image.len() + embedded.len();
+                    if embedded.len() <= UPLOAD_LIMIT && grown <= MESSAGE_LIMIT
+                    {
+                        total = grown;
+                        *image = embedded;
+                    }
+                }
+                // For "Restore original", when it fits in a single upload
+                if let [png] = rendered.images.as_mut_slice()
+                    && prefs.keep_original()
+                    && image_bytes.len() <= config().max_embedded_original
+                {
+                    let embedded = embed_original(png, &image_bytes)?;
+                    if embedded.len() <= UPLOAD_LIMIT {
+                        *png = embedded;
+                    }
+                }
+            }
+            Ok(rendered)
+        })
+    })
+    .await?
+}
+
+/// Run the whole conversion pipeline, shared by commands and components
+/// `assets` are the files uploaded along with the image
+fn render(
+    image_bytes: &[u8],
+    prefs: &Preferences,
+    assets: &Assets,
+) -> Res<Rendered> {
+    let renderer = renderer_for(prefs, assets)?;
+    let ascii_art = convert(&renderer, image_bytes, prefs)?;
+    // Only the drawn art gets the footer, the text outputs stay as they are
+    let drawn = with_footer(&renderer, &ascii_art, prefs);
+    let effect = prefs.effect();
+    // The effects are drawn on pixels, an SVG has none
+    if matches!(prefs.format, Some(OutputFormat::Svg))
+        && !matches!(effect, Effect::None)
+    {
+        return Err("Effects can't be applied to SVG output".into());
+    }
+    if let Effect::Typewriter | Effect::BeforeAfter | Effect::Rain = effect {
+        if !config().features.animations {
+            return Err("Animations are turned off on this bot".into());
+        }
+        let (gif, downscaled) = shrink_font(prefs, |prefs| {
+            let renderer = renderer_for(prefs, assets)?;
+            match effect {
+                Effect::BeforeAfter => {
+                    renderer.render_before_after(&drawn, image_bytes)
+                }
+                Effect::Rain => renderer.render_rain(&drawn),
+                _ => renderer.render_typewriter(&drawn),
+            }
+        })?
+        .ok_or(
+            "The animation is too large to upload, try a smaller max_size",
+        )?;
+        let format = OutputFormat::Gif;
+        return Ok(rendered(
+            &renderer,
+            ascii_art,
+            vec![gif],
+            format,
+            downscaled,
+        ));
+    }
+
+    let format = output_format(image_bytes, prefs);
+    if let OutputFormat::Svg = format {
+        let svg = renderer.render_to_svg(&drawn).into_bytes();
+        // A smaller font hardly changes the size of the markup, so it is
+        // refused instead of shrunk
+        if svg.len() > UPLOAD_LIMIT {
+            return Err(
+                "The render is too large to upload, try a smaller max_size"
+                    .into(),
+            );
+        }
+        return Ok(rendered(&renderer, ascii_art, vec![svg], format, false));
+    }
+
+    let source = match prefs.thumbnail() {
+        Thumbnail::Off => None,
+        _ => Some(renderer.load_image(image_bytes)?.to_rgba8()),
+    };
+    let draw = |prefs: &Preferences| {
+        let image = renderer_for(prefs, assets)?.render_to_image(&drawn)?;
+        Ok(match &source {
+            Some(source) => inset_thumbnail(image, source, prefs.thumbnail()),
+            None => image,
+        })
+    };
+    let (images, format, downscaled) = match (format, effect) {
+        // The thumbnail is pasted onto the whole image, streaming can't
+        (OutputFormat::Png, Effect::None) if source.is_none() => {
+            stream_png_fitting(prefs, &drawn, assets, draw)?
+        }
+        _ => encode_fitting(format, prefs, draw)?,
+    };
+
+    Ok(rendered(&renderer, ascii_art, images, format, downscaled))
+}
+
+/// Render the image in four looks at half the size, laid out in a labeled
+/// 2x2 grid. The text of the result is the one of the top left tile
+fn render_collage(
+    image_bytes: &[u8],
+    prefs: &Preferences,
+    assets: &Assets,
+) -> Res<Rendered> {
+    let mut tiles = Vec::with_capacity(COLLAGE_LOOKS.len());
+
+    for name in COLLAGE_LOOKS {
+        let preset = PRESETS
+            .iter()
+            .find(|p| p.name == name)
+            .expect("collage looks are presets");
+
+        let mut tile_prefs = prefs.clone();
+        tile_prefs.size = Some((prefs.size() / 2).max(1));
+        tile_prefs.max_height = prefs.max_height.map(|h| (h / 2).max(1));
+        preset.apply(&mut tile_prefs);
+
+        let renderer = renderer_for(&tile_prefs, assets)?;
+        let ascii_art = convert(&renderer, image_bytes, &tile_prefs)?;
+
+        tiles.push((name, tile_prefs, ascii_art));
+    }
+
+    let format = output_format(image_bytes, prefs);
+    let (images, format, downscaled) =
+        encode_fitting(format, prefs, |prefs| {
+            draw_collage(&tiles, prefs, assets)
+        })?;
+    let (_, tile_prefs, ascii_art) = tiles.swap_remove(0);
+    let renderer = renderer_for(&tile_prefs, assets)?;
+
+    Ok(rendered(&renderer, ascii_art, images, format, downscaled))
+}
+
+/// The art followed by the caption and credit lines, centered under it
+fn with_footer<'a>(
+    renderer: &AsciiRenderer,
+    ascii_art: &'a str,
+    prefs: &Preferences,
+) -> Cow<'a, str> {
+    let caption = prefs.caption.as_deref().map(str::trim);
+    let caption = caption.filter(|c| !c.is_empty());
+    if caption.is_none() && !prefs.credit() {
+        return Cow::Borrowed(ascii_art);
+    }
+
+    let columns =
+        ascii_art.lines().map(|l| renderer.visible_width(l)).max().unwrap_or(0);
+    // A blank line keeps the footer apart from the art
+    let mut art = format!("{}\x1b[0m\n", ascii_art.trim_end_matches('\n'));
+    let mut push_line = |text: &str, [r, g, b]: [u8; 3]| {
+        let pad = columns.saturating_sub(renderer.visible_width(text)) / 2;
+        let _ = write!(art, "\n{:pad$}\x1b[38;2;{r};{g};{b}m{text}\x1b[0m", "");
+    };
+
+    if let Some(caption) = caption {
+        push_line(caption, CAPTION_COLOR);
+    }
+    if prefs.credit() {
+        push_line(CREDIT, CREDIT_COLOR);
+    }
+    Cow::Owned(art)
+}
+
+/// Turn the image into colored text, with the charset or block characters
+#[instrument(level = "debug", skip_all)]
+fn convert(
+    renderer: &AsciiRenderer,
+    image_bytes: &[u8],
+    prefs: &Preferences,
+) -> Res<String> {
+    let ascii_art = match (prefs.mode(), prefs.mode().block_mode()) {
+        (_, Some(mode)) => renderer.process_blocks(image_bytes, mode)?,
+        (Mode::Edges, None) => renderer.process_edges(image_bytes, false)?,
+        (Mode::ShadedEdges, None) => {
+            renderer.process_edges(image_bytes, true)?
+        }
+        (Mode::Shapes, None) => renderer.process_shapes(image_bytes)?,
+        _ => renderer.process_image(image_bytes)?,
+    };
+
+    let ascii_art = match (prefs.fit(), renderer.max_height()) {
+        (Fit::Contain, _) => ascii_art,
+        (Fit::Exact, Some(height)) => {
+            letterbox(renderer, &ascii_art, renderer.max_width(), height)
+        }
+        (Fit::Exact, None) => {
+            return Err("`fit: Exact` needs a max_height too".into());
+        }
+    };
+
+    if prefs.ansi16() {
+        return Ok(palette::quantize_ansi16(&ascii_art));
+    }
+    if !prefs.palette().is_empty() {
+        return Ok(palette::snap_to_palette(&ascii_art, prefs.palette()));
+    }
+    Ok(ascii_art)
+}
+
+/// Center the art in exactly `width` by `height` cells, padding with blank
+/// cells that get the background color
+fn letterbox(
+    renderer: &AsciiRenderer,
+    ascii_art: &str,
+    width: u32,
+    height: u32,
+) -> String {
+    let lines: Vec<&str> = ascii_art.lines().collect();
+    let columns =
+        lines.iter().map(|l| renderer.visible_width(l)).max().unwrap_or(0);
+    let (width, height) = (width as usize, height as usize);
+    let left = width.saturating_sub(columns) / 2;
+    let top = height.saturating_sub(lines.len()) / 2;
+    let blank = " ".repeat(width);
+
+    let mut boxed = String::with_capacity(ascii_art.len() + width * height);
+    for _ in 0..top {
+        boxed.push_str(&blank);
+        boxed.push('\n');
+    }
+    for line in &lines {
+        let right = width.saturating_sub(left + renderer.visible_width(line));
+        boxed.push_str(&" ".repeat(left));
+        boxed.push_str(line);
+        // Reset first, so the padding doesn't pick up the line's colors
+        boxed.push_str("\x1b[0m");
+        boxed.push_str(&" ".repeat(right));
+        boxed.push('\n');
+    }
+    for _ in (top + lines.len())..height {
+        boxed.push_str(&blank);
+        boxed.push('\n');
+    }
+
+    boxed
+}
+
+/// Paste a shrunk copy of the source image, with a thin border, into a
+/// corner of the render
+fn inset_thumbnail(
+    mut image: RgbaImage,
+    source: &RgbaImage,
+    corner: Thumbnail,
+) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    let scale = f32::min(
+        (width / THUMBNAIL_FRACTION) as f32 / source.width() as f32,
+        (height / THUMBNAIL_FRACTION) as f32 / source.height() as f32,
+    );
+    let thumb_width = ((source.width() as f32 * scale) as u32).max(1);
+    let thumb_height = ((source.height() as f32 * scale) as u32).max(1);
+    let thumbnail = imageops::thumbnail(source, thumb_width, thumb_height);
+
+    let left = THUMBNAIL_MARGIN;
+    let top = THUMBNAIL_MARGIN;
+    let right = width.saturating_sub(thumb_width + THUMBNAIL_MARGIN);
+    let bottom = height.saturating_sub(thumb_height + THUMBNAIL_MARGIN);
+    let (x, y) = match corner {
+        Thumbnail::Off => return image,
+        Thumbnail::TopLeft => (left, top),
+        Thumbnail::TopRight => (right, top),
+        Thumbnail::BottomLeft => (left, bottom),
+        Thumbnail::BottomRight => (right, bottom),
+    };
+
+    let border = image::Rgba([255, 255, 255, 255]);
+    let frame =
+        RgbaImage::from_pixel(thumb_width + 2, thumb_height + 2, border);
+    imageops::overlay(&mut image, &frame, x as i64 - 1, y as i64 - 1);
+    imageops::overlay(&mut image, &thumbnail, x as i64, y as i64);
+    image
+}
+
+/// Draw the collage tiles, at the font size of `prefs`
+fn draw_collage(
+    tiles: &[(&str, Preferences, String)],
+    prefs: &Preferences,
+    assets: &Assets,
+) -> Res<RgbaImage> {
+    let mut images = Vec::with_capacity(tiles.len());
+    for (name, tile_prefs, ascii_art) in tiles {
+        let tile_prefs =
+            Preferences { font_size: prefs.font_size, ..tile_prefs.clone() };
+        let renderer = renderer_for(&tile_prefs, assets)?;
+        let image = renderer.render_to_image(ascii_art)?;
+
+        images.push((name, renderer, image));
+    }
+
+    let tile_width = images.iter().map(|t| t.2.width()).max().unwrap_or(0);
+    let tile_height = images.iter().map(|t| t.2.height()).max().unwrap_or(0)
+        + COLLAGE_LABEL_HEIGHT;
+    let alpha = if prefs.transparent() { 0 } else { 255 };
+    let mut collage = RgbaImage::from_pixel(
+        tile_width * 2,
+        tile_height * 2,
+        image::Rgba([0, 0, 0, alpha]),
+    );
+
+    for (i, (name, renderer, image)) in images.iter().enumerate() {
+        let x = (i as u32 % 2) * tile_width;
+        let y = (i as u32 / 2) * tile_height;
+
+        renderer.draw_label(&mut collage, x as i32 + 4, y as i32 + 3, name);
+        imageops::overlay(
+            &mut collage,
+            image,
+            x as i64,
+            (y + COLLAGE_LABEL_HEIGHT) as i64,
+        );
+    }
+
+    Ok(collage)
+}
+
+/// Encode the image drawn by `draw`, shrinking the font, then splitting it
+/// into tiles and then lowering the quality until it fits in a Discord
+/// message. Also tells whether it had to
+fn encode_fitting(
+    format: OutputFormat,
+    prefs: &Preferences,
+    draw: impl Fn(&Preferences) -> Res<RgbaImage>,
+) -> Res<(Vec<Vec<u8>>, OutputFormat, bool)> {
+    let image_format = format
+        .image_format()
+        .ok_or("SVG output is only available for single renders")?;
+    let mut prefs = prefs.clone();
+    let mut downscaled = false;
+
+    let image = loop {
+        let font_size = prefs.font_size();
+        let image = debug_span!("render", font_size).in_scope(|| {
+            draw(&prefs).map(|image| apply_effect(image, &prefs))
+        })?;
+        // Encoders refusing the dimensions count as too big as well
+        let encoded = debug_span!("encode", format = format.name())
+            .in_scope(|| encode_image(&image, image_format));
+        if let Ok(encoded) = encoded
+            && encoded.len() <= UPLOAD_LIMIT
+        {
+            return Ok((vec![encoded], format, downscaled));
+        }
+
+        if font_size <= MIN_FONT_SIZE {
+            break image;
+        }
+        prefs.font_size = Some((font_size * 3 / 4).max(MIN_FONT_SIZE));
+        downscaled = true;
+    };
+
+    // Smallest font and still too big, splitting it up keeps the format
+    // and the quality, lossy is the last resort
+    for count in 2..=MAX_TILES {
+        if let Some(tiles) = encode_tiles(&image, count, image_format) {
+            return Ok((tiles, format, true));
+        }
+    }
+
+    for quality in FALLBACK_JPEG_QUALITIES {
+        if let Ok(encoded) = encode_jpeg(&image, quality)
+            && encoded.len() <= UPLOAD_LIMIT
+        {
+            return Ok((vec![encoded], OutputFormat::Jpeg, true));
+        }
+    }
+
+    Err("The render is too large to upload, try a smaller max_size".into())
+}
+
+/// Cut the image into `count` horizontal strips, `None` when any of them
+/// is still too big, or all of them are too big for one message
+fn encode_tiles(
+    image: &RgbaImage,
+    count: u32,
+    image_format: ImageFormat,
+) -> Option<Vec<Vec<u8>>> {
+    let (width, height) = image.dimensions();
+    let tile_height = height.div_ceil(count);
+
+    let tiles: Vec<Vec<u8>> = (0..count)
+        .map(|i| {
+            let top = i * tile_height;
+            let rows = tile_height.min(height.saturating_sub(top));
+            let tile = imageops::crop_imm(image, 0, top, width, rows);
+            encode_image(&tile.to_image(), image_format)
+                .ok()
+                .filter(|encoded| encoded.len() <= UPLOAD_LIMIT)
+        })
+        .collect::<Option<_>>()?;
+    (tiles.iter().map(Vec::len).sum::<usize>() <= MESSAGE_LIMIT)
+        .then_some(tiles)
+}
+
+/// Name of the `index`th of `count` tiles, `name` itself when there's
+/// only one, e.g. `cat_ascii_w150_mixed_2of3.png`
+pub fn tile_name(name: &str, index: usize, count: usize) -> String {
+    if count <= 1 {
+        return name.to_string();
+    }
+    let (stem, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    format!("{stem}_{}of{count}.{extension}", index + 1)
+}
+
+/// Like `encode_fitting` for plain PNGs, which are encoded band by band
+/// without ever holding the whole image. A background image still takes
+/// a full size backdrop, held once rather than next to the drawn image,
+/// and the lossy fallback of `encode_fitting` needs the full image
+fn stream_png_fitting(
+    prefs: &Preferences,
+    ascii_art: &str,
+    assets: &Assets,
+    draw: impl Fn(&Preferences) -> Res<RgbaImage>,
+) -> Res<(Vec<Vec<u8>>, OutputFormat, bool)> {
+    let encode = |prefs: &Preferences| {
+        renderer_for(prefs, assets)?.render_to_png(ascii_art)
+    };
+    if let Some((png, downscaled)) = shrink_font(prefs, encode)? {
+        return Ok((vec![png], OutputFormat::Png, downscaled));
+    }
+
+    let mut prefs = prefs.clone();
+    prefs.font_size = Some(MIN_FONT_SIZE);
+    let (images, format, _) = encode_fitting(OutputFormat::Png, &prefs, draw)?;
+    Ok((images, format, true))
+}
+
+/// Encode with a font shrinking until the result fits in a Discord upload,
+/// also tells whether it had to shrink. `None` when even the smallest font
+/// is too big
+fn shrink_font(
+    prefs: &Preferences,
+    encode: impl Fn(&Preferences) -> Res<Vec<u8>>,
+) -> Res<Option<(Vec<u8>, bool)>> {
+    let mut prefs = prefs.clone();
+    let mut downscaled = false;
+
+    loop {
+        let font_size = prefs.font_size();
+        // Drawing and encoding are interleaved, so they're timed together
+        let encoded = debug_span!("render_encode", font_size)
+            .in_scope(|| encode(&prefs))?;
+        if encoded.len() <= UPLOAD_LIMIT {
+            return Ok(Some((encoded, downscaled)));
+        }
+
+        if font_size <= MIN_FONT_SIZE {
+            return Ok(None);
+        }
+        prefs.font_size = Some((font_size * 3 / 4).max(MIN_FONT_SIZE));
+        downscaled = true;
+    }
+}
+
+/// Standalone HTML page of already converted art
+pub fn html_export(ascii_art: &str, prefs: &Preferences) -> Res<String> {
+    Ok(renderer_for(prefs, &Assets::default())?.render_to_html(ascii_art))
+}
+
+/// Name of an output file, built from the source and the main options so
+/// saved renders stay identifiable, e.g. `myphoto_ascii_w150_mixed.png`
+pub fn output_name(
+    source: &str,
+    prefs: &Preferences,
+    collage: bool,
+    spoiler: bool,
+    extension: &str,
+) -> String {
+    let stem = source.rsplit_once('.').map_or(source, |(stem, _)| stem);
+    let stem = stem.strip_prefix("SPOILER_").unwrap_or(stem);
+    let mut stem: String = stem
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+        .take(32)
+        .collect();
+    if stem.is_empty() {
+        stem = "image".to_string();
+    }
+
+    let look = if collage {
+        "collage".to_string()
+    } else {
+        prefs.style().name().to_lowercase().replace(' ', "_")
+    };
+    // Discord hides any attachment whose name starts with SPOILER_
+    let prefix = if spoiler { "SPOILER_" } else { "" };
+
+    format!("{prefix}{stem}_ascii_w{}_{look}.{extension}", prefs.size())
+}
+
+/// `prefs` with the charset renders of `image_bytes` are drawn with: picked
+/// by `charset: auto`, sorted, and not to be sorted again
+pub async fn drawn_prefs(
+    image_bytes: Arc<[u8]>,
+    prefs: Preferences,
+    assets: Assets,
+) -> Res<Preferences> {
+    tokio::task::spawn_blocking(move || {
+        let mut prefs = resolve_auto(&image_bytes, prefs)?;
+        let charset = renderer_for(&prefs, &assets)?.charset().to_string();
+        prefs.charset = Some(charset);
+        prefs.sort_charset = Some(false);
+        Ok(prefs)
+    })
+    .await?
+}
+
+/// PNG of `image_bytes` pixelated at the grid its renders have, see
+/// [`AsciiRenderer::pixelate`]
+pub async fn pixel_preview(
+    image_bytes: Arc<[u8]>,
+    prefs: Preferences,
+    assets: Assets,
+) -> Res<Vec<u8>> {
+    tokio::task::spawn_blocking(move || {
+        let renderer = renderer_for(&prefs, &assets)?;
+        encode_image(&renderer.pixelate(&image_bytes)?, ImageFormat::Png)
+    })
+    .await?
+}
+
+/// Charset `charset: auto` draws drawings with, in line art mode
+const DRAWING_CHARSET: &str = " .:-=+";
+
+/// Replace the `auto` charset and background brightness by what suits
+/// the image, measuring it once for both
+fn resolve_auto(
+    image_bytes: &[u8],
+    mut prefs: Preferences,
+) -> Res<Preferences> {
+    let auto_charset = prefs.charset.as_deref() == Some(AUTO_CHARSET);
+    let auto_background =
+        matches!(prefs.background_brightness, Some(BackgroundBrightness::Auto));
+    if !auto_charset && !auto_background {
+        return Ok(prefs);
+    }
+
+    let image = decode_image(image_bytes, config().pixel_budget)?;
+    let stats = ImageStats::measure(&image);
+    debug!(?stats, kind = ?stats.kind(), "Measured the image");
+    if auto_charset {
+        pick_charset(&mut prefs, stats.kind());
+    }
+    if auto_background {
+        let percent = stats.background_brightness();
+        prefs.background_brightness =
+            Some(BackgroundBrightness::Percent(percent));
+    }
+    Ok(prefs)
+}
+
+/// Charset suiting images of `kind`: the dense preset for photos, blocks
+/// for pixel art and line art for drawings. The mode is only picked when
+/// none was
+fn pick_charset(prefs: &mut Preferences, kind: ImageKind) {
+    let preset = |name| {
+        PRESETS
+            .iter()
+            .find(|p| p.name == name)
+            .and_then(|p| p.charset)
+            .expect("auto charsets are presets")
+    };
+    let (charset, mode) = match kind {
+        ImageKind::Photo => (preset("Dense"), Mode::Charset),
+        ImageKind::PixelArt => (preset("Blocks"), Mode::HalfBlocks),
+        ImageKind::Drawing => (DRAWING_CHARSET, Mode::Edges),
+    };
+    prefs.charset = Some(charset.to_string());
+    prefs.sort_charset = Some(false);
+    prefs.mode = prefs.mode.or(Some(mode));
+}
+
+fn renderer_for(prefs: &Preferences, assets: &Assets) -> Res<AsciiRenderer> {
+    let (h, v) = prefs.orientation.map_or((false, false), |o| o.flips());
+    AsciiRenderer::builder()
+        .font(prefs.font().into())
+        .custom_font(assets.font.clone())
+        .background_image(assets.background.clone())
+        .background_gradient(prefs.background_gradient())
+        .font_size(prefs.font_size())
+        .spacing(
+            prefs.char_spacing.unwrap_or(0),
+            prefs.line_spacing.unwrap_or(0),
+        )
+        .transparent(prefs.transparent())
+        .max_width(prefs.size())
+        .max_height(prefs.max_height)
+        .background_brightness(prefs.background_brightness())
+        .charset(prefs.charset())
+        .sort_charset(prefs.sort_charset())
+        .style(prefs.style().into())
+        .gamma(prefs.gamma())
+        .adjustments(prefs.contrast(), prefs.brightness())
+        .threshold(prefs.threshold())
+        .posterize(prefs.posterize())
+        .denoise(prefs.denoise())
+        .sharpen(prefs.sharpen())
+        .palette(prefs.palette())
+        .color_filter(prefs.color_filter())
+        .dither(prefs.dither())
+        .pixel_budget(config().pixel_budget)
+        .rotate(prefs.orientation.map_or(0, |o| o.quarter_turns()))
+        .flip(h, v)
+        .crop(prefs.crop)
+        .stretch(prefs.stretch())
+        .typewriter_frames(prefs.animation_frames())
+        .build()
+}
+
+fn output_format(image_bytes: &[u8], prefs: &Preferences) -> OutputFormat {
+    prefs.format.unwrap_or_else(|| OutputFormat::for_input(image_bytes))
+}
+
+fn apply_effect(image: RgbaImage, prefs: &Preferences) -> RgbaImage {
+    match prefs.effect() {
+        // Animations are drawn by the renderer itself
+        Effect::None
+        | Effect::Typewriter
+        | Effect::BeforeAfter
+        | Effect::Rain => image,
+        Effect::Crt => effects::crt(&image),
+    }
+}
+
+fn rendered(
+    renderer: &AsciiRenderer,
+    ascii_art: String,
+    images: Vec<Vec<u8>>,
+    format: OutputFormat,
+    downscaled: bool,
+) -> Rendered {
+    let columns =
+        ascii_art.lines().map(|l| renderer.visible_width(l)).max().unwrap_or(0)
+            as u32;
+    let rows = ascii_art.lines().count() as u32;
+
+    Rendered { ascii_art, images, format, columns, rows, downscaled }
+}
@@ -0,0 +1,136 @@
+use poise::{
+    FrameworkError,
+    serenity_prelude::{
+        self as serenity, CreateEmbed, CreateMessage, ExecuteWebhook,
+        FullEvent, Interaction, Webhook,
+    },
+};
+use tracing::{error, warn};
+
+use crate::{Data, Error, config::config};
+
+/// Longest error text put in a report, Discord caps descriptions at 4096
+const MAX_REPORT_LEN: usize = 1500;
+
+/// Forward errors that point at a bug or an outage, rather than at what a
+/// user sent, to the configured webhook and, when asked, the bot owners
+pub async fn report_error(error: &FrameworkError<'_, Data, Error>) {
+    let report = match error {
+        FrameworkError::Command { error, ctx, .. } if is_unexpected(error) => {
+            Report {
+                source: format!("command `/{}`", ctx.command().qualified_name),
+                user: Some(ctx.author().id),
+                guild: ctx.guild_id(),
+                details: error_chain(&**error),
+            }
+        }
+        FrameworkError::CommandPanic { payload, ctx, .. } => Report {
+            source: format!("command `/{}`", ctx.command().qualified_name),
+            user: Some(ctx.author().id),
+            guild: ctx.guild_id(),
+            details: format!(
+                "panicked: {}",
+                payload.as_deref().unwrap_or("no message")
+            ),
+        },
+        FrameworkError::EventHandler { error, event, .. }
+            if is_unexpected(error) =>
+        {
+            let (user, guild) = match event {
+                FullEvent::InteractionCreate {
+                    interaction: Interaction::Component(component),
+                } => (Some(component.user.id), component.guild_id),
+                FullEvent::InteractionCreate {
+                    interaction: Interaction::Modal(modal),
+                } => (Some(modal.user.id), modal.guild_id),
+                _ => (None, None),
+            };
+            Report {
+                source: format!("event `{}`", event.snake_case_name()),
+                user,
+                guild,
+                details: error_chain(&**error),
+            }
+        }
+        _ => return,
+    };
+
+    error!(source = %report.source, "{}", report.details);
+    let embed = report.embed();
+    let ctx = error.serenity_context();
+    let config = config();
+
+    if let Some(url) = &config.error_webhook {
+        let sent = async {
+            let webhook = Webhook::from_url(ctx, url).await?;
+            let message = ExecuteWebhook::new().embed(embed.clone());
+            webhook.execute(ctx, false, message).await
+        };
+        if let Err(e) = sent.await {
+            warn!("Couldn't send the error report to the webhook: {e}");
+        }
+    }
+
+    if config.report_to_owners {
+        let owners = match error {
+            FrameworkError::EventHandler { framework, .. } => {
+                &framework.options().owners
+            }
+            _ => match error.ctx() {
+                Some(ctx) => &ctx.framework().options().owners,
+                None => return,
+            },
+        };
+        for owner in owners {
+            let message = CreateMessage::new().embed(embed.clone());
+            if let Err(e) = owner.direct_message(ctx, message).await {
+                warn!(%owner, "Couldn't DM the error report: {e}");
+            }
+        }
+    }
+}
+
+/// Where an unexpected error happened and what it said
+struct Report {
+    source: String,
+    user: Option<serenity::UserId>,
+    guild: Option<serenity::GuildId>,
+    details: String,
+}
+
+impl Report {
+    fn embed(&self) -> CreateEmbed {
+        let mut details: String =
+            self.details.chars().take(MAX_REPORT_LEN).collect();
+        if details.len() < self.details.len() {
+            details.push('…');
+        }
+        let mention = |id: Option<String>| id.unwrap_or("*none*".into());
+
+        CreateEmbed::new()
+            .title(format!("Unexpected error in {}", self.source))
+            .description(format!("```\n{details}\n```"))
+            .field("User", mention(self.user.map(|u| format!("<@{u}>"))), true)
+            .field("Guild", mention(self.guild.map(|g| g.to_string())), true)
+    }
+}
+
+/// Errors from Discord, the network or the file system, anything else comes
+/// from the checks on the input and is already shown to the user
+fn is_unexpected(error: &Error) -> bool {
+    error.is::<serenity::Error>()
+        || error.is::<reqwest::Error>()
+        || error.is::<std::io::Error>()
+}
+
+/// The error followed by its causes, one per line. Boxed errors don't carry
+/// a backtrace, this is the closest to one
+fn error_chain(error: &(dyn std::error::Error + 'static)) -> String {
+    let mut chain = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        chain.push_str(&format!("\ncaused by: {cause}"));
+        source = cause.source();
+    }
+    chain
+}
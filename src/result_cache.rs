@@ -0,0 +1,243 @@
+use std::{
+    collections::VecDeque,
+    hash::{BuildHasher, Hash, Hasher, RandomState},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{
+    Res,
+    options::{Assets, OutputFormat, Preferences},
+    render::Rendered,
+};
+
+/// Finished renders keyed by their input and options, so converting the
+/// same image the same way again skips the work. Entries pushed out of
+/// memory are spilled to a directory, when one is configured
+pub struct ResultCache {
+    capacity: usize,
+    /// Seeded per process, so nobody can craft an input colliding with
+    /// someone else's render
+    hasher: RandomState,
+    entries: Mutex<VecDeque<(u64, Arc<Rendered>)>>,
+    disk: Option<DiskCache>,
+}
+
+/// Spilled renders, `<key>.json` holding everything but the images, which
+/// are stored next to it as `<key>.<tile>`
+struct DiskCache {
+    dir: PathBuf,
+    capacity: usize,
+    /// Oldest first
+    keys: Mutex<VecDeque<u64>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SpilledRender {
+    ascii_art: String,
+    format: OutputFormat,
+    columns: u32,
+    rows: u32,
+    downscaled: bool,
+    tiles: usize,
+}
+
+impl ResultCache {
+    /// A `capacity` of 0 disables the cache. Files left in `dir` by a
+    /// previous run are removed, their keys can't be found again
+    pub fn new(
+        capacity: usize,
+        dir: Option<PathBuf>,
+        disk_capacity: usize,
+    ) -> Res<Self> {
+        let disk = match dir {
+            Some(dir) if capacity > 0 && disk_capacity > 0 => {
+                std::fs::create_dir_all(&dir)?;
+                remove_spilled(&dir)?;
+                Some(DiskCache {
+                    dir,
+                    capacity: disk_capacity,
+                    keys: Mutex::new(VecDeque::new()),
+                })
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            capacity,
+            hasher: RandomState::new(),
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            disk,
+        })
+    }
+
+    /// Key of a render, `None` when it can't be cached. Uploaded fonts and
+    /// backgrounds only exist decoded, so renders using them never are
+    pub fn key(
+        &self,
+        image_bytes: &[u8],
+        prefs: &Preferences,
+        assets: &Assets,
+        collage: bool,
+    ) -> Option<u64> {
+        if self.capacity == 0
+            || assets.font.is_some()
+            || assets.background.is_some()
+        {
+            return None;
+        }
+
+        let prefs = serde_json::to_string(prefs).ok()?;
+        let mut hasher = self.hasher.build_hasher();
+        image_bytes.hash(&mut hasher);
+        prefs.hash(&mut hasher);
+        collage.hash(&mut hasher);
+        Some(hasher.finish())
+    }
+
+    pub async fn get(&self, key: u64) -> Option<Rendered> {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            if let Some(i) = entries.iter().position(|(k, _)| *k == key) {
+                // Most recently used last
+                let entry = entries.remove(i)?;
+                let rendered = Rendered::clone(&entry.1);
+                entries.push_back(entry);
+                debug!(key, "Result cache hit");
+                return Some(rendered);
+            }
+        }
+
+        let rendered = self.disk.as_ref()?.load(key).await?;
+        debug!(key, "Result cache hit on disk");
+        self.insert(key, &rendered).await;
+        Some(rendered)
+    }
+
+    pub async fn insert(&self, key: u64, rendered: &Rendered) {
+        let evicted = {
+            let mut entries = self.entries.lock().unwrap();
+            if entries.iter().any(|(k, _)| *k == key) {
+                return;
+            }
+            entries.push_back((key, Arc::new(rendered.clone())));
+            if entries.len() > self.capacity {
+                entries.pop_front()
+            } else {
+                None
+            }
+        };
+
+        if let (Some(disk), Some((key, rendered))) = (&self.disk, evicted)
+            && let Err(e) = disk.store(key, &rendered).await
+        {
+            warn!(key, "Couldn't spill a render to disk: {e}");
+        }
+    }
+
+    /// Forget every render, for when the config their defaults came from
+    /// changed
+    pub async fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+        if let Some(disk) = &self.disk {
+            let keys: Vec<u64> = disk.keys.lock().unwrap().drain(..).collect();
+            for key in keys {
+                disk.remove(key).await;
+            }
+        }
+    }
+}
+
+impl DiskCache {
+    fn path(&self, key: u64, extension: impl std::fmt::Display) -> PathBuf {
+        self.dir.join(format!("{key:016x}.{extension}"))
+    }
+
+    async fn load(&self, key: u64) -> Option<Rendered> {
+        if !self.keys.lock().unwrap().contains(&key) {
+            return None;
+        }
+
+        let meta = tokio::fs::read(self.path(key, "json")).await.ok()?;
+        let meta: SpilledRender = serde_json::from_slice(&meta).ok()?;
+        let mut images = Vec::with_capacity(meta.tiles);
+        for tile in 0..meta.tiles {
+            images.push(tokio::fs::read(self.path(key, tile)).await.ok()?);
+        }
+
+        Some(Rendered {
+            ascii_art: meta.ascii_art,
+            images,
+            format: meta.format,
+            columns: meta.columns,
+            rows: meta.rows,
+            downscaled: meta.downscaled,
+        })
+    }
+
+    async fn store(&self, key: u64, rendered: &Rendered) -> Res<()> {
+        if self.keys.lock().unwrap().contains(&key) {
+            return Ok(());
+        }
+
+        for (tile, image) in rendered.images.iter().enumerate() {
+            tokio::fs::write(self.path(key, tile), image).await?;
+        }
+        let meta = SpilledRender {
+            ascii_art: rendered.ascii_art.clone(),
+            format: rendered.format,
+            columns: rendered.columns,
+            rows: rendered.rows,
+            downscaled: rendered.downscaled,
+            tiles: rendered.images.len(),
+        };
+        // Written last, so a render is only found once it's complete
+        tokio::fs::write(self.path(key, "json"), serde_json::to_vec(&meta)?)
+            .await?;
+
+        let evicted = {
+            let mut keys = self.keys.lock().unwrap();
+            keys.push_back(key);
+            if keys.len() > self.capacity { keys.pop_front() } else { None }
+        };
+        if let Some(evicted) = evicted {
+            self.remove(evicted).await;
+        }
+        Ok(())
+    }
+
+    async fn remove(&self, key: u64) {
+        let Ok(meta) = tokio::fs::read(self.path(key, "json")).await else {
+            return;
+        };
+        tokio::fs::remove_file(self.path(key, "json")).await.ok();
+        if let Ok(meta) = serde_json::from_slice::<SpilledRender>(&meta) {
+            for tile in 0..meta.tiles {
+                tokio::fs::remove_file(self.path(key, tile)).await.ok();
+            }
+        }
+    }
+}
+
+/// Remove the files named like spilled renders from `dir`, leaving anything
+/// else that lives there alone
+fn remove_spilled(dir: &Path) -> Res<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_spilled = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.split_once('.'))
+            .is_some_and(|(key, _)| {
+                key.len() == 16 && u64::from_str_radix(key, 16).is_ok()
+            });
+
+        if is_spilled && path.is_file() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,96 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use tokio::sync::Notify;
+
+use crate::Res;
+
+pub const RESTARTING: &str = "The bot is restarting, try again in a minute";
+
+/// Keeps count of the renders running, so shutting down can wait for them
+/// instead of cutting them off
+#[derive(Default)]
+pub struct Shutdown {
+    closing: AtomicBool,
+    running: AtomicUsize,
+    idle: Notify,
+    /// Woken by `/admin shutdown`
+    requested: Notify,
+}
+
+/// A running render, counted until it's dropped
+pub struct Job<'a>(&'a Shutdown);
+
+impl Shutdown {
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+
+    /// Count a render in, refused once the bot is shutting down
+    pub fn start(&self) -> Res<Job<'_>> {
+        // Counted before checking, so `close` can't miss a render that
+        // slipped in between
+        self.running.fetch_add(1, Ordering::SeqCst);
+        let job = Job(self);
+        if self.is_closing() {
+            return Err(RESTARTING.into());
+        }
+
+        Ok(job)
+    }
+
+    /// Ask the bot to shut down, as a signal would
+    pub fn request(&self) {
+        self.requested.notify_one();
+    }
+
+    /// Resolve once a shutdown is requested through [`Shutdown::request`]
+    pub async fn requested(&self) {
+        self.requested.notified().await;
+    }
+
+    /// Refuse new renders and wait for the running ones, up to `timeout`.
+    /// Returns whether they all finished
+    pub async fn close(&self, timeout: Duration) -> bool {
+        self.closing.store(true, Ordering::SeqCst);
+
+        let finished = async {
+            loop {
+                let idle = self.idle.notified();
+                if self.running.load(Ordering::SeqCst) == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        };
+        tokio::time::timeout(timeout, finished).await.is_ok()
+    }
+}
+
+impl Drop for Job<'_> {
+    fn drop(&mut self) {
+        if self.0.running.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// Resolve on Ctrl+C, or SIGTERM on Unix
+pub async fn signal() -> Res<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use metrics::{counter, histogram};
+
+use crate::storage::StatTotals;
+
+/// Names of the metrics exported on `/metrics`
+pub const COMMANDS_METRIC: &str = "ascii_bot_commands_total";
+pub const RENDER_SECONDS_METRIC: &str = "ascii_bot_render_seconds";
+pub const OUTPUT_BYTES_METRIC: &str = "ascii_bot_output_bytes";
+pub const ERRORS_METRIC: &str = "ascii_bot_errors_total";
+
+/// Runtime counters shown by `/stats`
+pub struct Stats {
+    started: Instant,
+    renders: AtomicU64,
+    render_micros: AtomicU64,
+    /// Unix time of the latest render, in seconds, 0 before the first one
+    last_render: AtomicU64,
+}
+
+impl Stats {
+    /// Counting on from the totals of the previous runs
+    pub fn new(saved: StatTotals) -> Self {
+        Self {
+            started: Instant::now(),
+            renders: AtomicU64::new(saved.renders),
+            render_micros: AtomicU64::new(saved.render_micros),
+            last_render: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a finished render, `output_bytes` being the size of the
+    /// encoded images
+    pub fn record_render(&self, elapsed: Duration, output_bytes: usize) {
+        self.renders.fetch_add(1, Ordering::Relaxed);
+        self.render_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH);
+        let now = now.map_or(0, |d| d.as_secs());
+        self.last_render.store(now, Ordering::Relaxed);
+
+        histogram!(RENDER_SECONDS_METRIC).record(elapsed.as_secs_f64());
+        histogram!(OUTPUT_BYTES_METRIC).record(output_bytes as f64);
+    }
+
+    /// Unix time of the latest successful render, in seconds
+    pub fn last_render(&self) -> Option<u64> {
+        Some(self.last_render.load(Ordering::Relaxed)).filter(|&t| t > 0)
+    }
+
+    pub fn renders(&self) -> u64 {
+        self.renders.load(Ordering::Relaxed)
+    }
+
+    pub fn average_render_time(&self) -> Duration {
+        let renders = self.renders();
+        if renders == 0 {
+            return Duration::ZERO;
+        }
+
+        Duration::from_micros(
+            self.render_micros.load(Ordering::Relaxed) / renders,
+        )
+    }
+
+    /// Counters to carry over to the next run
+    pub fn totals(&self) -> StatTotals {
+        StatTotals {
+            renders: self.renders(),
+            render_micros: self.render_micros.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+pub fn record_command(name: &str) {
+    counter!(COMMANDS_METRIC, "command" => name.to_string()).increment(1);
+}
+
+/// Count an error, `kind` being what went wrong, like `command` or
+/// `cooldown`
+pub fn record_error(kind: &'static str) {
+    counter!(ERRORS_METRIC, "kind" => kind).increment(1);
+}
+
+/// Format a duration as `1d 2h 3m 4s`, skipping leading zero units
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let units = [
+        (secs / 86_400, "d"),
+        (secs / 3600 % 24, "h"),
+        (secs / 60 % 60, "m"),
+        (secs % 60, "s"),
+    ];
+
+    let parts: Vec<String> = units
+        .iter()
+        .skip_while(|(value, unit)| *value == 0 && *unit != "s")
+        .map(|(value, unit)| format!("{value}{unit}"))
+        .collect();
+
+    parts.join(" ")
+}
@@ -0,0 +1,482 @@
+use std::{collections::HashMap, fmt, io::ErrorKind, num::NonZeroU64};
+
+use poise::serenity_prelude::{ChannelId, GuildId, UserId};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use sqlx::{
+    SqlitePool,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+};
+use tracing::info;
+
+use crate::{
+    Res,
+    options::{GuildSettings, Preferences},
+    quota::{self, Reservation},
+};
+
+/// Settings file used before the database, imported into it once
+const LEGACY_SETTINGS: &str = "settings.json";
+
+/// Lifetime render counters, carried over between runs
+#[derive(Default)]
+pub struct StatTotals {
+    pub renders: u64,
+    pub render_micros: u64,
+}
+
+/// Lists of users and servers kept by the moderation checks
+#[derive(Clone, Copy)]
+pub enum List {
+    /// Refused
+    Blacklist,
+    /// The only ones allowed, when the config restricts the bot to them
+    Allowlist,
+}
+
+impl List {
+    fn table(self) -> &'static str {
+        match self {
+            List::Blacklist => "blacklist",
+            List::Allowlist => "allowlist",
+        }
+    }
+}
+
+/// A user or server on a [`List`]
+#[derive(Clone, Copy)]
+pub enum Target {
+    User(UserId),
+    Guild(GuildId),
+}
+
+impl Target {
+    /// Kind and id, as stored
+    fn key(self) -> (&'static str, i64) {
+        match self {
+            Target::User(id) => ("user", id.get() as i64),
+            Target::Guild(id) => ("guild", id.get() as i64),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::User(id) => write!(f, "User <@{id}>"),
+            Target::Guild(id) => write!(f, "Server `{id}`"),
+        }
+    }
+}
+
+/// SQLite backed store for saved settings, quota usage and stats
+pub struct Storage {
+    pool: SqlitePool,
+}
+
+impl Storage {
+    /// Open the database, creating it and running the pending migrations
+    pub async fn open(path: &str) -> Res<Self> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal);
+        // A single connection, so a quota check and its update can't
+        // interleave with another render's
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await?;
+        sqlx::migrate!().run(&pool).await?;
+
+        let storage = Self { pool };
+        storage.import_legacy().await?;
+        Ok(storage)
+    }
+
+    pub async fn user(&self, id: UserId) -> Res<Preferences> {
+        self.load("SELECT prefs FROM users WHERE id = ?", id.get()).await
+    }
+
+    pub async fn set_user(&self, id: UserId, prefs: Preferences) -> Res<()> {
+        if prefs.is_empty() {
+            self.delete("DELETE FROM users WHERE id = ?", id.get()).await
+        } else {
+            self.save(
+                "INSERT INTO users (id, prefs) VALUES (?, ?) \
+                 ON CONFLICT (id) DO UPDATE SET prefs = excluded.prefs",
+                id.get(),
+                &prefs,
+            )
+            .await
+        }
+    }
+
+    pub async fn guild(&self, id: GuildId) -> Res<GuildSettings> {
+        self.load("SELECT settings FROM guilds WHERE id = ?", id.get()).await
+    }
+
+    pub async fn set_guild(
+        &self,
+        id: GuildId,
+        settings: GuildSettings,
+    ) -> Res<()> {
+        if settings.is_empty() {
+            self.delete("DELETE FROM guilds WHERE id = ?", id.get()).await
+        } else {
+            self.save(
+                "INSERT INTO guilds (id, settings) VALUES (?, ?) \
+                 ON CONFLICT (id) DO UPDATE SET settings = excluded.settings",
+                id.get(),
+                &settings,
+            )
+            .await
+        }
+    }
+
+    /// Count a render of `user` against the daily quotas of `guild` before
+    /// it runs, or refuse it with a
+    /// [`QuotaExceeded`](crate::quota::QuotaExceeded) once one of them is
+    /// used up. Checked and counted in one statement, so concurrent renders
+    /// can't both take the last one. `None` when the server has no quota
+    pub async fn reserve_quota(
+        &self,
+        guild: GuildId,
+        user: UserId,
+        settings: &GuildSettings,
+    ) -> Res<Option<Reservation>> {
+        if settings.daily_quota.is_none() && settings.user_daily_quota.is_none()
+        {
+            return Ok(None);
+        }
+
+        let day = quota::today();
+        let limit = |quota: Option<u32>| quota.map_or(i64::MAX, i64::from);
+        let mut tx = self.pool.begin().await?;
+
+        let reserved = sqlx::query(
+            "INSERT INTO quota_usage (day, guild, user, renders) \
+             SELECT ?1, ?2, ?3, 1 \
+             WHERE (SELECT COALESCE(SUM(renders), 0) FROM quota_usage \
+                    WHERE day = ?1 AND guild = ?2) < ?4 \
+             AND (SELECT COALESCE(SUM(renders), 0) FROM quota_usage \
+                  WHERE day = ?1 AND guild = ?2 AND user = ?3) < ?5 \
+             ON CONFLICT (day, guild, user) \
+             DO UPDATE SET renders = renders + 1",
+        )
+        .bind(day as i64)
+        .bind(guild.get() as i64)
+        .bind(user.get() as i64)
+        .bind(limit(settings.daily_quota))
+        .bind(limit(settings.user_daily_quota))
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+
+        if !reserved {
+            // Only to tell which quota refused it
+            let used: i64 = sqlx::query_scalar(
+                "SELECT COALESCE(SUM(renders), 0) FROM quota_usage \
+                 WHERE day = ? AND guild = ?",
+            )
+            .bind(day as i64)
+            .bind(guild.get() as i64)
+            .fetch_one(&mut *tx)
+            .await?;
+            let member_used: i64 = sqlx::query_scalar(
+                "SELECT COALESCE(SUM(renders), 0) FROM quota_usage \
+                 WHERE day = ? AND guild = ? AND user = ?",
+            )
+            .bind(day as i64)
+            .bind(guild.get() as i64)
+            .bind(user.get() as i64)
+            .fetch_one(&mut *tx)
+            .await?;
+            quota::check(settings, day, used as u32, member_used as u32)?;
+            return Err("The daily render quota is used up".into());
+        }
+
+        // Past days are never read again
+        sqlx::query("DELETE FROM quota_usage WHERE day < ?")
+            .bind(day as i64)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(Some(Reservation { day, guild, user }))
+    }
+
+    /// Give back a render reserved by [`Self::reserve_quota`] that failed
+    pub async fn refund_quota(
+        &self,
+        reservation: Option<Reservation>,
+    ) -> Res<()> {
+        let Some(reservation) = reservation else {
+            return Ok(());
+        };
+        sqlx::query(
+            "UPDATE quota_usage SET renders = renders - 1 \
+             WHERE day = ? AND guild = ? AND user = ? AND renders > 0",
+        )
+        .bind(reservation.day as i64)
+        .bind(reservation.guild.get() as i64)
+        .bind(reservation.user.get() as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Whether `user`, or the server it's in, is on `list`
+    pub async fn is_listed(
+        &self,
+        list: List,
+        user: UserId,
+        guild: Option<GuildId>,
+    ) -> Res<bool> {
+        let guild = guild.map(|id| id.get() as i64);
+        let query = format!(
+            "SELECT 1 FROM {} WHERE (kind = 'user' AND id = ?) \
+             OR (kind = 'guild' AND id = ?)",
+            list.table()
+        );
+        let found: Option<i64> = sqlx::query_scalar(&query)
+            .bind(user.get() as i64)
+            .bind(guild)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(found.is_some())
+    }
+
+    /// Every entry of `list`, with its reason
+    pub async fn list_entries(
+        &self,
+        list: List,
+    ) -> Res<Vec<(Target, Option<String>)>> {
+        let query = format!("SELECT kind, id, reason FROM {}", list.table());
+        let rows: Vec<(String, i64, Option<String>)> =
+            sqlx::query_as(&query).fetch_all(&self.pool).await?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|(kind, id, reason)| {
+                let id = NonZeroU64::new(id as u64)?;
+                let target = match kind.as_str() {
+                    "user" => Target::User(id.into()),
+                    _ => Target::Guild(id.into()),
+                };
+                Some((target, reason))
+            })
+            .collect())
+    }
+
+    pub async fn list_add(
+        &self,
+        list: List,
+        target: Target,
+        reason: Option<&str>,
+    ) -> Res<()> {
+        let (kind, id) = target.key();
+        let query = format!(
+            "INSERT INTO {} (kind, id, reason) VALUES (?, ?, ?) \
+             ON CONFLICT (kind, id) DO UPDATE SET reason = excluded.reason",
+            list.table()
+        );
+        sqlx::query(&query)
+            .bind(kind)
+            .bind(id)
+            .bind(reason)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn list_remove(&self, list: List, target: Target) -> Res<()> {
+        let (kind, id) = target.key();
+        let query =
+            format!("DELETE FROM {} WHERE kind = ? AND id = ?", list.table());
+        sqlx::query(&query).bind(kind).bind(id).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    pub async fn is_watched(&self, channel: ChannelId) -> Res<bool> {
+        let found: Option<i64> = sqlx::query_scalar(
+            "SELECT 1 FROM watched_channels WHERE channel = ?",
+        )
+        .bind(channel.get() as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(found.is_some())
+    }
+
+    /// The watched channels of `guild`
+    pub async fn watched_channels(
+        &self,
+        guild: GuildId,
+    ) -> Res<Vec<ChannelId>> {
+        let ids: Vec<i64> = sqlx::query_scalar(
+            "SELECT channel FROM watched_channels WHERE guild = ?",
+        )
+        .bind(guild.get() as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(ids
+            .into_iter()
+            .filter_map(|id| NonZeroU64::new(id as u64).map(ChannelId::from))
+            .collect())
+    }
+
+    pub async fn set_watched(
+        &self,
+        guild: GuildId,
+        channel: ChannelId,
+        watched: bool,
+    ) -> Res<()> {
+        let query = if watched {
+            "INSERT INTO watched_channels (channel, guild) VALUES (?, ?) \
+             ON CONFLICT (channel) DO NOTHING"
+        } else {
+            "DELETE FROM watched_channels WHERE channel = ? AND guild = ?"
+        };
+        sqlx::query(query)
+            .bind(channel.get() as i64)
+            .bind(guild.get() as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Options of the last `/image_to_ascii` run in `channel`, `None` when
+    /// there was none
+    pub async fn channel_options(
+        &self,
+        channel: ChannelId,
+    ) -> Res<Option<Preferences>> {
+        self.load(
+            "SELECT prefs FROM channel_options WHERE channel = ?",
+            channel.get(),
+        )
+        .await
+    }
+
+    /// Remember `prefs` for `channel`, even empty ones, so `/same_as_last`
+    /// can tell defaults apart from a channel nobody converted in
+    pub async fn set_channel_options(
+        &self,
+        channel: ChannelId,
+        prefs: &Preferences,
+    ) -> Res<()> {
+        self.save(
+            "INSERT INTO channel_options (channel, prefs) VALUES (?, ?) \
+             ON CONFLICT (channel) DO UPDATE SET prefs = excluded.prefs",
+            channel.get(),
+            prefs,
+        )
+        .await
+    }
+
+    pub async fn stat_totals(&self) -> Res<StatTotals> {
+        let rows: HashMap<String, i64> =
+            sqlx::query_as("SELECT name, value FROM stats")
+                .fetch_all(&self.pool)
+                .await?
+                .into_iter()
+                .collect();
+        let total = |name| rows.get(name).copied().unwrap_or(0) as u64;
+
+        Ok(StatTotals {
+            renders: total("renders"),
+            render_micros: total("render_micros"),
+        })
+    }
+
+    pub async fn save_stat_totals(&self, totals: StatTotals) -> Res<()> {
+        let values = [
+            ("renders", totals.renders),
+            ("render_micros", totals.render_micros),
+        ];
+        for (name, value) in values {
+            sqlx::query(
+                "INSERT INTO stats (name, value) VALUES (?, ?) \
+                 ON CONFLICT (name) DO UPDATE SET value = excluded.value",
+            )
+            .bind(name)
+            .bind(value as i64)
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Wait for pending queries and close the database
+    pub async fn close(&self) {
+        self.pool.close().await;
+    }
+
+    /// Row `id` of a JSON column, the default when there's none
+    async fn load<T: DeserializeOwned + Default>(
+        &self,
+        query: &str,
+        id: u64,
+    ) -> Res<T> {
+        let json: Option<String> = sqlx::query_scalar(query)
+            .bind(id as i64)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        match json {
+            Some(json) => Ok(serde_json::from_str(&json)?),
+            None => Ok(T::default()),
+        }
+    }
+
+    async fn save(
+        &self,
+        query: &str,
+        id: u64,
+        value: &impl Serialize,
+    ) -> Res<()> {
+        sqlx::query(query)
+            .bind(id as i64)
+            .bind(serde_json::to_string(value)?)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, query: &str, id: u64) -> Res<()> {
+        sqlx::query(query).bind(id as i64).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Move the settings file used before the database into it. The file
+    /// is renamed afterwards, so it's only imported once
+    async fn import_legacy(&self) -> Res<()> {
+        #[derive(Deserialize)]
+        struct LegacySettings {
+            #[serde(default)]
+            users: HashMap<u64, Preferences>,
+            #[serde(default)]
+            guilds: HashMap<u64, GuildSettings>,
+        }
+
+        let bytes = match tokio::fs::read(LEGACY_SETTINGS).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let legacy: LegacySettings = serde_json::from_slice(&bytes)?;
+        let (users, guilds) = (legacy.users.len(), legacy.guilds.len());
+
+        for (id, prefs) in legacy.users {
+            self.set_user(UserId::new(id), prefs).await?;
+        }
+        for (id, settings) in legacy.guilds {
+            self.set_guild(GuildId::new(id), settings).await?;
+        }
+
+        let imported = format!("{LEGACY_SETTINGS}.imported");
+        tokio::fs::rename(LEGACY_SETTINGS, imported).await?;
+        info!(users, guilds, "Imported {LEGACY_SETTINGS} into the database");
+        Ok(())
+    }
+}
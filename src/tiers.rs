@@ -0,0 +1,73 @@
+use poise::serenity_prelude::{
+    Context as SerenityContext, Entitlement, UserId,
+};
+
+use tracing::debug;
+
+use crate::{Context, config::config, options::Preferences};
+
+/// What a user may render. Supporters get the bigger limits of
+/// `[tiers]` and go first in the render queue
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Tier {
+    Free,
+    Supporter,
+}
+
+impl Tier {
+    /// Clamp `prefs` to the limits of this tier, every render goes through
+    /// this before being queued
+    pub fn limit(self, mut prefs: Preferences) -> Preferences {
+        let tiers = &config().tiers;
+        let (max_size, frames) = match self {
+            Tier::Free => (tiers.free_max_size, tiers.free_animation_frames),
+            Tier::Supporter => {
+                (tiers.supporter_max_size, tiers.supporter_animation_frames)
+            }
+        };
+        prefs.size = Some(prefs.size().min(max_size));
+        prefs.max_height = prefs.max_height.map(|h| h.min(max_size));
+        prefs.animation_frames = Some(frames);
+        prefs
+    }
+}
+
+/// The tier of `user`: supporters have an entitlement to the configured
+/// SKU, or the configured role in the supporter server. `entitlements`
+/// are the ones Discord sent with the interaction
+pub async fn tier(
+    ctx: &SerenityContext,
+    user: UserId,
+    entitlements: &[Entitlement],
+) -> Tier {
+    let tiers = &config().tiers;
+    if let Some(sku) = tiers.supporter_sku
+        && entitlements.iter().any(|e| e.sku_id == sku)
+    {
+        return Tier::Supporter;
+    }
+
+    if let (Some(guild), Some(role)) =
+        (tiers.supporter_guild, tiers.supporter_role)
+    {
+        // Failing for users outside the server, who aren't supporters
+        match guild.member(ctx, user).await {
+            Ok(member) if member.roles.contains(&role) => {
+                return Tier::Supporter;
+            }
+            Ok(_) => {}
+            Err(e) => debug!(%user, "No supporter role: {e}"),
+        }
+    }
+
+    Tier::Free
+}
+
+/// [`tier`] of the author of a command
+pub async fn command_tier(ctx: Context<'_>) -> Tier {
+    let entitlements = match ctx {
+        Context::Application(ctx) => ctx.interaction.entitlements.as_slice(),
+        Context::Prefix(_) => &[],
+    };
+    tier(ctx.serenity_context(), ctx.author().id, entitlements).await
+}